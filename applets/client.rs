@@ -0,0 +1,359 @@
+/**
+* Internal client API, applets calling other applets.
+*
+* Follows the GlobalPlatform TEE_OpenTASession, TEE_InvokeTACommand and
+* TEE_CloseTASession semantics. An applet can open a session to another applet
+* by UUID if the UUID is listed in `peers` in its manifest. Commands are
+* invoked synchronously, the caller is suspended until the callee calls
+* SYSNO_EXIT.
+*
+* Parameters are passed as an array of MAX_PARAMS `Param` in caller memory.
+* Memory references are shared page by page into the callee at
+* VA_SHARED_START, so the callee works directly on the caller's memory. The
+* kernel writes the parameters for the callee at VA_PARAMS, values and memref
+* sizes updated by the callee are written back to the caller on return.
+*
+* Callee is entered through its smc function with function id FNID_INVOKE,
+* the command and the address of the parameters.
+*
+* Only single-instance applets can be opened, all sessions share the instance.
+* Calls can't be recursive, invoking an applet already in the call chain
+* returns err::BUSY.
+*/
+use cpu::interrupt;
+use driver::mmu;
+use lib::math;
+use lib::sizes;
+use lib::log;
+use verify::Uuid;
+use cpu;
+use super::*;
+
+extern "C" {
+	fn resume_el0(frame: u64);
+}
+
+/* Outside the range of function ids from normal world */
+pub const FNID_INVOKE: u64 = 1 << 16;
+
+pub const MAX_PARAMS: usize = 4;
+
+/* Parameter types */
+pub mod param {
+	pub const NONE:          u64 = 0;
+	pub const VALUE_INPUT:   u64 = 1;
+	pub const VALUE_OUTPUT:  u64 = 2;
+	pub const VALUE_INOUT:   u64 = 3;
+	pub const MEMREF_INPUT:  u64 = 5;
+	pub const MEMREF_OUTPUT: u64 = 6;
+	pub const MEMREF_INOUT:  u64 = 7;
+}
+
+/*
+* Errors returned from SYSNO_OPEN_SESSION and SYSNO_INVOKE, same values as in
+* GlobalPlatform
+*/
+pub mod err {
+	pub const ACCESS_DENIED:  u64 = 0xffff0001;
+	pub const BAD_PARAMETERS: u64 = 0xffff0006;
+	pub const ITEM_NOT_FOUND: u64 = 0xffff0008;
+	pub const NOT_SUPPORTED:  u64 = 0xffff000a;
+	pub const OUT_OF_MEMORY:  u64 = 0xffff000c;
+	pub const BUSY:           u64 = 0xffff000d;
+	pub const TARGET_DEAD:    u64 = 0xffff3024;
+}
+
+/**
+* Value parameters use `a` and `b`, memory references use `a` as address and `b`
+* as size.
+*/
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Param {
+	pub kind: u64,
+	pub a: u64,
+	pub b: u64,
+}
+const PARAM_WORDS: usize = 3;
+
+/* Bottom of upper half is reserved for parameters and shared memory */
+pub const VA_PARAMS: u64 = !((1 << mmu::VA_BITS) - 1);
+const SLOT_SIZE: u64 = 16 * sizes::MB;
+pub const VA_SHARED_START: u64 = VA_PARAMS + SLOT_SIZE;
+pub const VA_SHARED_END: u64 = VA_SHARED_START + (MAX_PARAMS as u64 * SLOT_SIZE);
+pub const MAX_MEMREF_SIZE: u64 = SLOT_SIZE - mmu::PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+struct Session {
+	used: bool,
+	caller: usize,
+	callee: usize,
+}
+const MAX_SESSIONS: usize = 16;
+static mut SESSIONS: [Session; MAX_SESSIONS] = [Session{used: false, caller: 0, callee: 0}; MAX_SESSIONS];
+
+/*
+* Call in progress, `frame` is the caller's saved state on the kernel stack,
+* `fnid` and `start` is what we need to restore in RUNNING.
+*/
+#[derive(Clone, Copy)]
+struct Call {
+	caller: usize,
+	callee: usize,
+	frame: u64,
+	fnid: u64,
+	start: u64,
+	params: u64,
+	kinds: [u64; MAX_PARAMS],
+	shared: [u64; MAX_PARAMS],
+}
+const MAX_CALL_DEPTH: usize = 4;
+const NO_CALL: Call = Call{caller: 0, callee: 0, frame: 0, fnid: 0, start: 0, params: 0, kinds: [0; MAX_PARAMS], shared: [0; MAX_PARAMS]};
+/* Calls in progress, only used by the core which owns EL0, see applets::EL0_OWNER */
+static mut CALLS: [Call; MAX_CALL_DEPTH] = [NO_CALL; MAX_CALL_DEPTH];
+static mut DEPTH: usize = 0;
+
+fn is_memref(kind: u64) -> bool {
+	return kind == param::MEMREF_INPUT || kind == param::MEMREF_OUTPUT || kind == param::MEMREF_INOUT;
+}
+fn is_output(kind: u64) -> bool {
+	return kind == param::VALUE_OUTPUT || kind == param::VALUE_INOUT || kind == param::MEMREF_OUTPUT || kind == param::MEMREF_INOUT;
+}
+
+/**
+* Range reserved for parameters and shared memory.
+*/
+pub fn in_shared(addr: u64, size: u64) -> bool {
+	return addr < VA_SHARED_END && addr + size > VA_PARAMS;
+}
+
+/**
+* Open session from running applet to applet with UUID in `lo` and `hi`, returns
+* session id or err::*.
+*/
+pub fn open(lo: u64, hi: u64) -> u64 {
+	let caller = unsafe { RUNNING.idx };
+	let mut uuid: Uuid = [0; 16];
+	uuid[..8].copy_from_slice(&lo.to_le_bytes());
+	uuid[8..].copy_from_slice(&hi.to_le_bytes());
+
+	let callee = match unsafe { APPLETS.iter().position(|a| a.manifest.uuid == uuid) } {
+		Some(i) => i,
+		None => { return err::ITEM_NOT_FOUND; }
+	};
+	if ! unsafe { APPLETS[caller].manifest.allows_peer(&uuid) } {
+		deny(caller, cpu::svc::SYSNO_OPEN_SESSION, callee as u64, "audit: session denied");
+		return err::ACCESS_DENIED;
+	}
+	let app = unsafe { &APPLETS[callee] };
+	if app.manifest.multi_instance {
+		return err::NOT_SUPPORTED;
+	}
+	if app.killed {
+		return err::TARGET_DEAD;
+	}
+	for i in 0..MAX_SESSIONS {
+		let s = unsafe { &mut SESSIONS[i] };
+		if ! s.used {
+			*s = Session{used: true, caller: caller, callee: callee};
+			return i as u64;
+		}
+	}
+	return err::BUSY;
+}
+
+/**
+* Close session opened by running applet.
+*/
+pub fn close(id: u64) -> u64 {
+	let caller = unsafe { RUNNING.idx };
+	if id as usize >= MAX_SESSIONS {
+		return err::BAD_PARAMETERS;
+	}
+	let s = unsafe { &mut SESSIONS[id as usize] };
+	if ! s.used || s.caller != caller {
+		return err::BAD_PARAMETERS;
+	}
+	s.used = false;
+	return 0;
+}
+
+/**
+* Close all sessions opened by applet at `idx`.
+*/
+pub fn close_all(idx: usize) {
+	for s in unsafe { SESSIONS.iter_mut() } {
+		if s.caller == idx {
+			s.used = false;
+		}
+	}
+}
+
+/**
+* Check if other applets have open sessions to applet at `idx`.
+*/
+pub fn has_callers(idx: usize) -> bool {
+	return unsafe { SESSIONS.iter().any(|s| s.used && s.callee == idx) };
+}
+
+fn unshare(ttbr: u64, shared: &[u64; MAX_PARAMS]) {
+	for i in 0..MAX_PARAMS {
+		if shared[i] > 0 {
+			mmu::unshare_pages(ttbr, VA_SHARED_START + (i as u64 * SLOT_SIZE), shared[i]);
+		}
+	}
+}
+
+/**
+* Invoke `cmd` in session `id` with parameters at `params` in caller memory,
+* `frame` is where the caller's state is saved. Only returns on error, the
+* return value from the callee is given to the caller in finish.
+*/
+pub fn invoke(id: u64, cmd: u64, params: u64, frame: u64) -> u64 {
+	let caller = unsafe { RUNNING.idx };
+	if id as usize >= MAX_SESSIONS {
+		return err::BAD_PARAMETERS;
+	}
+	let s = unsafe { SESSIONS[id as usize] };
+	if ! s.used || s.caller != caller {
+		return err::BAD_PARAMETERS;
+	}
+	let depth = unsafe { DEPTH };
+	if depth == MAX_CALL_DEPTH || s.callee == caller || unsafe { CALLS[..depth].iter().any(|c| c.caller == s.callee) } {
+		return err::BUSY;
+	}
+	let (from, app) = unsafe { (APPLETS[caller].ttbr, &mut APPLETS[s.callee]) };
+	if app.killed || ! app.ready {
+		return err::TARGET_DEAD;
+	}
+
+	let mut words = [0u64; MAX_PARAMS * PARAM_WORDS];
+	if params != 0 && mmu::copy_from_el0(from, params, &mut words) < 0 {
+		return err::BAD_PARAMETERS;
+	}
+	if init_session(app) < 0 {
+		return err::OUT_OF_MEMORY;
+	}
+
+	let mut call = Call{caller: caller, callee: s.callee, frame: frame, fnid: unsafe { RUNNING.fnid }, start: unsafe { RUNNING.start }, params: params, ..NO_CALL};
+	for i in 0..MAX_PARAMS {
+		let kind = words[i * PARAM_WORDS];
+		call.kinds[i] = kind;
+		if kind > param::MEMREF_INOUT || kind == 4 {
+			unshare(app.ttbr, &call.shared);
+			return err::BAD_PARAMETERS;
+		}
+		let (addr, size) = (words[i * PARAM_WORDS + 1], words[i * PARAM_WORDS + 2]);
+		if ! is_memref(kind) || size == 0 {
+			continue;
+		}
+		if size > MAX_MEMREF_SIZE || ! mmu::inupper!(addr) || addr.checked_add(size + mmu::PAGE_SIZE).is_none() {
+			unshare(app.ttbr, &call.shared);
+			return err::BAD_PARAMETERS;
+		}
+		let start = math::align_pow2_down!(addr, mmu::PAGE_SIZE);
+		let pages = (math::align_pow2_up!(addr + size, mmu::PAGE_SIZE) - start) / mmu::PAGE_SIZE;
+		let prot = if kind == param::MEMREF_INPUT { mmu::EL0_RO } else { mmu::EL0_RW };
+		let slot = VA_SHARED_START + (i as u64 * SLOT_SIZE);
+		if mmu::share_pages(from, start, app.ttbr, slot, pages, prot) < 0 {
+			unshare(app.ttbr, &call.shared);
+			return err::BAD_PARAMETERS;
+		}
+		call.shared[i] = pages;
+		words[i * PARAM_WORDS + 1] = slot + (addr - start);
+	}
+
+	// Parameters for callee
+	if mmu::copy_to_el0(app.ttbr, VA_PARAMS, &words) < 0 {
+		if mmu::alloc_page(app.ttbr, VA_PARAMS, mmu::EL0_RW) < 0 || mmu::copy_to_el0(app.ttbr, VA_PARAMS, &words) < 0 {
+			unshare(app.ttbr, &call.shared);
+			return err::OUT_OF_MEMORY;
+		}
+	}
+
+	unsafe {
+		CALLS[depth] = call;
+		DEPTH += 1;
+	}
+	exec_in_el0!(app, FNID_INVOKE, cmd, VA_PARAMS, MAX_PARAMS as u64);
+	return err::TARGET_DEAD;
+}
+
+/**
+* Check if applet at `idx` was called by another applet and hasn't returned.
+*/
+pub fn in_call(idx: usize) -> bool {
+	let depth = unsafe { DEPTH };
+	return depth > 0 && unsafe { CALLS[depth - 1].callee } == idx;
+}
+
+/**
+* Return to caller with `ret` after the running applet has finished the call.
+* If callee has been killed, its address space is destroyed and no parameters
+* are written back. Does not return.
+*/
+pub fn finish(ret: u64, killed: bool) {
+	let call = unsafe {
+		DEPTH -= 1;
+		CALLS[DEPTH]
+	};
+	let (caller, callee) = unsafe { (&APPLETS[call.caller], &mut APPLETS[call.callee]) };
+
+	let mut words = [0u64; MAX_PARAMS * PARAM_WORDS];
+	let updated = ! killed && mmu::copy_from_el0(callee.ttbr, VA_PARAMS, &mut words) == 0;
+	unshare(callee.ttbr, &call.shared);
+	if killed {
+		close_session(callee);
+	}
+
+	// Values and memref sizes can be updated by callee, other fields are
+	// ignored
+	for i in 0..MAX_PARAMS {
+		if updated && call.params != 0 && is_output(call.kinds[i]) {
+			let off = call.params + ((i * PARAM_WORDS + 1) * 8) as u64;
+			let w = i * PARAM_WORDS;
+			let res = if is_memref(call.kinds[i]) {
+				mmu::copy_to_el0(caller.ttbr, off + 8, &words[w + 2..w + 3])
+			} else {
+				mmu::copy_to_el0(caller.ttbr, off, &words[w + 1..w + 3])
+			};
+			if res < 0 {
+				log::info("Unable to write parameters back to caller");
+			}
+		}
+	}
+
+	let running = unsafe { &mut RUNNING };
+	running.idx = call.caller;
+	running.fnid = call.fnid;
+	running.start = call.start;
+
+	let state = unsafe { &mut *(call.frame as *mut interrupt::InterruptState) };
+	state.regs[0] = ret;
+	mmu::switch_ttbr1(caller.ttbr);
+	unsafe { resume_el0(call.frame); }
+}
+
+/**
+* Wrappers used by applets in EL0.
+*/
+pub mod api {
+	use applets::arch_svc;
+	use verify::Uuid;
+	use cpu;
+	use super::{Param, MAX_PARAMS};
+
+	pub fn open_session(uuid: &Uuid) -> u64 {
+		let mut lo = [0u8; 8];
+		let mut hi = [0u8; 8];
+		lo.copy_from_slice(&uuid[..8]);
+		hi.copy_from_slice(&uuid[8..]);
+		return svc!(cpu::svc::SYSNO_OPEN_SESSION, u64::from_le_bytes(lo), u64::from_le_bytes(hi));
+	}
+	pub fn invoke_command(session: u64, cmd: u64, params: &mut [Param; MAX_PARAMS]) -> u64 {
+		return svc!(cpu::svc::SYSNO_INVOKE, session, cmd, params.as_mut_ptr() as u64);
+	}
+	pub fn close_session(session: u64) -> u64 {
+		return svc!(cpu::svc::SYSNO_CLOSE_SESSION, session);
+	}
+}
@@ -0,0 +1,78 @@
+/**
+* Capabilities declared by each applet.
+*
+* The manifest is declared next to the applet in `APPLETS` and can be updated
+* at runtime with a signed manifest, see `verify`. Everything not declared is
+* denied, the kernel checks the manifest of the running applet on every SVC.
+*/
+use cpu::svc;
+use verify::Uuid;
+
+/**
+* Bit for `sysno` in `Manifest::syscalls`.
+*/
+pub const fn sysno_bit(sysno: u64) -> u64 {
+	return 1 << sysno;
+}
+
+/* Syscalls every applet can use */
+pub const ALWAYS_ALLOWED: u64 = sysno_bit(svc::SYSNO_EXIT) | sysno_bit(svc::SYSNO_CANCELLED);
+
+/**
+* - `uuid` identifies applet in signed manifests and when other applets call it
+* - `stack` is number of pages used for stack
+* - `quota` is the maximum number of pages the applet can use, including stack
+*   and page tables. 0 means no limit.
+* - `multi_instance` allows a separate instance for each session, otherwise all
+*   sessions share the same instance
+* - `syscalls` is a bitmask of allowed syscalls, see `sysno_bit`
+* - `gpios` are the pins applet can use through SYSNO_GPIO, pin must also be
+*   owned by applets in the platform table
+* - `peers` are UUIDs of other applets this applet may call
+*/
+#[derive(Debug)]
+pub struct Manifest {
+	pub uuid: Uuid,
+	pub stack: u64,
+	pub quota: u64,
+	pub multi_instance: bool,
+	pub syscalls: u64,
+	pub gpios: &'static [u32],
+	pub peers: &'static [Uuid],
+}
+
+impl Manifest {
+	pub fn allows_syscall(&self, sysno: u64) -> bool {
+		if sysno >= 64 {
+			return false;
+		}
+		return ((self.syscalls | ALWAYS_ALLOWED) & sysno_bit(sysno)) != 0;
+	}
+	pub fn allows_gpio(&self, pin: u32) -> bool {
+		return self.gpios.contains(&pin);
+	}
+	pub fn allows_peer(&self, uuid: &Uuid) -> bool {
+		return self.peers.contains(uuid);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn declared_capabilities() {
+		let man = Manifest{
+			uuid: [1; 16], stack: 1, quota: 0, multi_instance: false,
+			syscalls: sysno_bit(svc::SYSNO_MMAP), gpios: &[3], peers: &[[2; 16]]
+		};
+		assert!(man.allows_syscall(svc::SYSNO_EXIT));
+		assert!(man.allows_syscall(svc::SYSNO_MMAP));
+		assert!(!man.allows_syscall(svc::SYSNO_STORE_PTR));
+		assert!(!man.allows_syscall(64));
+		assert!(man.allows_gpio(3));
+		assert!(!man.allows_gpio(4));
+		assert!(man.allows_peer(&[2; 16]));
+		assert!(!man.allows_peer(&[1; 16]));
+	}
+}
@@ -6,16 +6,22 @@
 * accomplished by using a separate page directory for all data segments.
 */
 use cpu::interrupt;
+use cpu::smc;
+use cpu::timer;
 use driver::mmu;
 use lib::math;
+use lib::log;
+use verify;
 use cpu;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 mod storage;
+pub mod manifest;
 
 extern "C" {
 	fn drop_el0(x: &interrupt::InterruptState);
 	pub fn arch_svc(a0: u64, a1: u64, a2: u64, fnid: u64) -> u64;
-	fn arch_load_offset() -> u64;
 }
 
 #[macro_export]
@@ -33,21 +39,15 @@ macro_rules! svc {
 		unsafe { arch_svc($a1, $a2, $a3, $id) }
 	};
 }
-macro_rules! adjust_func_add {
-	($addr:expr) => {
-//		let offset = unsafe { arch_load_offset() };
-		$addr + unsafe { arch_load_offset() }
-	};
-}
 macro_rules! exec_in_el0 {
 	($app:expr) => {
 		let mut state = interrupt::InterruptState::default();
-		state.elr = adjust_func_add!($app.init as u64);
+		state.elr = $app.init as u64;
 		__exec_in_el0($app, &mut state);
 	};
 	($app:expr, $fnid:expr, $cmd:expr, $arg:expr) => {
 		let mut state = interrupt::InterruptState::default();
-		state.elr = adjust_func_add!($app.smc as u64);
+		state.elr = $app.smc as u64;
 		state.regs[0] = $app.data;
 		state.regs[1] = $fnid;
 		state.regs[2] = $cmd;
@@ -56,7 +56,7 @@ macro_rules! exec_in_el0 {
 	};
 	($app:expr, $fnid:expr, $cmd:expr, $arg:expr, $len:expr) => {
 		let mut state = interrupt::InterruptState::default();
-		state.elr = adjust_func_add!($app.smc as u64);
+		state.elr = $app.smc as u64;
 		state.regs[0] = $app.data;
 		state.regs[1] = $fnid;
 		state.regs[2] = $cmd;
@@ -82,6 +82,9 @@ macro_rules! exec_in_el0 {
 
 pub(crate) use svc;
 
+// Uses exec_in_el0!
+pub mod client;
+
 type AppletSmc  = fn(u64, u64, u64, u64, u64);
 type AppletInit = fn();
 
@@ -101,24 +104,139 @@ type AppletInit = fn();
 * Current method for the applet is to allocate memory dynamically with the
 * SYSNO_MMAP call at any VA in the upper half where it executes. During the
 * init-function the applet has an oppurtunity to set all this up.
+*
+* `manifest` declares what the applet is allowed to do, see manifest.rs.
+*
+* `budget` is the maximum time in milliseconds the applet can execute before
+* returning, 0 means no limit. Applet is killed and disabled if it runs for
+* longer.
+*
+* Signed manifests can change quota, budget and allowed syscalls at runtime,
+* see `load_image`.
+*
+* `flush` is set for applets which keep state that must be persisted before the
+* system goes down, they are called with FNID_FLUSH during shutdown.
 */
 #[derive(Debug)]
 pub struct Applet {
+	name: &'static str,
+	manifest: manifest::Manifest,
 	ttbr: u64,
 	stack: u64,
 	init: AppletInit,
 	smc:  AppletSmc,
 	data: u64,
 	ready: bool,
+	budget: u64,
+	killed: bool,
+	flush: bool,
+}
+
+/* Applet currently executing in EL0, idx is usize::MAX if none */
+struct Running {
+	idx: usize,
+	fnid: u64,
+	start: u64,
 }
+static mut RUNNING: Running = Running{idx: usize::MAX, fnid: 0, start: 0};
 
 /*
 * Declare all registered applets
 */
 static mut APPLETS: [Applet; 1] = [
-	Applet{smc: storage::smc, init: storage::init, stack: u64::MAX, ttbr: u64::MAX, data: 0, ready: false}
+	Applet{name: "storage", manifest: storage::MANIFEST, smc: storage::smc, init: storage::init, stack: u64::MAX, ttbr: u64::MAX, data: 0, ready: false, budget: 1000, killed: false, flush: true}
 ];
 
+/* Call from normal world waiting for applet to be initialized */
+#[derive(Debug, Clone, Copy)]
+struct PendingCall {
+	idx: usize,
+	args: [u64; 6],
+}
+static mut PENDING: Option<PendingCall> = None;
+
+/*
+* Error codes returned in x2 to normal world when x1 is u64::MAX
+*/
+pub const ERR_NO_APPLET: u64 = 1;
+pub const ERR_NO_MEMORY: u64 = 2;
+pub const ERR_SHUTDOWN:  u64 = 3;
+pub const ERR_TIMEOUT:   u64 = 4;
+/* Image was rejected, x3 contains error from verify */
+pub const ERR_VERIFY:    u64 = 5;
+/* Applet was killed because of data abort, in stack guard page or elsewhere */
+pub const ERR_STACK_OVERFLOW: u64 = 6;
+pub const ERR_FAULT:          u64 = 7;
+/* Call was cancelled and applet didn't return in time */
+pub const ERR_CANCELLED:      u64 = 8;
+/* Applets are executing on another core */
+pub const ERR_BUSY:           u64 = 9;
+
+/* Time applet has to return after its call has been cancelled */
+pub const CANCEL_GRACE_MS: u64 = 500;
+
+/* Time a cancel for a call which hasn't arrived yet is remembered */
+pub const CANCEL_EARLY_MS: u64 = 1000;
+
+/*
+* Call from normal world in progress. `cancel_id` is chosen by normal world, 0
+* means call can't be cancelled. `deadline` is 0 if there is no timeout and
+* `cancelled` is when cancel was requested, 0 if it hasn't been.
+*/
+#[derive(Debug, Default, Clone, Copy)]
+struct NwCall {
+	cancel_id: u64,
+	deadline: u64,
+	cancelled: u64,
+}
+static mut CALL: NwCall = NwCall{cancel_id: 0, deadline: 0, cancelled: 0};
+
+/*
+* Cancel from normal world for a call which isn't in progress, cancel may
+* arrive before the call. `id` is 0 if there is none and `time` is when it was
+* requested.
+*/
+#[derive(Debug, Default, Clone, Copy)]
+struct CancelRequest {
+	id: u64,
+	time: u64,
+}
+static mut CANCEL_REQUEST: CancelRequest = CancelRequest{id: 0, time: 0};
+
+/*
+* Core which is executing applets, u32::MAX if none. APPLETS, RUNNING, CALL and
+* the calls between applets in client are shared by all cores, so only one core
+* executes applets at a time. It's taken when a call from normal world enters
+* and released before returning to normal world.
+*/
+static EL0_OWNER: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/* Take EL0 for this core, false if another core has it */
+fn el0_acquire() -> bool {
+	let id = cpu::id();
+	return match EL0_OWNER.compare_exchange(u32::MAX, id, Ordering::Acquire, Ordering::Relaxed) {
+		Ok(_) => true,
+		Err(owner) => owner == id,
+	};
+}
+
+/* Release EL0 if this core has it */
+fn el0_release() {
+	let _ = EL0_OWNER.compare_exchange(cpu::id(), u32::MAX, Ordering::Release, Ordering::Relaxed);
+}
+
+/* Set when system is powering down, no more applets are executed */
+static mut SHUTDOWN: bool = false;
+
+/* Applet persisting its state during shutdown */
+static mut FLUSHING: Option<usize> = None;
+
+/*
+* Function id applets with `flush` set are called with during shutdown, other
+* arguments are 0. Applet must persist its state and exit.
+*/
+pub const FNID_FLUSH: u64 = u64::MAX;
+
 // Empty function if applet doesn't need any initialization
 // Is ignored below and not called, but if called, it would be valid
 fn init_el0_empty() { svc!(cpu::svc::SYSNO_EXIT); }
@@ -128,85 +246,640 @@ pub fn __exec_in_el0(app: &Applet, state: &mut interrupt::InterruptState)	{
 	state.spsr = 0;
 	state.saved_sp = app.stack;
 
+	let running = unsafe { &mut RUNNING };
+	running.idx = unsafe { APPLETS.iter().position(|a| core::ptr::eq(a, app)) }.unwrap_or(usize::MAX);
+	running.fnid = state.regs[1];
+	running.start = timer::now_ms();
+
 	mmu::switch_ttbr1(app.ttbr);
 	unsafe { drop_el0(&state) };
 }
 
+/**
+* Create address space for applet if it doesn't have one. Returns -1 if we run
+* out of memory.
+*/
 fn init_session(app: &mut Applet) -> i32 {
 	if app.ttbr == u64::MAX {
 		let ttbr = mmu::alloc_pgd();
+		if ttbr == u64::MAX {
+			return -1;
+		}
+		mmu::set_quota(ttbr, app.manifest.quota);
 
-		// Allocate stack at highest available memory
-		let stackva = mmu::maxmem_upper!() - mmu::PAGE_SIZE;
-		mmu::alloc_page(ttbr, stackva, mmu::EL0_RW);
-		app.stack = stackva + mmu::PAGE_SIZE;
+		// Allocate stack at highest available memory, page below it is left
+		// unmapped as a guard page
+		let stackva = mmu::maxmem_upper!() - mmu::PAGE_SIZE * app.manifest.stack;
+		if mmu::alloc_pages(ttbr, stackva, app.manifest.stack as i32, mmu::EL0_RW) < 0 {
+			mmu::destroy_address_space(ttbr);
+			return -1;
+		}
+		app.stack = mmu::maxmem_upper!();
 		app.ttbr = ttbr;
 	}
 	return 0;
 }
 
-fn exec_smc(idx: usize, fnid: u64, cmd: u64, mut arg: u64, len: u64) -> i32 {
+/**
+* Tear down address space for applet and free all memory it has used. A new
+* session is created and init is called again the next time applet is called.
+*/
+fn close_session(app: &mut Applet) {
+	if app.ttbr != u64::MAX {
+		mmu::destroy_address_space(app.ttbr);
+		app.ttbr = u64::MAX;
+		app.stack = u64::MAX;
+	}
+	app.ready = false;
+	app.data = 0;
+}
+
+/**
+* Close session from normal world to applet at `idx`, its instance is torn down
+* and all sessions it has opened to other applets are closed. Returns -1 if
+* applet doesn't exist, is executing or other applets have sessions to it.
+*/
+pub fn close(idx: usize) -> i32 {
+	if ! el0_acquire() {
+		return -1;
+	}
+	let mut ret = -1;
+	if idx < count() && running() != idx && ! client::has_callers(idx) {
+		client::close_all(idx);
+		close_session(unsafe { &mut APPLETS[idx] });
+		ret = 0;
+	}
+	el0_release();
+	return ret;
+}
+
+/**
+* Run init for applet at `idx`, returns -1 if no session could be created and
+* does not return otherwise.
+*/
+fn init_applet(idx: usize) -> i32 {
+	let app = unsafe { &mut APPLETS[idx] };
+	app.ready = true;
+	if app.init != init_el0_empty {
+		if init_session(app) < 0 {
+			log::info("Unable to create session for applet");
+			return -1;
+		}
+		exec_in_el0!(app);
+	}
+	return 0;
+}
+
+/**
+* Returns negative ERR_* value on error, does not return on success.
+*/
+fn exec_smc(idx: usize, fnid: u64, cmd: u64, mut arg: u64, len: u64, cancel_id: u64, timeout: u64) -> i32 {
+	if unsafe { SHUTDOWN } {
+		return -(ERR_SHUTDOWN as i32);
+	}
 	let mlen = unsafe { APPLETS.len() };
 	if idx < mlen {
 		let app = unsafe { &mut APPLETS[idx] };
-		init_session(app);
+		if app.killed {
+			return -(ERR_NO_APPLET as i32);
+		}
+		if ! app.ready {
+			// Session was closed, call is continued when init is done
+			unsafe { PENDING = Some(PendingCall{idx: idx, args: [fnid, cmd, arg, len, cancel_id, timeout]}); }
+			if init_applet(idx) < 0 {
+				unsafe { PENDING = None; }
+				return -(ERR_NO_MEMORY as i32);
+			}
+			unsafe { PENDING = None; }
+		}
+		if init_session(app) < 0 {
+			return -(ERR_NO_MEMORY as i32);
+		}
 		if len > 0 {
-			mmu::memcpy_ns(app.ttbr, mmu::VA_RESERVED_START, arg, len, true);
+			if mmu::memcpy_ns(app.ttbr, mmu::VA_RESERVED_START, arg, len, true) < 0 {
+				return -(ERR_NO_MEMORY as i32);
+			}
 			arg = mmu::VA_RESERVED_START;
 		}
+		start_call(cancel_id, timeout);
 		exec_in_el0!(app, fnid, cmd, arg, len);
 		return 0;
 	}
-	return -1;
+	return -(ERR_NO_APPLET as i32);
 }
 
+/**
+* Call applet `func` from normal world, x1-x3 are passed to the applet, x4 is
+* the cancel id and x5 is timeout in milliseconds, 0 means no timeout.
+*/
 pub fn smc_handler(func: u64, args: &mut [u64; 8]) {
+	if ! el0_acquire() {
+		args[1] = u64::MAX;
+		args[2] = ERR_BUSY;
+		args[3] = 0;
+		return;
+	}
 	// func is 64b but only lowest 16b can be set
-	let ret = exec_smc(func as usize, args[0], args[1], args[2], args[3]);
+	let ret = exec_smc(func as usize, args[0], args[1], args[2], args[3], args[4], args[5]);
+	el0_release();
 
 	if ret < 0 {
 		// Keep args[0] intact since it's the function id
 		args[1] = u64::MAX;
-		args[2] = 0;
+		args[2] = (-ret) as u64;
 		args[3] = 0;
 	} else {
 		args[1] = ret as u64;
 	}
 }
 
-pub fn store_ptr(addr: u64) -> u64 {
+/**
+* Page directory used by applet at `idx`, u64::MAX if applet doesn't exist or
+* has no session.
+*/
+pub fn ttbr(idx: usize) -> u64 {
 	let len = unsafe { APPLETS.len() };
-	let mut fix = usize::MAX;
-	for i in 0..len {
+	if idx < len {
+		return unsafe { APPLETS[idx].ttbr };
+	}
+	return u64::MAX;
+}
+
+/**
+* Number of registered applets.
+*/
+pub fn count() -> usize {
+	return unsafe { APPLETS.len() };
+}
+
+/**
+* Name of applet at `idx`, None if applet doesn't exist.
+*/
+pub fn name(idx: usize) -> Option<&'static str> {
+	let len = unsafe { APPLETS.len() };
+	if idx < len {
+		return Some(unsafe { APPLETS[idx].name });
+	}
+	return None;
+}
+
+/**
+* Verify signed image in `blob` and apply it. Applet manifests update limits of
+* the applet with the same UUID, configuration payloads are stored for the
+* applet. Version counter is only raised after image has been applied.
+*
+* Returns (0, 0) on success, (-ERR_VERIFY, error from verify) if image is
+* rejected, (-ERR_NO_APPLET, 0) if no applet has the UUID and (-ERR_BUSY, 0) if
+* applets are executing on another core.
+*/
+pub fn load_image(blob: &[u8]) -> (i32, i32) {
+	if ! el0_acquire() {
+		return (-(ERR_BUSY as i32), 0);
+	}
+	let ret = apply_image(blob);
+	el0_release();
+	return ret;
+}
+
+fn apply_image(blob: &[u8]) -> (i32, i32) {
+	if ! verify::PERSISTENT_COUNTERS {
+		log::info("Rollback counters are not persistent, image rejected");
+		return (-(ERR_VERIFY as i32), verify::ERR_NO_STORE);
+	}
+	let mut man = verify::Manifest::default();
+	let ret = verify::verify_image(blob, &mut man);
+	if ret < 0 {
+		log::info("Image rejected");
+		return (-(ERR_VERIFY as i32), ret);
+	}
+	let idx = match unsafe { APPLETS.iter().position(|a| a.manifest.uuid == man.uuid) } {
+		Some(i) => i,
+		None => { return (-(ERR_NO_APPLET as i32), 0); }
+	};
+	let app = unsafe { &mut APPLETS[idx] };
+	if man.kind == verify::KIND_APPLET {
+		app.manifest.quota = man.quota as u64;
+		app.manifest.syscalls = man.perms;
+		app.budget = man.budget as u64;
+		if app.ttbr != u64::MAX {
+			mmu::set_quota(app.ttbr, app.manifest.quota);
+		}
+	} else {
+		let end = man.payload_offset + man.payload_size;
+		verify::store_config(&man.uuid, &blob[man.payload_offset..end]);
+	}
+	let ret = verify::commit_version(&man);
+	if ret < 0 {
+		return (-(ERR_VERIFY as i32), ret);
+	}
+	return (0, 0);
+}
+
+/**
+* Copy configuration loaded for the running applet to buffer at `addr` with room
+* for `len` bytes, both must be multiples of 8. Nothing is copied if buffer is
+* too small. Returns size of configuration or u64::MAX if there is none or
+* buffer isn't writable.
+*/
+pub fn config(addr: u64, len: u64) -> u64 {
+	let idx = running();
+	if idx >= count() || addr % 8 != 0 || len % 8 != 0 {
+		return u64::MAX;
+	}
+	let app = unsafe { &APPLETS[idx] };
+	let payload = match verify::config(&app.manifest.uuid) {
+		Some(p) => p,
+		None => { return u64::MAX; }
+	};
+	let size = payload.len() as u64;
+	if size > len {
+		return size;
+	}
+	let words: Vec<u64> = payload.chunks(8).map(|c| {
+		let mut w = [0u8; 8];
+		w[..c.len()].copy_from_slice(c);
+		u64::from_le_bytes(w)
+	}).collect();
+	if mmu::copy_to_el0(app.ttbr, addr, &words) < 0 {
+		return u64::MAX;
+	}
+	return size;
+}
+
+/**
+* Store pointer passed to the running applet on every call, only allowed from
+* init.
+*/
+pub fn store_ptr(addr: u64) -> u64 {
+	let running = unsafe { &RUNNING };
+	if running.idx == usize::MAX || running.fnid != 0 {
+		return u64::MAX;
+	}
+	unsafe { APPLETS[running.idx].data = addr; }
+	return 0;
+}
+
+/*
+* Denied requests from applets, AUDIT_NEXT is where next entry is written
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct AuditEntry {
+	pub applet: usize,
+	pub sysno: u64,
+	pub arg: u64,
+	pub time: u64,
+}
+const AUDIT_ENTRIES: usize = 16;
+static mut AUDIT: [AuditEntry; AUDIT_ENTRIES] = [AuditEntry{applet: usize::MAX, sysno: 0, arg: 0, time: 0}; AUDIT_ENTRIES];
+static mut AUDIT_NEXT: usize = 0;
+
+fn deny(idx: usize, sysno: u64, arg: u64, msg: &str) -> bool {
+	log::info(msg);
+	if idx < count() {
+		log::info(unsafe { APPLETS[idx].name });
+	}
+	unsafe {
+		AUDIT[AUDIT_NEXT % AUDIT_ENTRIES] = AuditEntry{applet: idx, sysno: sysno, arg: arg, time: timer::now_ms()};
+		AUDIT_NEXT += 1;
+	}
+	return false;
+}
+
+/**
+* Entry `n` in audit log, 0 is the newest. None if there are fewer entries.
+*/
+pub fn audit(n: usize) -> Option<AuditEntry> {
+	let next = unsafe { AUDIT_NEXT };
+	if n >= AUDIT_ENTRIES || n >= next {
+		return None;
+	}
+	return Some(unsafe { AUDIT[(next - 1 - n) % AUDIT_ENTRIES] });
+}
+
+/**
+* Check that running applet has declared `sysno` in its manifest. Denied calls
+* are added to the audit log.
+*/
+pub fn allow_syscall(sysno: u64) -> bool {
+	let idx = unsafe { RUNNING.idx };
+	if idx >= count() {
+		return deny(idx, sysno, 0, "audit: syscall without running applet");
+	}
+	if ! unsafe { APPLETS[idx].manifest.allows_syscall(sysno) } {
+		return deny(idx, sysno, 0, "audit: syscall denied");
+	}
+	return true;
+}
+
+/**
+* Check that running applet has declared `pin` in its manifest.
+*/
+pub fn allow_gpio(pin: u32) -> bool {
+	let idx = unsafe { RUNNING.idx };
+	if idx >= count() || ! unsafe { APPLETS[idx].manifest.allows_gpio(pin) } {
+		return deny(idx, cpu::svc::SYSNO_GPIO, pin as u64, "audit: GPIO denied");
+	}
+	return true;
+}
+
+/**
+* Index of applet executing in EL0, usize::MAX if none.
+*/
+pub fn running() -> usize {
+	return unsafe { RUNNING.idx };
+}
+
+/**
+* Called when applet has returned from EL0.
+*/
+pub fn exited() {
+	unsafe { RUNNING.idx = usize::MAX; }
+	end_call();
+	el0_release();
+}
+
+fn start_call(cancel_id: u64, timeout: u64) {
+	let now = timer::now_ms();
+	let call = unsafe { &mut CALL };
+	call.cancel_id = cancel_id;
+	call.deadline = if timeout > 0 { now.saturating_add(timeout) } else { 0 };
+	call.cancelled = 0;
+	let req = unsafe { &mut CANCEL_REQUEST };
+	if early_cancel(req, cancel_id, now) {
+		call.cancelled = now;
+		*req = CancelRequest::default();
+	} else if now - req.time > CANCEL_EARLY_MS {
+		*req = CancelRequest::default();
+	}
+}
+fn end_call() {
+	unsafe { CALL = NwCall::default(); }
+}
+
+/**
+* Check if cancel requested before call with `cancel_id` started at `now`
+* applies to it.
+*/
+fn early_cancel(req: &CancelRequest, cancel_id: u64, now: u64) -> bool {
+	return cancel_id != 0 && req.id == cancel_id && now - req.time <= CANCEL_EARLY_MS;
+}
+
+/**
+* Request cancellation of call with `cancel_id` from normal world. The call may
+* be in progress or arrive within CANCEL_EARLY_MS. Returns -1 if id is 0.
+*/
+pub fn cancel(cancel_id: u64) -> i32 {
+	if cancel_id == 0 {
+		return -1;
+	}
+	let now = timer::now_ms();
+	unsafe {
+		if CALL.cancel_id == cancel_id {
+			if CALL.cancelled == 0 {
+				CALL.cancelled = now;
+			}
+		} else {
+			CANCEL_REQUEST = CancelRequest{id: cancel_id, time: now};
+		}
+	}
+	return 0;
+}
+
+/**
+* Returns 1 if call from normal world has been cancelled, applets called by
+* other applets see the cancellation of the original call.
+*/
+pub fn cancelled() -> u64 {
+	return if unsafe { CALL.cancelled } != 0 { 1 } else { 0 };
+}
+
+/**
+* Error call should be terminated with at `now`, 0 if call can continue.
+*/
+fn call_expired(call: &NwCall, now: u64) -> u64 {
+	if call.deadline != 0 && now > call.deadline {
+		return ERR_TIMEOUT;
+	}
+	if call.cancelled != 0 && now - call.cancelled > CANCEL_GRACE_MS {
+		return ERR_CANCELLED;
+	}
+	return 0;
+}
+
+/**
+* Kill running applet, disable it and return `code` to normal world.
+*/
+fn kill(code: u64) {
+	stop(code, true);
+}
+
+/**
+* Stop running applet and return `code` to normal world. Its instance is torn
+* down and created again on the next call, unless `disable` is set.
+*/
+fn stop(code: u64, disable: bool) {
+	let running = unsafe { &mut RUNNING };
+	let idx = running.idx;
+	let app = unsafe { &mut APPLETS[idx] };
+	log::info(app.name);
+	running.idx = usize::MAX;
+	app.killed = disable;
+	client::close_all(idx);
+
+	// Applet called by another applet returns to its caller
+	if client::in_call(idx) {
+		client::finish(client::err::TARGET_DEAD, true);
+	}
+	close_session(app);
+	end_call();
+	el0_release();
+
+	// Call waiting for init is answered here, it must not be replayed
+	let pending = unsafe { PENDING.take() };
+
+	// Function id 0 means we were in init, shutdown continues if applet was
+	// flushing its state
+	if flushing() || (running.fnid == 0 && pending.is_none()) {
+		smc::smc_return(running.fnid, 0);
+	} else {
+		smc::smc_return_error(code);
+	}
+}
+
+/**
+* Kill running applet if it has exceeded its time budget. If the call from
+* normal world has timed out or was cancelled without the applet returning, the
+* applet is stopped but not disabled. Must only be called when an exception has
+* been taken from EL0, does not return if applet is stopped.
+*/
+pub fn check_timeouts() {
+	let running = unsafe { &RUNNING };
+	if running.idx == usize::MAX {
+		return;
+	}
+	let app = unsafe { &APPLETS[running.idx] };
+	let now = timer::now_ms();
+	if app.budget != 0 && now - running.start > app.budget {
+		log::info("Applet exceeded time budget, killing it");
+		kill(ERR_TIMEOUT);
+	}
+	let code = call_expired(unsafe { &CALL }, now);
+	if code == ERR_TIMEOUT {
+		log::info("Call timed out, stopping applet");
+		stop(code, false);
+	} else if code == ERR_CANCELLED {
+		log::info("Cancelled call did not return, stopping applet");
+		stop(code, false);
+	}
+}
+
+/**
+* Range of stack including guard page for applet.
+*/
+fn stack_region(app: &Applet) -> (u64, u64) {
+	let top = mmu::maxmem_upper!();
+	return (top - (app.manifest.stack + 1) * mmu::PAGE_SIZE, top);
+}
+
+/**
+* Check if range overlaps stack or guard page of running applet, or the region
+* used for calls between applets.
+*/
+pub fn in_reserved(addr: u64, size: u64) -> bool {
+	let idx = unsafe { RUNNING.idx };
+	if idx >= count() {
+		return false;
+	}
+	let (start, end) = stack_region(unsafe { &APPLETS[idx] });
+	return (addr < end && addr + size > start) || client::in_shared(addr, size);
+}
+
+/**
+* Data or instruction abort at `far` in running applet, kills applet and
+* reports whether it was a stack overflow. Does not return.
+*/
+pub fn fault(far: u64) {
+	let idx = unsafe { RUNNING.idx };
+	if idx >= count() {
+		log::info("Abort without running applet, halting...");
+		loop { }
+	}
+	let (guard, _) = stack_region(unsafe { &APPLETS[idx] });
+	if far >= guard && far < guard + mmu::PAGE_SIZE {
+		log::info("Applet stack overflow, killing it");
+		kill(ERR_STACK_OVERFLOW);
+	} else {
+		log::info("Applet abort, killing it");
+		kill(ERR_FAULT);
+	}
+}
+
+/**
+* Stop all applets before system is powered off or reset. Applets with `flush`
+* set are first called with FNID_FLUSH, this does not return while an applet is
+* flushing and smc_return calls shutdown again when it exits. Every address
+* space is then destroyed so that keys and other data held by applets are
+* scrubbed from memory.
+*/
+pub fn shutdown() {
+	unsafe { SHUTDOWN = true; }
+
+	// Applet on another core returns or is killed within its budget, EL0 is
+	// not released again
+	while ! el0_acquire() {
+		core::hint::spin_loop();
+	}
+	let len = count();
+	let next = unsafe { FLUSHING.map_or(0, |i| i + 1) };
+	for i in next..len {
 		let app = unsafe { &mut APPLETS[i] };
-		if ! app.ready {
-			break;
+		if app.flush && app.ready && ! app.killed && app.ttbr != u64::MAX {
+			unsafe { FLUSHING = Some(i); }
+			exec_in_el0!(app, FNID_FLUSH, 0, 0);
 		}
-		fix = i;
 	}
-	if fix != usize::MAX {
-		let app = unsafe { &mut APPLETS[fix] };
-		app.data = addr;
-		return 0;
+	unsafe { FLUSHING = None; }
+
+	mmu::switch_ttbr1(0);
+	for i in 0..len {
+		let app = unsafe { &mut APPLETS[i] };
+		client::close_all(i);
+		close_session(app);
 	}
-	return u64::MAX;
+	verify::scrub_configs();
+}
+
+/**
+* Check if an applet is persisting its state during shutdown.
+*/
+pub fn flushing() -> bool {
+	return unsafe { FLUSHING.is_some() };
 }
 
 pub fn init() -> u32 {
+	if unsafe { SHUTDOWN } {
+		return 0;
+	}
+	let owned = el0_acquire();
+
+	// Continue call from normal world which was waiting for init, only
+	// returns on error
+	if let Some(call) = unsafe { PENDING.take() } {
+		let a = call.args;
+		let ret = if ! owned {
+			-(ERR_BUSY as i32)
+		} else if unsafe { APPLETS[call.idx].ready } {
+			exec_smc(call.idx, a[0], a[1], a[2], a[3], a[4], a[5])
+		} else {
+			-(ERR_NO_APPLET as i32)
+		};
+		el0_release();
+		smc::smc_return_error((-ret) as u64);
+		return 0;
+	}
+	if ! owned {
+		return 0;
+	}
+
 	let len = unsafe { APPLETS.len() };
 	for i in 0..len {
-		let app = unsafe { &mut APPLETS[i] };
-		if ! app.ready {
-			app.ready = true;
-			if app.init != init_el0_empty {
-				init_session(app);
-				exec_in_el0!(app);
-			}
+		let app = unsafe { &APPLETS[i] };
+		if ! app.ready && ! app.killed {
+			init_applet(i);
 		}
 	}
+	el0_release();
 	return 0;
 }
 
 // Can be used to place data in specific sections
 // #[link_section = ".usermode"]
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn call_timeouts() {
+		let mut call = NwCall{cancel_id: 7, deadline: 0, cancelled: 0};
+		assert_eq!(call_expired(&call, 1000), 0);
+
+		call.deadline = 1500;
+		assert_eq!(call_expired(&call, 1500), 0);
+		assert_eq!(call_expired(&call, 1501), ERR_TIMEOUT);
+
+		call.deadline = 0;
+		call.cancelled = 1000;
+		assert_eq!(call_expired(&call, 1000 + CANCEL_GRACE_MS), 0);
+		assert_eq!(call_expired(&call, 1001 + CANCEL_GRACE_MS), ERR_CANCELLED);
+	}
+
+	#[test]
+	fn cancel_before_call() {
+		let req = CancelRequest{id: 7, time: 1000};
+		assert!(early_cancel(&req, 7, 1000 + CANCEL_EARLY_MS));
+		assert!(! early_cancel(&req, 7, 1001 + CANCEL_EARLY_MS));
+		assert!(! early_cancel(&req, 8, 1000));
+		assert!(! early_cancel(&CancelRequest::default(), 0, 0));
+	}
+}
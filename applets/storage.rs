@@ -1,5 +1,6 @@
 use applets;
 use applets::arch_svc;
+use applets::manifest::{Manifest, sysno_bit};
 use driver::mmu;
 use lib::math;
 use cpu;
@@ -13,6 +14,17 @@ use core::borrow::Borrow;
 // static mut GVAR: i32 = 0;
 
 
+pub const MANIFEST: Manifest = Manifest{
+	// 927c477b-8a8d-4eb9-ba95-b8df28ea19bd
+	uuid: [0x92, 0x7c, 0x47, 0x7b, 0x8a, 0x8d, 0x4e, 0xb9, 0xba, 0x95, 0xb8, 0xdf, 0x28, 0xea, 0x19, 0xbd],
+	stack: 1,
+	quota: 192,
+	multi_instance: false,
+	syscalls: sysno_bit(cpu::svc::SYSNO_MMAP) | sysno_bit(cpu::svc::SYSNO_MUNMAP) | sysno_bit(cpu::svc::SYSNO_STORE_PTR),
+	gpios: &[],
+	peers: &[],
+};
+
 const MEMORY_REGION: u64 = mmu::maxmem_upper!() - (mmu::PAGE_SIZE * 128);
 
 pub fn init() {
@@ -27,6 +39,10 @@ pub fn init() {
 }
 
 pub fn smc(data: u64, func: u64, _cmd: u64, _arg: u64, _len: u64)	{
+	if func == applets::FNID_FLUSH {
+		// No state is persisted yet, acknowledge so shutdown can continue
+		applets::svc!(cpu::svc::SYSNO_EXIT, func, 0);
+	}
 	let x = unsafe { Box::from_raw(data as *mut u64) };
 	let val = x.borrow();
 	applets::svc!(cpu::svc::SYSNO_EXIT, func, *val);
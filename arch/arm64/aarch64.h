@@ -18,13 +18,17 @@
 
 #define OPTEE_ENTRY_DONE OPTEE_ID(0)
 
-//#ifndef MAX_CPUS
-//# define MAX_CPUS 2
-//#endif
+/* Cores with their own exception stack, must be a power of 2 */
+#ifndef MAX_CPUS
+# define MAX_CPUS 8
+#endif
 
 //#define IMAGE_LOAD 0x42000
 
 
+/* Only relocation type in static PIE */
+#define R_AARCH64_RELATIVE (1027)
+
 #define ARM64_PAGE_SIZE (4096)
 #define PAGE_SIZE (ARM64_PAGE_SIZE)
 
@@ -3,27 +3,38 @@ BUILD := debug
 NAME := bl32
 LIBOBJ := target/target/$(BUILD)/lib$(NAME).a
 
-# We must provide an address of where we're loaded in memory. OS will work if
-# it's incorrent, but we must then offset function pointers used, for instance
-# when returning to EL0. If address is unknown, it should be set to 0.
-LOAD_ENTRY_BL32 := 0xe100000
-
 
 ifeq ($(ARCH),arm64)
 	CROSS_COMPILE := aarch64-linux-gnu-
 	TARGET := aarch64-linux-gnu-
 endif
 
+# Image is linked as PIE and relocated at boot, so it can be loaded anywhere
+CFLAGS += -fpie
+
 CC := $(CROSS_COMPILE)gcc
 AS := $(CROSS_COMPILE)gcc
 LD := $(CROSS_COMPILE)ld
 OBJDUMP := $(CROSS_COMPILE)objdump
 OBJCOPY := $(CROSS_COMPILE)objcopy
 
+# Public key which runtime manifests are verified with, required
+#ROOT_KEY := /path/to/root_pub.pem
+
 RUST_OPTS += --cfg gic="2"
 #RUST_OPTS += --cfg spd="tsp"
 RUST_OPTS += --cfg spd="optee"
+# Board used if device tree doesn't match any known board: qemu, sbsa or fvp
 RUST_OPTS += --cfg platform="qemu"
+# Default is 39-bit VA with 3-level page tables
+#RUST_OPTS += --cfg va_bits="48"
+# Freed pages are zeroed by default, uncomment to zero on allocation instead
+#RUST_OPTS += --cfg pmm_scrub="alloc"
+# Randomize linear region with kaslr-seed from /secure-chosen, the image itself
+# is not moved
+#RUST_OPTS += --cfg random_linear
+# Interactive console on secure UART, only for development boards
+#RUST_OPTS += --cfg debug_console
 RUST_OPTS += -C soft-float
 RUST_OPTS += -C panic=abort
 RUST_OPTS += -C opt-level=0
@@ -33,5 +44,7 @@ RUST_OPTS += -C lto=no
 
 ifeq ($(BUILD),debug)
 	RUST_OPTS += -C debuginfo=2
+	# Fill freed pages with a pattern to catch use-after-free
+	RUST_OPTS += --cfg pmm_poison
 endif
 
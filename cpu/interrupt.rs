@@ -1,17 +1,94 @@
 use cpu::svc;
+use cpu::stack;
+use cpu::register;
+use applets;
+use platform;
 use lib::log;
-/*
+use driver::gic;
+use driver::gic::GIC;
+
 #[cfg(gic = "2")] type GicImpl = gic::GICv2;
+#[cfg(gic = "2")] const GIC_VERSION: u32 = 2;
+/*
 //const TSP_IRQ_SEC_PHY_TIMER: u32 = 29;
 const AARCH64_EXC_IRQ_SPX: u64 = 0x12;
 */
 
+const MAX_IRQ_HANDLERS: usize = 8;
+
+type IrqHandler = fn(u32);
+
+/* Secure interrupts we handle, ID is u32::MAX if slot is free */
+static mut HANDLERS: [(u32, IrqHandler); MAX_IRQ_HANDLERS] = [(u32::MAX, irq_unhandled); MAX_IRQ_HANDLERS];
+
+fn irq_unhandled(_id: u32) {
+	log::info("Unhandled secure interrupt");
+}
+
+/**
+* Register handler for secure interrupt and enable it in the GIC. Returns -1 if
+* there is no more room for handlers.
+*/
+pub fn register_irq(id: u32, handler: IrqHandler) -> i32 {
+	if platform::board().gic_version != GIC_VERSION {
+		log::info("Secure interrupts are not supported with this GIC");
+		return -1;
+	}
+	for i in 0..MAX_IRQ_HANDLERS {
+		let slot = unsafe { &mut HANDLERS[i] };
+		if slot.0 == u32::MAX || slot.0 == id {
+			*slot = (id, handler);
+			GicImpl::enable_secure(id);
+			return 0;
+		}
+	}
+	log::info("No more room for interrupt handlers");
+	return -1;
+}
+
+/**
+* Enable interrupt which has already been registered in banked GIC registers of
+* the current core, used for private interrupts on cores powered on after init.
+*/
+pub fn enable_local(id: u32) {
+	if platform::board().gic_version == GIC_VERSION {
+		GicImpl::enable_secure(id);
+	}
+}
+
+/**
+* Handle pending secure interrupt, called when secure monitor hands an
+* interrupt to us.
+*/
+pub fn handle_secure_irq() {
+	let id = GicImpl::acknowledge();
+	if id >= gic::SPURIOUS {
+		return;
+	}
+	let mut handler: IrqHandler = irq_unhandled;
+	for i in 0..MAX_IRQ_HANDLERS {
+		let slot = unsafe { HANDLERS[i] };
+		if slot.0 == id {
+			handler = slot.1;
+			break;
+		}
+	}
+	handler(id);
+	GicImpl::eoi(id);
+}
+
+const AARCH64_EXC_SYNC_SPX:     u64 = 0x11;
 const AARCH64_EXC_SYNC_AARCH64: u64 = 0x21;
+const AARCH64_EXC_FIQ_AARCH64:  u64 = 0x23;
+const AARCH64_EXC_FIQ_SPX:      u64 = 0x13;
 pub mod esr {
 	pub const MASK: u64 = 0b111111;
 	pub mod ec {
 		pub const SMC: u32 = 0b010111;
 		pub const SVC: u32 = 0b010101;
+		pub const IABT_LOWER: u32 = 0b100000;
+		pub const DABT_LOWER: u32 = 0b100100;
+		pub const DABT_CUR:   u32 = 0b100101;
 	}
 
 }
@@ -39,7 +116,12 @@ fn handle_sync_lower(state: &mut InterruptState) {
 				state.regs[0], state.regs[1], state.regs[2], state.regs[3],
 				state.regs[4], state.regs[5], state.regs[6], state.regs[7]
 			];
-			state.regs[0] = svc::handle(sysno, args);
+			let frame = state as *mut InterruptState as u64;
+			state.regs[0] = svc::handle(sysno, args, frame);
+		}
+		esr::ec::IABT_LOWER | esr::ec::DABT_LOWER => {
+			// Does not return
+			applets::fault(register::read::far_el1());
 		}
 		_ => {
 			log::info("Unknown EC, halting...");
@@ -48,15 +130,43 @@ fn handle_sync_lower(state: &mut InterruptState) {
 	}
 }
 
+/**
+* Exception in kernel, we're running on the exception stack since kernel stack
+* may have overflowed.
+*/
+fn handle_sync_current(state: &mut InterruptState) {
+	let ec = get_esr_ec(state.esr);
+	let far = register::read::far_el1();
+	if ec == esr::ec::DABT_CUR && stack::in_guard_el1(far) {
+		log::info("Kernel stack overflow, halting...");
+	} else if ec == esr::ec::DABT_CUR {
+		log::info("Kernel data abort, halting...");
+	} else {
+		log::info("Unknown EC in kernel, halting...");
+	}
+	loop { }
+}
+
 #[no_mangle]
 pub extern "C" fn handle_exception(state: &mut InterruptState)	{
 	match state.exc_type {
+		AARCH64_EXC_SYNC_SPX => {
+			handle_sync_current(state);
+		}
 		/*AARCH64_EXC_IRQ_SPX => {
 			ret = handle_irq(state);
 		}*/
 		AARCH64_EXC_SYNC_AARCH64 => {
 			handle_sync_lower(state);
 		}
+		AARCH64_EXC_FIQ_AARCH64 => {
+			// Interrupted applet, this is where we take back control from it
+			handle_secure_irq();
+			applets::check_timeouts();
+		}
+		AARCH64_EXC_FIQ_SPX => {
+			handle_secure_irq();
+		}
 		_ => {
 			log::info("Unknown exception, halting...");
 			loop { }
@@ -2,14 +2,16 @@ pub mod register;
 pub mod smc;
 pub mod interrupt;
 pub mod svc;
+pub mod timer;
+pub mod stack;
 
+/**
+* Unique id of core from affinity levels 0-2 in MPIDR, boot core on platforms
+* we support is 0.
+*/
 pub fn id() -> u32 {
-	// TODO: This is a quick way to get unique CPU id on qemu
-	// will not necessarily work on other platforms
-	let mut id = register::read::mpidr_el1();
+	let id = register::read::mpidr_el1();
 
-	// Get affinity level
-	id &= 0xff;
-
-	return id as u32;
+	// Aff2, Aff1 and Aff0 are the lowest 24 bits, MT and U bits are masked
+	return (id & 0xffffff) as u32;
 }
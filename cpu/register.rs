@@ -1,118 +1,189 @@
 
-pub mod read {
-	pub fn cntfrq_el0() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, cntfrq_el0", ret = out(reg) ret); }
-		return ret;
-	}
-	pub fn cntpct_el0() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, cntpct_el0", ret = out(reg) ret); }
-		return ret;
-	}
-	pub fn cntvct_el0() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, cntvct_el0", ret = out(reg) ret); }
-		return ret;
-	}
-	pub fn mpidr_el1() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, mpidr_el1", ret = out(reg) ret); }
-		return ret;
-	}
-	pub fn scr_el3() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, scr_el3", ret = out(reg) ret); }
-		return ret;
-	}
-	pub fn tcr_el1() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, tcr_el1", ret = out(reg) ret); }
-		return ret;
-	}
-/*	pub fn ttbr0_el1() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, ttbr0_el1", ret = out(reg) ret); }
-		return ret;
+/*
+* System registers are only accessed through the functions in `read` and
+* `write`. Unit tests run on the host where system registers don't exist, so
+* tests get the stubs in `fake` instead.
+*/
+#[cfg(not(test))]
+pub use self::arm64::{read, write};
+#[cfg(test)]
+pub use self::fake::{read, write};
+
+#[cfg(not(test))]
+mod arm64 {
+	pub mod read {
+		pub fn cntfrq_el0() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, cntfrq_el0", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn cntpct_el0() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, cntpct_el0", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn cntvct_el0() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, cntvct_el0", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn mpidr_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, mpidr_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn scr_el3() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, scr_el3", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn far_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, far_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn id_aa64mmfr0_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, id_aa64mmfr0_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn ctr_el0() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, ctr_el0", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn tcr_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, tcr_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn sctlr_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, sctlr_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn ttbr0_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, ttbr0_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn ttbr1_el1() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, ttbr1_el1", ret = out(reg) ret); }
+			return ret;
+		}
+		pub fn daif() -> u64 {
+			let ret: u64;
+			unsafe { asm!("mrs {ret}, daif", ret = out(reg) ret); }
+			return ret;
+		}
 	}
-	pub fn ttbr1_el1() -> u64 {
-		let ret: u64;
-		unsafe { asm!("mrs {ret}, ttbr1_el1", ret = out(reg) ret); }
-		return ret;
+
+	pub mod write {
+		pub fn cntv_cval_el1(v: u64) {
+			unsafe { asm!("msr cntv_cval_el1, {v}", v = in(reg) v); }
+		}
+		pub fn cntps_cval_el1(v: u64) {
+			unsafe { asm!("msr cntps_cval_el1, {v}", v = in(reg) v); }
+		}
+		pub fn cntps_ctl_el1(v: u64) {
+			unsafe { asm!("msr cntps_ctl_el1, {v}", v = in(reg) v); }
+		}
+		pub fn scr_el3(v: u64) {
+			unsafe { asm!("msr scr_el3, {v}", v = in(reg) v); }
+		}
+		pub fn sctlr_el1(v: u64) {
+			unsafe { asm!("msr sctlr_el1, {v:x}", v = in(reg) v); }
+		}
+		pub fn mair_el1(v: u64) {
+			unsafe { asm!("msr mair_el1, {v:x}", v = in(reg) v); }
+		}
+		pub fn tcr_el1(v: u64) {
+			unsafe { asm!("msr tcr_el1, {v:x}", v = in(reg) v); }
+		}
+		pub fn ttbr0_el1(v: u64) {
+			unsafe { asm!("msr ttbr0_el1, {v:x}", v = in(reg) v); }
+		}
+		pub fn ttbr1_el1(v: u64) {
+			unsafe { asm!("msr ttbr1_el1, {v:x}", v = in(reg) v); }
+		}
+		pub fn daif(v: u64) {
+			unsafe { asm!("msr daif, {v:x}", v = in(reg) v); }
+		}
 	}
-	*/
 }
 
-pub mod write {
-	pub fn cntv_cval_el1(v: u64) {
-		unsafe { asm!("msr cntv_cval_el1, {v}", v = in(reg) v); }
-	}
-	pub fn cntps_cval_el1(v: u64) {
-		unsafe { asm!("msr cntps_cval_el1, {v}", v = in(reg) v); }
-	}
-	pub fn cntps_ctl_el1(v: u64) {
-		unsafe { asm!("msr cntps_ctl_el1, {v}", v = in(reg) v); }
+/*
+* Registers as seen from host tests, writes are ignored and reads return a fixed
+* value. Counter frequency must be non-zero since it's used as divisor.
+*/
+#[cfg(test)]
+mod fake {
+	pub mod read {
+		pub fn cntfrq_el0() -> u64 { return 1_000_000_000; }
+		pub fn cntpct_el0() -> u64 { return 0; }
+		pub fn cntvct_el0() -> u64 { return 0; }
+		pub fn mpidr_el1() -> u64 { return 0; }
+		pub fn scr_el3() -> u64 { return 0; }
+		pub fn far_el1() -> u64 { return 0; }
+		pub fn id_aa64mmfr0_el1() -> u64 { return 0; }
+		pub fn ctr_el0() -> u64 { return 4 << 16; }
+		pub fn tcr_el1() -> u64 { return 0; }
+		pub fn sctlr_el1() -> u64 { return 0; }
+		pub fn ttbr0_el1() -> u64 { return 0; }
+		pub fn ttbr1_el1() -> u64 { return 0; }
+		pub fn daif() -> u64 { return 0; }
 	}
-	pub fn scr_el3(v: u64) {
-		unsafe { asm!("msr scr_el3, {v}", v = in(reg) v); }
+	pub mod write {
+		pub fn cntv_cval_el1(_v: u64) { }
+		pub fn cntps_cval_el1(_v: u64) { }
+		pub fn cntps_ctl_el1(_v: u64) { }
+		pub fn scr_el3(_v: u64) { }
+		pub fn sctlr_el1(_v: u64) { }
+		pub fn mair_el1(_v: u64) { }
+		pub fn tcr_el1(_v: u64) { }
+		pub fn ttbr0_el1(_v: u64) { }
+		pub fn ttbr1_el1(_v: u64) { }
+		pub fn daif(_v: u64) { }
 	}
 }
 
 #[macro_export]
-macro_rules! read_sctlr_el1 {
-	() => {
-		unsafe {
-			let result: u64;
-			asm!("mrs {ret}, sctlr_el1", ret = out(reg) result);
-			result
-		}
-	};
-}
+macro_rules! read_sctlr_el1 { () => { $crate::cpu::register::read::sctlr_el1() }; }
 #[macro_export]
-macro_rules! read_ttbr0_el1 {
-	() => {
-		unsafe {
-			let result: u64;
-			asm!("mrs {ret}, ttbr0_el1", ret = out(reg) result);
-			result
-		}
-	};
-}
+macro_rules! read_ttbr0_el1 { () => { $crate::cpu::register::read::ttbr0_el1() }; }
 #[macro_export]
-macro_rules! read_ttbr1_el1 {
-	() => {
-		unsafe {
-			let result: u64;
-			asm!("mrs {ret}, ttbr1_el1", ret = out(reg) result);
-			result
-		}
-	};
-}
+macro_rules! read_ttbr1_el1 { () => { $crate::cpu::register::read::ttbr1_el1() }; }
 pub(crate) use read_sctlr_el1;
 pub(crate) use read_ttbr0_el1;
 pub(crate) use read_ttbr1_el1;
 
-
-
-
 #[macro_export]
-macro_rules! write_sctlr_el1 { ($v: expr) => { unsafe { asm!("msr sctlr_el1, {v:x}", v = in(reg) $v) }; } }
-
+macro_rules! write_sctlr_el1 { ($v: expr) => { $crate::cpu::register::write::sctlr_el1($v) }; }
 #[macro_export]
-macro_rules! write_tcr_el1 { ($v: expr) => { unsafe { asm!("msr tcr_el1, {v:x}", v = in(reg) $v) }; } }
-
+macro_rules! write_mair_el1 { ($v: expr) => { $crate::cpu::register::write::mair_el1($v) }; }
+#[macro_export]
+macro_rules! write_tcr_el1 { ($v: expr) => { $crate::cpu::register::write::tcr_el1($v) }; }
 #[macro_export]
-macro_rules! write_ttbr0_el1 { ($v: expr) => { unsafe { asm!("msr ttbr0_el1, {v:x}", v = in(reg) $v) }; } }
+macro_rules! write_ttbr0_el1 { ($v: expr) => { $crate::cpu::register::write::ttbr0_el1($v) }; }
 #[macro_export]
-macro_rules! write_ttbr1_el1 { ($v: expr) => { unsafe { asm!("msr ttbr1_el1, {v:x}", v = in(reg) $v) }; } }
+macro_rules! write_ttbr1_el1 { ($v: expr) => { $crate::cpu::register::write::ttbr1_el1($v) }; }
 
 pub(crate) use write_sctlr_el1;
+pub(crate) use write_mair_el1;
 pub(crate) use write_tcr_el1;
 pub(crate) use write_ttbr0_el1;
 pub(crate) use write_ttbr1_el1;
 
 pub mod sctlr {
+	pub const M:   u64 = 1 << 0;
+	pub const C:   u64 = 1 << 2;
 	pub const I:   u64 = 1 << 12;
 	pub const EOS: u64 = 1 << 11;
 	pub const EIS: u64 = 1 << 22;
 }
+
+pub mod daif {
+	pub const F: u64 = 1 << 6;
+	pub const I: u64 = 1 << 7;
+}
@@ -1,6 +1,11 @@
 use log;
 use applets;
 use driver::mmu;
+use driver::pmm;
+use driver::sp805;
+use cpu::interrupt;
+use cpu::timer;
+use platform;
 
 extern "C" {
 	fn return_el3();
@@ -109,6 +114,12 @@ mod tsp {
 	pub fn smc_return(_func: u64, _ret: u64) {
 		panic!();
 	}
+	pub fn smc_return_error(_code: u64) {
+		panic!();
+	}
+	pub fn smc_return_args(_args: &[u64; 8]) {
+		panic!();
+	}
 }
 
 mod optee {
@@ -157,6 +168,12 @@ mod optee {
 	pub fn smc_return(_func: u64, ret: u64) {
 		unsafe { smcret(CALL_DONE, ret, 0, 0); }
 	}
+	pub fn smc_return_error(code: u64) {
+		unsafe { smcret(CALL_DONE, u64::MAX, code, 0); }
+	}
+	pub fn smc_return_args(args: &[u64; 8]) {
+		unsafe { smcret(args[0], args[1], args[2], args[3]); }
+	}
 	pub fn cpu_off(args: &mut [u64; 8]) {
 		args[0] = OFF_DONE;
 	}
@@ -181,6 +198,145 @@ mod optee {
 	}
 }
 
+/**
+* Diagnostics for normal world, functions only return information.
+*/
+mod diag {
+	use log;
+	use applets;
+	use driver::mmu;
+	use driver::pmm;
+
+	pub const SVCID: u64 = 0x3c;
+
+	const FNID_MMU_STATS: u64 = 1;
+	const FNID_PMM_STATS: u64 = 2;
+	/* x1 is entry in audit log, 0 is newest */
+	const FNID_AUDIT:     u64 = 3;
+
+	pub fn smc_handler(func: u64, args: &mut [u64; 8]) {
+		match func {
+			FNID_MMU_STATS => {
+				// args[1] is applet index or u64::MAX for EL1 address space
+				let ttbr;
+				if args[1] == u64::MAX {
+					ttbr = mmu::kernel_ttbr();
+				} else {
+					ttbr = applets::ttbr(args[1] as usize);
+				}
+				let (pages, tables) = mmu::stats(ttbr);
+				args[1] = pages;
+				args[2] = tables;
+			}
+			FNID_PMM_STATS => {
+				let (total, free) = pmm::stats();
+				args[1] = total;
+				args[2] = free;
+			}
+			FNID_AUDIT => {
+				// Applet index and syscall in x1, argument in x2 and time in x3
+				if let Some(e) = applets::audit(args[1] as usize) {
+					args[1] = ((e.applet as u64) << 32) | e.sysno;
+					args[2] = e.arg;
+					args[3] = e.time;
+				} else {
+					args[1] = u64::MAX;
+					args[2] = 0;
+					args[3] = 0;
+				}
+				return;
+			}
+			_ => {
+				log::info("diag: invalid function called");
+				args[1] = u64::MAX;
+				args[2] = 0;
+			}
+		}
+		args[3] = 0;
+	}
+}
+
+/**
+* Control of applet calls and sessions, should be called as fast calls.
+*/
+mod control {
+	use log;
+	use applets;
+
+	pub const SVCID: u64 = 0x3a;
+
+	/* x1 is the applet, the same number as function id used to call it */
+	const FNID_CLOSE_SESSION: u64 = 1;
+	/* x1 is the cancel id given in x4 when the applet was called */
+	const FNID_CANCEL: u64 = 2;
+
+	pub fn smc_handler(func: u64, args: &mut [u64; 8]) {
+		match func {
+			FNID_CLOSE_SESSION => {
+				args[1] = if applets::close(args[1] as usize) < 0 { u64::MAX } else { 0 };
+			}
+			FNID_CANCEL => {
+				args[1] = if applets::cancel(args[1]) < 0 { u64::MAX } else { 0 };
+			}
+			_ => {
+				log::info("control: invalid function called");
+				args[1] = u64::MAX;
+			}
+		}
+		args[2] = 0;
+		args[3] = 0;
+	}
+}
+
+/**
+* Loading of signed images from normal world, see verify module for format.
+*/
+mod image {
+	use log;
+	use applets;
+	use verify;
+	use driver::mmu;
+	use alloc::vec;
+
+	pub const SVCID: u64 = 0x3b;
+
+	/* x1 is physical address of image in normal world memory, x2 is size */
+	const FNID_LOAD: u64 = 1;
+
+	fn load(paddr: u64, len: u64) -> (i32, i32) {
+		if len == 0 || len > verify::MAX_IMAGE_SIZE as u64 {
+			return (-(applets::ERR_VERIFY as i32), verify::ERR_FORMAT);
+		}
+		// Image is copied first so normal world can't change it after it's
+		// verified
+		let mut buf = vec![0u8; len as usize];
+		if mmu::memcpy_ns(mmu::kernel_ttbr(), buf.as_mut_ptr() as u64, paddr, len, false) < 0 {
+			return (-(applets::ERR_NO_MEMORY as i32), 0);
+		}
+		return applets::load_image(&buf);
+	}
+
+	pub fn smc_handler(func: u64, args: &mut [u64; 8]) {
+		match func {
+			FNID_LOAD => {
+				let (ret, detail) = load(args[1], args[2]);
+				if ret < 0 {
+					args[1] = u64::MAX;
+					args[2] = (-ret) as u64;
+					args[3] = (-detail) as u64;
+					return;
+				}
+				args[1] = 0;
+			}
+			_ => {
+				log::info("image: invalid function called");
+				args[1] = u64::MAX;
+				args[2] = 0;
+			}
+		}
+		args[3] = 0;
+	}
+}
 
 /**
 * Generic wrapper implementing SMC calling convention
@@ -190,6 +346,9 @@ mod optee {
 * - x0[0:15] contain function id
 * - x0[24:29] is service call range, see TF-A
 *   - 0x72 is Test Secure Payload
+*   - 0x3a is cancellation of applet calls and closing of sessions
+*   - 0x3b is loading of signed images
+*   - 0x3c is diagnostics
 * - x0[30] If set, SMC64 is used
 * - x0[31] Fast call is used
 * - x1-x7 contain the arguments
@@ -219,6 +378,15 @@ pub extern "C" fn smc_handler(args: &mut [u64; 8])	{
 		0x3d => {
 			applets::smc_handler(func, args);
 		}
+		control::SVCID => {
+			control::smc_handler(func, args);
+		}
+		image::SVCID => {
+			image::smc_handler(func, args);
+		}
+		diag::SVCID => {
+			diag::smc_handler(func, args);
+		}
 		_ => {
 			log::info("Invalid svc range\n");
 		}
@@ -230,36 +398,76 @@ extern "C" fn smc_cpu_off(args: &mut [u64; 8])	{
 	//log::logf(format_args!("cpu off id: {}\n", cid));
 	//for i in &mut args[1..8] { *i = 0 }
 	//args[0] = tsp::OFF_DONE;
+	timer::stop_cpu();
 	#[cfg(spd = "tsp")]   tsp::cpu_off(args);
 	#[cfg(spd = "optee")] optee::cpu_off(args);
 }
 #[no_mangle]
 extern "C" fn smc_cpu_suspend(args: &mut [u64; 8])	{
+	timer::stop_cpu();
 	#[cfg(spd = "tsp")]   tsp::cpu_suspend(args);
 	#[cfg(spd = "optee")] optee::cpu_suspend(args);
 }
 #[no_mangle]
 extern "C" fn smc_cpu_resume(args: &mut [u64; 8])	{
+	timer::init_cpu();
 	#[cfg(spd = "tsp")]   tsp::cpu_resume(args);
 	#[cfg(spd = "optee")] optee::cpu_resume(args);
 }
+
+/* Set when system reset is requested, power off otherwise */
+static mut POWER_RESET: bool = false;
+
+/**
+* Stop applets and scrub their memory before system goes down. Does not return
+* while an applet is flushing its state, smc_return calls it again when the
+* applet exits.
+*/
+fn power_down() {
+	sp805::stop();
+	applets::shutdown();
+	pmm::scrub_free();
+}
+
+/**
+* Power off or reset platform and set response to EL3 in `args`.
+*/
+fn power_done(args: &mut [u64; 8]) {
+	if unsafe { POWER_RESET } {
+		if platform::power_reset() {
+			log::info("Platform did not reset");
+		}
+		#[cfg(spd = "tsp")]   tsp::system_reset(args);
+		#[cfg(spd = "optee")] optee::system_reset(args);
+	} else {
+		if platform::power_off() {
+			log::info("Platform did not power off");
+		}
+		#[cfg(spd = "tsp")]   tsp::system_off(args);
+		#[cfg(spd = "optee")] optee::system_off(args);
+	}
+}
 #[no_mangle]
 extern "C" fn smc_system_off(args: &mut [u64; 8])	{
-	#[cfg(spd = "tsp")]   tsp::system_off(args);
-	#[cfg(spd = "optee")] optee::system_off(args);
+	unsafe { POWER_RESET = false; }
+	power_down();
+	power_done(args);
 }
 #[no_mangle]
 extern "C" fn smc_system_reset(args: &mut [u64; 8])	{
-	#[cfg(spd = "tsp")]   tsp::system_reset(args);
-	#[cfg(spd = "optee")] optee::system_reset(args);
+	unsafe { POWER_RESET = true; }
+	power_down();
+	power_done(args);
 }
 #[no_mangle]
 extern "C" fn smc_cpu_on(args: &mut [u64; 8])	{
+	timer::init_cpu();
 	#[cfg(spd = "tsp")]   tsp::cpu_on(args);
 	#[cfg(spd = "optee")] optee::cpu_on(args);
 }
 #[no_mangle]
 extern "C" fn smc_fiq_entry(args: &mut [u64; 8])	{
+	interrupt::handle_secure_irq();
 	#[cfg(spd = "optee")] optee::fiq_entry(args);
 }
 
@@ -274,7 +482,14 @@ extern "C" fn smc_fiq_entry(args: &mut [u64; 8])	{
 * the next applet.
 */
 pub fn smc_return(func: u64, ret: u64)	{
-	if func == 0 {
+	if applets::flushing() {
+		// Applet has persisted its state, continue going down
+		power_down();
+		let mut args = [0u64; 8];
+		power_done(&mut args);
+		#[cfg(spd = "tsp")]   tsp::smc_return_args(&args);
+		#[cfg(spd = "optee")] optee::smc_return_args(&args);
+	} else if func == 0 {
 		// Using a function id of 0 indicate that this is a reponse to init-function
 		log::info("Back in smc_return");
 
@@ -289,3 +504,12 @@ pub fn smc_return(func: u64, ret: u64)	{
 		#[cfg(spd = "optee")] optee::smc_return(func, ret);
 	}
 }
+
+/**
+* Return error `code` to normal world when applet could not finish, uses the
+* same format as applets::smc_handler.
+*/
+pub fn smc_return_error(code: u64) {
+	#[cfg(spd = "tsp")]   tsp::smc_return_error(code);
+	#[cfg(spd = "optee")] optee::smc_return_error(code);
+}
@@ -0,0 +1,67 @@
+/**
+* Kernel stacks with guard pages.
+*
+* Every kernel stack has an unmapped guard page directly below it, so an
+* overflow results in a data abort instead of silently overwriting whatever is
+* mapped below. The guard pages are recorded so that the abort handler can
+* report the overflow.
+*/
+use driver::mmu;
+use lib::math;
+
+/*
+* Number of pages in each kernel stack, not including guard page. Calls between
+* applets are nested on the kernel stack.
+*/
+pub const KERNEL_STACK_PAGES: u64 = 4;
+
+/* One stack per core */
+const MAX_KERNEL_STACKS: usize = 8;
+
+/* Guard page for each allocated stack, u64::MAX if slot is free */
+static mut GUARDS: [u64; MAX_KERNEL_STACKS] = [u64::MAX; MAX_KERNEL_STACKS];
+
+fn range_available(vaddr: u64, pages: u64) -> bool {
+	for i in 0..pages {
+		if ! mmu::page_available_el1(vaddr + i * mmu::PAGE_SIZE) {
+			return false;
+		}
+	}
+	return true;
+}
+
+/**
+* Allocate new kernel stack at the highest available address in lower half and
+* return the top of it. Returns u64::MAX if there is no more room.
+*/
+pub fn alloc_el1() -> u64 {
+	let slot = match unsafe { GUARDS.iter().position(|g| *g == u64::MAX) } {
+		Some(i) => i,
+		None => { return u64::MAX; }
+	};
+
+	// Range we need is guard page and stack
+	let size = (KERNEL_STACK_PAGES + 1) * mmu::PAGE_SIZE;
+	let mut top = mmu::maxmem_lower!() - mmu::PAGE_SIZE;
+	while ! range_available(top - size, KERNEL_STACK_PAGES + 1) {
+		top -= size;
+	}
+	let guard = top - size;
+	if mmu::alloc_pages_el1(guard + mmu::PAGE_SIZE, KERNEL_STACK_PAGES as i32, mmu::EL1_RW) < 0 {
+		return u64::MAX;
+	}
+	unsafe { GUARDS[slot] = guard; }
+	return top;
+}
+
+/**
+* Check if `addr` is in the guard page of a kernel stack.
+*/
+pub fn in_guard_el1(addr: u64) -> bool {
+	for g in unsafe { GUARDS.iter() } {
+		if *g != u64::MAX && addr >= *g && addr < *g + mmu::PAGE_SIZE {
+			return true;
+		}
+	}
+	return false;
+}
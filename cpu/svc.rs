@@ -6,12 +6,35 @@ pub const SYSNO_EXIT:   u64 = 1;
 pub const SYSNO_MMAP:   u64 = 2;
 pub const SYSNO_MUNMAP: u64 = 3;
 pub const SYSNO_STORE_PTR: u64 = 4;
+pub const SYSNO_GPIO:   u64 = 5;
+pub const SYSNO_OPEN_SESSION:  u64 = 6;
+pub const SYSNO_INVOKE:        u64 = 7;
+pub const SYSNO_CLOSE_SESSION: u64 = 8;
+pub const SYSNO_CANCELLED:     u64 = 9;
+pub const SYSNO_CONFIG:        u64 = 10;
 
-pub fn handle(sysno: u64, args: [u64; 8]) -> u64 {
+/* Returned when syscall isn't declared in applet manifest */
+pub const ERR_DENIED: u64 = u64::MAX - 1;
+
+/**
+* Handle syscall from applet, `frame` is the address of the applet's saved
+* state and is only used when another applet is invoked.
+*/
+pub fn handle(sysno: u64, args: [u64; 8], frame: u64) -> u64 {
 	let ret: u64;
+	if ! applets::allow_syscall(sysno) {
+		return ERR_DENIED;
+	}
 	match sysno {
 		SYSNO_EXIT => {
 			ret = 0;
+
+			// Applet called by another applet returns to its caller
+			if applets::client::in_call(applets::running()) {
+				applets::client::finish(args[1], false);
+			}
+			applets::exited();
+
 			// Need to perform smc call back to S-EL3
 			smc::smc_return(args[0], args[1]);
 
@@ -26,6 +49,25 @@ pub fn handle(sysno: u64, args: [u64; 8]) -> u64 {
 		SYSNO_STORE_PTR => {
 			ret = applets::store_ptr(args[0]);
 		}
+		SYSNO_GPIO => {
+			ret = syscall::gpio(args[0], args[1] as u32, args[2]);
+		}
+		SYSNO_OPEN_SESSION => {
+			ret = applets::client::open(args[0], args[1]);
+		}
+		SYSNO_INVOKE => {
+			// Only returns on error
+			ret = applets::client::invoke(args[0], args[1], args[2], frame);
+		}
+		SYSNO_CLOSE_SESSION => {
+			ret = applets::client::close(args[0]);
+		}
+		SYSNO_CANCELLED => {
+			ret = applets::cancelled();
+		}
+		SYSNO_CONFIG => {
+			ret = applets::config(args[0], args[1]);
+		}
 		_ => { return u64::MAX; }
 	}
 	return ret;
@@ -0,0 +1,170 @@
+/**
+* Periodic tick from the secure physical timer.
+*
+* The tick lets us take back control from applets which run for too long and
+* keeps the hardware watchdog from resetting the system. Each core checks in on
+* its own tick and the watchdog is kicked only once every online core has
+* checked in since the last kick, so a single core hanging with interrupts
+* masked results in a reset. Timer is started on the boot core by init and on
+* other cores by init_cpu when they are powered on, stop_cpu takes a core out
+* of the check-in when it's powered off or suspended.
+*/
+use core::sync::atomic::{AtomicU32, Ordering};
+use cpu;
+use cpu::register;
+use cpu::interrupt;
+use driver::dtb;
+use driver::gic;
+use driver::sp805;
+use lib::log;
+use platform;
+
+pub const TICK_MS: u64 = 100;
+
+/* Timer control register */
+const CTL_ENABLE: u64 = 1 << 0;
+
+static mut TICKS: u64 = 0;
+
+/* Interrupt for secure timer, u32::MAX until init has run */
+static mut IRQ: u32 = u32::MAX;
+
+/* Same as MAX_CPUS in aarch64.h, cores are indexed by Aff0 */
+const MAX_CPUS: u32 = 8;
+
+/* Cores with a running tick and cores which have ticked since last kick */
+static ONLINE: AtomicU32 = AtomicU32::new(0);
+static CHECKED_IN: AtomicU32 = AtomicU32::new(0);
+
+fn cpu_bit() -> u32 {
+	return 1 << (cpu::id() & (MAX_CPUS - 1));
+}
+
+/*
+* Record tick of core `bit`, returns true if every online core has now checked
+* in. Check-in is cleared then so that the next kick waits for all cores again.
+*/
+fn check_in(bit: u32) -> bool {
+	let seen = CHECKED_IN.fetch_or(bit, Ordering::SeqCst) | bit;
+	let online = ONLINE.load(Ordering::SeqCst);
+	if seen & online != online {
+		return false;
+	}
+	CHECKED_IN.fetch_and(! seen, Ordering::SeqCst);
+	return true;
+}
+
+fn arm_timer() {
+	let ticks = (register::read::cntfrq_el0() * TICK_MS) / 1000;
+	register::write::cntps_cval_el1(register::read::cntpct_el0() + ticks);
+}
+
+/**
+* Number of ticks on the boot core since timer was started.
+*/
+pub fn ticks() -> u64 {
+	return unsafe { TICKS };
+}
+
+/**
+* Milliseconds since boot according to system counter.
+*/
+pub fn now_ms() -> u64 {
+	return counter_ms(register::read::cntpct_el0(), register::read::cntfrq_el0());
+}
+
+/* Convert counter value to milliseconds without overflowing */
+fn counter_ms(cnt: u64, frq: u64) -> u64 {
+	return (cnt / frq) * 1000 + ((cnt % frq) * 1000) / frq;
+}
+
+pub fn handle_interrupt(_id: u32) {
+	if cpu::id() == 0 {
+		unsafe { TICKS += 1; }
+	}
+	arm_timer();
+	if check_in(cpu_bit()) {
+		sp805::kick();
+	}
+}
+
+/**
+* Start periodic tick, interrupt for secure timer is taken from the device
+* tree. Returns -1 if interrupt couldn't be registered.
+*/
+pub fn init() -> i32 {
+	let mut irq = u32::MAX;
+	if let Some(fdt) = dtb::fdt() {
+		if let Some(node) = fdt.find_compatible("arm,armv8-timer", None) {
+			// First entry is the secure physical timer
+			irq = gic::dt_irq(&fdt, &node, 0);
+		}
+	}
+	if irq == u32::MAX {
+		irq = platform::board().timer_irq;
+	}
+	if interrupt::register_irq(irq, handle_interrupt) < 0 {
+		log::info("Unable to register timer interrupt");
+		return -1;
+	}
+	unsafe { IRQ = irq; }
+	ONLINE.fetch_or(cpu_bit(), Ordering::SeqCst);
+	arm_timer();
+	register::write::cntps_ctl_el1(CTL_ENABLE);
+	return 0;
+}
+
+/**
+* Start periodic tick on core which has been powered on or resumed. Timer
+* interrupt is private to each core, so it's enabled in the banked GIC
+* registers of this core as well.
+*/
+pub fn init_cpu() {
+	let irq = unsafe { IRQ };
+	if irq == u32::MAX {
+		return;
+	}
+	interrupt::enable_local(irq);
+	ONLINE.fetch_or(cpu_bit(), Ordering::SeqCst);
+	arm_timer();
+	register::write::cntps_ctl_el1(CTL_ENABLE);
+}
+
+/**
+* Stop tick on core which is being powered off or suspended, watchdog is no
+* longer waiting for it to check in.
+*/
+pub fn stop_cpu() {
+	register::write::cntps_ctl_el1(0);
+	let bit = cpu_bit();
+	ONLINE.fetch_and(! bit, Ordering::SeqCst);
+	CHECKED_IN.fetch_and(! bit, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counter_to_ms() {
+		assert_eq!(counter_ms(62_500_000, 62_500_000), 1000);
+		assert_eq!(counter_ms(62_500, 62_500_000), 1);
+		// Multiplying first overflows after about 9 years at 62.5 MHz
+		let cnt = u64::MAX / 10;
+		assert_eq!(counter_ms(cnt, 62_500_000), ((cnt as u128 * 1000) / 62_500_000) as u64);
+	}
+
+	#[test]
+	fn kick_after_all_cores_checked_in() {
+		ONLINE.store(0b101, Ordering::SeqCst);
+		CHECKED_IN.store(0, Ordering::SeqCst);
+		assert!(! check_in(0b001));
+		// Same core ticking again doesn't count for the hanging one
+		assert!(! check_in(0b001));
+		assert!(check_in(0b100));
+		assert!(! check_in(0b100));
+		// Core going offline no longer holds back the kick
+		ONLINE.store(0b001, Ordering::SeqCst);
+		assert!(check_in(0b001));
+	}
+}
@@ -0,0 +1,172 @@
+/**
+* Interactive debug console on the secure UART.
+*
+* Only meant for development boards, it can read any memory mapped in secure
+* world. Input is handled from the UART RX interrupt, a command is executed
+* when a full line has been received.
+*/
+use driver::serial;
+use driver::pmm;
+use driver::mmu;
+use applets;
+
+const LINE_SIZE: usize = 80;
+
+/* Maximum number of bytes printed by md */
+const MAX_DUMP: u64 = 1024;
+
+const PROMPT: &str = "tee> ";
+
+static mut LINE: [u8; LINE_SIZE] = [0; LINE_SIZE];
+static mut LINELEN: usize = 0;
+
+fn write_hex(val: u64, digits: usize) {
+	for i in (0..digits).rev() {
+		let n = ((val >> (i * 4)) & 0xf) as u8;
+		let c = if n < 10 { b'0' + n } else { b'a' + n - 10 };
+		serial::putc(c as char);
+	}
+}
+
+fn write_dec(mut val: u64) {
+	let mut buf = [0u8; 20];
+	let mut i = buf.len();
+	loop {
+		i -= 1;
+		buf[i] = b'0' + (val % 10) as u8;
+		val /= 10;
+		if val == 0 { break; }
+	}
+	for c in buf[i..].iter() {
+		serial::putc(*c as char);
+	}
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+	let s = s.trim_start_matches("0x");
+	if s.len() == 0 || s.len() > 16 {
+		return None;
+	}
+	return u64::from_str_radix(s, 16).ok();
+}
+
+fn cmd_help() {
+	serial::write("help               Show this text\n");
+	serial::write("pmm                Physical memory usage\n");
+	serial::write("applets            List applets\n");
+	serial::write("md <addr> [len]    Dump memory, both in hex\n");
+}
+
+fn cmd_pmm() {
+	let (total, free) = pmm::stats();
+	serial::write("pages total: ");
+	write_dec(total);
+	serial::write(" free: ");
+	write_dec(free);
+	serial::putc('\n');
+}
+
+fn cmd_applets() {
+	for i in 0..applets::count() {
+		write_dec(i as u64);
+		serial::write(": ");
+		serial::write(applets::name(i).unwrap_or("?"));
+		let ttbr = applets::ttbr(i);
+		if ttbr == u64::MAX {
+			serial::write(" (no session)\n");
+			continue;
+		}
+		let (pages, tables) = mmu::stats(ttbr);
+		serial::write(" pages: ");
+		write_dec(pages);
+		serial::write(" tables: ");
+		write_dec(tables);
+		serial::putc('\n');
+	}
+}
+
+fn cmd_md<'a, I: Iterator<Item = &'a str>>(mut args: I) {
+	let addr = match args.next().and_then(parse_hex) {
+		Some(a) => a,
+		None => { serial::write("md: invalid address\n"); return; }
+	};
+	let len = match args.next() {
+		Some(l) => match parse_hex(l) {
+			Some(l) => core::cmp::min(l, MAX_DUMP),
+			None => { serial::write("md: invalid length\n"); return; }
+		},
+		None => 0x40,
+	};
+	let start = addr & !0xf;
+	let end = match addr.checked_add(len) {
+		Some(e) => e,
+		None => { serial::write("md: invalid length\n"); return; }
+	};
+	let mut line = start;
+	while line < end {
+		// Check every page we touch, we don't want to fault here or read
+		// device registers
+		if line == start || (line & (mmu::PAGE_SIZE - 1)) == 0 {
+			if ! mmu::el1_readable(line) {
+				serial::write("md: address not readable\n");
+				return;
+			}
+		}
+		write_hex(line, 16);
+		serial::putc(':');
+		for i in 0..16 {
+			let val = unsafe { core::ptr::read_volatile((line + i) as *const u8) };
+			serial::putc(' ');
+			write_hex(val as u64, 2);
+		}
+		serial::putc('\n');
+		line += 16;
+	}
+}
+
+fn execute(line: &str) {
+	let mut args = line.split_whitespace();
+	match args.next() {
+		Some("help") => cmd_help(),
+		Some("pmm") => cmd_pmm(),
+		Some("applets") => cmd_applets(),
+		Some("md") => cmd_md(args),
+		Some(_) => serial::write("Unknown command, try help\n"),
+		None => {},
+	}
+}
+
+/**
+* Called when UART has received data.
+*/
+pub fn input() {
+	let buf = unsafe { &mut LINE };
+	let len = unsafe { &mut LINELEN };
+	while let Some(c) = serial::getc() {
+		match c {
+			b'\r' | b'\n' => {
+				serial::putc('\n');
+				if let Ok(line) = core::str::from_utf8(&buf[..*len]) {
+					execute(line);
+				}
+				*len = 0;
+				serial::write(PROMPT);
+			},
+			// Backspace and delete
+			0x08 | 0x7f => {
+				if *len > 0 {
+					*len -= 1;
+					serial::write("\x08 \x08");
+				}
+			},
+			0x20..=0x7e => {
+				if *len < LINE_SIZE {
+					buf[*len] = c;
+					*len += 1;
+					serial::putc(c as char);
+				}
+			},
+			_ => {},
+		}
+	}
+}
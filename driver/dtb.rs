@@ -1,6 +1,21 @@
+/**
+* Flattened Device Tree (FDT) parser.
+*
+* The blob comes from the normal world, so every read is checked against the
+* sizes in the header and malformed blobs result in None instead of reads
+* outside the blob. Nodes are referred to by the offset of their begin token,
+* together with the offset of their parent, which is needed to interpret reg.
+*
+* Spec: <https://devicetree-specification.readthedocs.io/>
+*/
 use lib::memory;
-use lib::log;
 use lib::math;
+use lib::log;
+use driver::pmm;
+
+extern "C" {
+	fn memcpy(dst: u64, src: u64, size: u64);
+}
 
 const FDT_MAGIC: u32 = 0xd00dfeed;
 
@@ -13,208 +28,657 @@ const FDT_END: u32 = 0x00000009;
 const SIZE_CELL_DEFAULT: u32 = 1;
 const ADDR_CELL_DEFAULT: u32 = 2;
 
+// Offsets in header
+const HDR_MAGIC: u32 = 0;
+const HDR_TOTALSIZE: u32 = 4;
+const HDR_OFF_STRUCT: u32 = 8;
+const HDR_OFF_STRINGS: u32 = 12;
+const HDR_OFF_RSVMAP: u32 = 16;
+const HDR_VERSION: u32 = 20;
+const HDR_LAST_COMP_VERSION: u32 = 24;
+const HDR_SIZE_STRINGS: u32 = 32;
+const HDR_SIZE_STRUCT: u32 = 36;
+const HDR_LEN: u32 = 40;
+
+// Deepest tree we can iterate over
+const MAX_DEPTH: usize = 16;
+
+// Versions of the format we understand
+const FDT_MIN_VERSION: u32 = 16;
+const FDT_LAST_COMP_VERSION: u32 = 17;
+
+// Largest blob we copy to secure memory
+const MAX_FDT_SIZE: u32 = 1 << 21;
+
 static mut FDT: u64 = 0;
 
-// #[repr(C)]
-// pub struct fdtHeader {
-// 	magic: u32,
-// 	totalsize: u32,
-//     off_dt_struct: u32,
-//     off_dt_strings: u32,
-//     off_mem_rsvmap: u32,
-//     version: u32,
-//     last_comp_version: u32,
-//     boot_cpuid_phys: u32,
-//     size_dt_strings: u32,
-//     size_dt_struct: u32,
-// }
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct FdtProp {
-	size_cells: u32,
-	addr_cells: u32,
-	addr: u64,
-	size: usize,
-	status: u64
+#[derive(Debug, Clone, Copy)]
+pub struct Fdt {
+	base: u64,
+	size: u32,
+
+	/* Struct and strings block, as offsets from base */
+	structs: u32,
+	structs_end: u32,
+	strings: u32,
+	strings_end: u32,
+	rsvmap: u32,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Node {
+	/* Offset of FDT_BEGIN_NODE token */
+	off: u32,
+
+	/* Offset of parent node, u32::MAX for root */
+	parent: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Prop {
+	/* Offset and length of property data */
+	off: u32,
+	len: u32,
+}
 
 /**
-* Find a property in Flattened Device Tree (FDT).
-*
-* The function returns start of property data and the length of the property
-* data. If no property with the name was found, (0, 0) is returned.
+* Iterator over all nodes in tree, depth-first.
 */
-fn _find_prop(header: u64, _name: &str, result: &mut FdtProp, iskip: i32) -> i32	{
-	let asint = header as u64;
-	let mut skip = iskip;
+pub struct Nodes<'a> {
+	fdt: &'a Fdt,
+	off: u32,
+	depth: usize,
+	stack: [u32; MAX_DEPTH],
+}
 
-	let off_dt_struct = memory::be::read::u32(header + 8);
-	let off_dt_strings = memory::be::read::u32(header + 12);
+/**
+* Iterator over children of a node.
+*/
+pub struct Children<'a> {
+	fdt: &'a Fdt,
+	next: Option<Node>,
+}
 
-	let name = _name.as_bytes();
+/**
+* Iterator over properties in a node, yields name and property.
+*/
+pub struct Props<'a> {
+	fdt: &'a Fdt,
+	off: u32,
+}
 
-	let strdata = asint + off_dt_strings as u64;
-	let mut curr = asint + off_dt_struct as u64;
-	let mut node: u32 = 0;
-	let mut matchcount = 0;
-	let mut innode: bool = false;
-	let mut nodefinished: bool = false;
+/**
+* Iterator over strings in a string-list property.
+*/
+pub struct Strings<'a> {
+	data: &'a [u8],
+}
 
-	result.size_cells = SIZE_CELL_DEFAULT;
-	result.addr_cells = ADDR_CELL_DEFAULT;
-	result.status = 0;
+impl Fdt {
+	/**
+	* Parse header of blob at `base`, returns None if header is invalid.
+	*/
+	pub fn new(base: u64) -> Option<Fdt> {
+		return Fdt::new_bounded(base, u32::MAX);
+	}
 
-	let lensize = "#size-cells".len();
-	let addrsize = "#address-cells".len();
+	/**
+	* Same as `new`, but blob must also fit in `maxsize` bytes.
+	*/
+	pub fn new_bounded(base: u64, maxsize: u32) -> Option<Fdt> {
+		if maxsize < HDR_LEN || memory::be::read::u32(base + HDR_MAGIC as u64) != FDT_MAGIC {
+			return None;
+		}
+		let hdr = |off: u32| memory::be::read::u32(base + off as u64);
+		let size = hdr(HDR_TOTALSIZE);
+		if size > maxsize {
+			return None;
+		}
+		let structs = hdr(HDR_OFF_STRUCT);
+		let strings = hdr(HDR_OFF_STRINGS);
+		let rsvmap = hdr(HDR_OFF_RSVMAP);
+		let size_structs = hdr(HDR_SIZE_STRUCT);
+		let size_strings = hdr(HDR_SIZE_STRINGS);
+		if hdr(HDR_VERSION) < FDT_MIN_VERSION || hdr(HDR_LAST_COMP_VERSION) > FDT_LAST_COMP_VERSION {
+			return None;
+		}
 
-	while node != FDT_END {
-		node = memory::be::read::u32(curr);
-		curr += 4;
-		match node {
+		// Blocks must be inside blob and tokens must be aligned
+		let inside = |off: u32, len: u32| off >= HDR_LEN && (off as u64 + len as u64) <= size as u64;
+		if size < HDR_LEN || ! inside(structs, size_structs) || ! inside(strings, size_strings)
+			|| ! inside(rsvmap, 16) || structs % 4 != 0 || size_structs % 4 != 0 || rsvmap % 8 != 0 {
+			return None;
+		}
+		return Some(Fdt {
+			base: base, size: size,
+			structs: structs, structs_end: structs + size_structs,
+			strings: strings, strings_end: strings + size_strings,
+			rsvmap: rsvmap,
+		});
+	}
+
+	pub fn totalsize(&self) -> u32 {
+		return self.size;
+	}
+
+	fn be32(&self, off: u32) -> Option<u32> {
+		if off % 4 != 0 || off as u64 + 4 > self.size as u64 {
+			return None;
+		}
+		return Some(memory::be::read::u32(self.base + off as u64));
+	}
+	fn bytes(&self, off: u32, len: u32) -> &[u8] {
+		return unsafe { core::slice::from_raw_parts((self.base + off as u64) as *const u8, len as usize) };
+	}
+
+	/**
+	* NULL-terminated string which must end before `end`, terminator is not
+	* included.
+	*/
+	fn cstr(&self, off: u32, end: u32) -> Option<&str> {
+		if off >= end || end > self.size {
+			return None;
+		}
+		let data = self.bytes(off, end - off);
+		let len = data.iter().position(|b| *b == 0)?;
+		return core::str::from_utf8(&data[..len]).ok();
+	}
+
+	/**
+	* Read token at `off`, returns token and offset of next token.
+	*/
+	fn token(&self, off: u32) -> Option<(u32, u32)> {
+		if off < self.structs || off >= self.structs_end {
+			return None;
+		}
+		let tag = self.be32(off)?;
+		let next = match tag {
 			FDT_BEGIN_NODE => {
-				// Node name follows directly after identifier
-// 				log::info("FDT begin node");
-// 				log::from_memory(curr);
-				let nodelen = memory::cstring::strlen(curr as *const u8);
-				let mcount = memory::cstring::bytesequals(curr as *const u8, name, matchcount as usize);
-
-				// Given string should always be separated by '/'
-// 				let hitb = name[matchcount+mcount];
-				if matchcount+mcount >= name.len() || name[matchcount+mcount] == b'/' {
-					// Whole property is matched
-					if mcount == nodelen {
-						innode = true;
-						matchcount += mcount + 1;
-					}
-					// We've matched everything up until '@'
-					// Data after '@' is often address, we don't know that at compile time
-					else if unsafe { *((curr + mcount as u64) as *const u8) } == b'@' {
-						// We only skip on entries using '@' because those are
-						// the only ones where we might have duplicates
-						if skip == 0 {
-							innode = true;
-							matchcount += mcount + 1;
-							if matchcount >= name.len() {
-								nodefinished = true;
-							}
-						} else {
-							skip -= 1;
-							innode = false;
-						}
-					}
-					else {
-						innode = false;
+				let name = self.cstr(off + 4, self.structs_end)?;
+				off as u64 + 4 + math::align_pow2_up!(name.len() as u64 + 1, 4)
+			}
+			FDT_PROP => {
+				let len = self.be32(off + 4)?;
+				off as u64 + 12 + math::align_pow2_up!(len as u64, 4)
+			}
+			FDT_END_NODE | FDT_NOP | FDT_END => { off as u64 + 4 }
+			_ => { return None; }
+		};
+		if next > self.structs_end as u64 {
+			return None;
+		}
+		return Some((tag, next as u32));
+	}
+
+	/* Offset of first token after node name */
+	fn node_body(&self, node: &Node) -> Option<u32> {
+		let (tag, next) = self.token(node.off)?;
+		if tag != FDT_BEGIN_NODE {
+			return None;
+		}
+		return Some(next);
+	}
+
+	/* Offset after FDT_END_NODE which belongs to node at `off` */
+	fn skip_node(&self, off: u32) -> Option<u32> {
+		let mut depth: u32 = 0;
+		let mut curr = off;
+		loop {
+			let (tag, next) = self.token(curr)?;
+			match tag {
+				FDT_BEGIN_NODE => { depth += 1; }
+				FDT_END_NODE => {
+					if depth <= 1 {
+						return Some(next);
 					}
-				} else {
-					innode = false;
+					depth -= 1;
 				}
+				FDT_END => { return None; }
+				_ => { }
+			}
+			curr = next;
+		}
+	}
 
-				// Strings always have a NULL-byte, so length is strlen + 1 and aligned up to 4
-				curr += math::align_pow2_up!( nodelen as u64 + 1, 4);
+	/* First node at or after `off` on the same level */
+	fn node_from(&self, mut off: u32, parent: u32) -> Option<Node> {
+		loop {
+			let (tag, next) = self.token(off)?;
+			match tag {
+				FDT_BEGIN_NODE => { return Some(Node{off: off, parent: parent}); }
+				FDT_PROP | FDT_NOP => { off = next; }
+				_ => { return None; }
 			}
-			FDT_END_NODE => {
-// 				log::info("FDT end node");
-				innode = false;
-				if nodefinished {
-					return 0;
-				}
+		}
+	}
+
+	pub fn root(&self) -> Option<Node> {
+		return self.node_from(self.structs, u32::MAX);
+	}
+	pub fn first_child(&self, node: &Node) -> Option<Node> {
+		let body = self.node_body(node)?;
+		return self.node_from(body, node.off);
+	}
+	pub fn next_sibling(&self, node: &Node) -> Option<Node> {
+		let after = self.skip_node(node.off)?;
+		return self.node_from(after, node.parent);
+	}
+	pub fn children(&self, node: &Node) -> Children {
+		return Children{ fdt: self, next: self.first_child(node) };
+	}
+	pub fn nodes(&self) -> Nodes {
+		return Nodes{ fdt: self, off: self.structs, depth: 0, stack: [u32::MAX; MAX_DEPTH] };
+	}
+
+	/**
+	* Get parent node, this requires a search from the root.
+	*/
+	pub fn parent(&self, node: &Node) -> Option<Node> {
+		if node.parent == u32::MAX {
+			return None;
+		}
+		return self.nodes().find(|n| n.off == node.parent);
+	}
+
+	/**
+	* Full name of node, including unit address. Root node has an empty name.
+	*/
+	pub fn name(&self, node: &Node) -> &str {
+		return self.cstr(node.off + 4, self.structs_end).unwrap_or("");
+	}
+
+	pub fn props(&self, node: &Node) -> Props {
+		let off = self.node_body(node).unwrap_or(u32::MAX);
+		return Props{ fdt: self, off: off };
+	}
+	pub fn prop(&self, node: &Node, name: &str) -> Option<Prop> {
+		return self.props(node).find(|(n, _p)| *n == name).map(|(_n, p)| p);
+	}
+	pub fn prop_data(&self, prop: &Prop) -> &[u8] {
+		return self.bytes(prop.off, prop.len);
+	}
+	pub fn prop_u32(&self, node: &Node, name: &str) -> Option<u32> {
+		let prop = self.prop(node, name)?;
+		if prop.len != 4 {
+			return None;
+		}
+		return self.be32(prop.off);
+	}
+	pub fn prop_u64(&self, node: &Node, name: &str) -> Option<u64> {
+		let prop = self.prop(node, name)?;
+		if prop.len != 8 {
+			return None;
+		}
+		return Some(((self.be32(prop.off)? as u64) << 32) | self.be32(prop.off + 4)? as u64);
+	}
+	/**
+	* Get cell `idx` in property, for properties like interrupts and clocks
+	* which are arrays of cells.
+	*/
+	pub fn prop_cell(&self, node: &Node, name: &str, idx: usize) -> Option<u32> {
+		let prop = self.prop(node, name)?;
+		if (idx + 1) * 4 > prop.len as usize {
+			return None;
+		}
+		return self.be32(prop.off + (idx * 4) as u32);
+	}
+	/**
+	* Input clock of device, either clock-frequency in node or in the node of
+	* the first clock.
+	*/
+	pub fn clock_frequency(&self, node: &Node) -> Option<u32> {
+		if let Some(freq) = self.prop_u32(node, "clock-frequency") {
+			return Some(freq);
+		}
+		let clk = self.find_phandle(self.prop_cell(node, "clocks", 0)?)?;
+		return self.prop_u32(&clk, "clock-frequency");
+	}
+	pub fn prop_str(&self, node: &Node, name: &str) -> Option<&str> {
+		let prop = self.prop(node, name)?;
+		let data = self.prop_data(&prop);
+		if data.len() == 0 || data[data.len() - 1] != 0 {
+			return None;
+		}
+		return core::str::from_utf8(&data[..data.len() - 1]).ok();
+	}
+	pub fn prop_strings(&self, node: &Node, name: &str) -> Strings {
+		let data = match self.prop(node, name) {
+			Some(prop) => self.prop_data(&prop),
+			None => &[],
+		};
+		return Strings{ data: data };
+	}
+
+	/**
+	* #address-cells and #size-cells specified in node, these are used for reg
+	* in the children of node.
+	*/
+	pub fn cells(&self, node: &Node) -> (u32, u32) {
+		let addr = self.prop_u32(node, "#address-cells").unwrap_or(ADDR_CELL_DEFAULT);
+		let size = self.prop_u32(node, "#size-cells").unwrap_or(SIZE_CELL_DEFAULT);
+		return (addr, size);
+	}
+
+	/* Read number stored in 1 or 2 cells */
+	fn read_cells(&self, off: u32, cells: u32) -> Option<u64> {
+		match cells {
+			0 => { return Some(0); }
+			1 => { return Some(self.be32(off)? as u64); }
+			2 => { return Some(((self.be32(off)? as u64) << 32) | self.be32(off + 4)? as u64); }
+			_ => { return None; }
+		}
+	}
+
+	/* Cells used by reg in node and size of each entry in bytes */
+	fn reg_format(&self, node: &Node) -> Option<(u32, u32, u32)> {
+		let (acells, scells) = if node.parent == u32::MAX {
+			(ADDR_CELL_DEFAULT, SIZE_CELL_DEFAULT)
+		} else {
+			self.cells(&Node{off: node.parent, parent: u32::MAX})
+		};
+		if acells == 0 || acells > 2 || scells > 2 {
+			return None;
+		}
+		return Some((acells, scells, (acells + scells) * 4));
+	}
+
+	/**
+	* Number of (address, size) entries in reg.
+	*/
+	pub fn reg_count(&self, node: &Node) -> usize {
+		let prop = match self.prop(node, "reg") { Some(p) => p, None => { return 0; } };
+		return match self.reg_format(node) {
+			Some((_a, _s, entsize)) => (prop.len / entsize) as usize,
+			None => 0,
+		};
+	}
+
+	/**
+	* Get entry `idx` in reg, cell sizes are taken from parent.
+	*/
+	pub fn reg(&self, node: &Node, idx: usize) -> Option<(u64, u64)> {
+		let prop = self.prop(node, "reg")?;
+		let (acells, scells, entsize) = self.reg_format(node)?;
+		if idx >= (prop.len / entsize) as usize {
+			return None;
+		}
+		let off = prop.off + (idx as u32 * entsize);
+		let addr = self.read_cells(off, acells)?;
+		let size = self.read_cells(off + (acells * 4), scells)?;
+		return Some((addr, size));
+	}
+
+	/**
+	* Node is enabled if status is missing or "okay".
+	*/
+	pub fn is_enabled(&self, node: &Node) -> bool {
+		return match self.prop_str(node, "status") {
+			Some(s) => s == "okay" || s == "ok",
+			None => true,
+		};
+	}
+
+	/**
+	* Check if node can be used by secure world, secure-status overrides status
+	* if it's present.
+	*/
+	pub fn is_secure_enabled(&self, node: &Node) -> bool {
+		return match self.prop_str(node, "secure-status") {
+			Some(s) => s == "okay" || s == "ok",
+			None => self.is_enabled(node),
+		};
+	}
+
+	/**
+	* Check if node is explicitly assigned to secure world. Unlike
+	* is_secure_enabled, a node without secure-status is normal world only.
+	*/
+	pub fn is_secure_assigned(&self, node: &Node) -> bool {
+		return match self.prop_str(node, "secure-status") {
+			Some(s) => s == "okay" || s == "ok",
+			None => false,
+		};
+	}
+
+	/* Check if name of node matches path component, unit address is optional */
+	fn name_matches(&self, node: &Node, comp: &str) -> bool {
+		let name = self.name(node);
+		if name == comp {
+			return true;
+		}
+		return ! comp.contains('@') && name.split('@').next() == Some(comp);
+	}
+
+	fn lookup(&self, path: &str, enabled: bool) -> Option<Node> {
+		// Path not starting with '/' begins with an alias
+		let (mut node, rest) = if path.starts_with('/') {
+			(self.root()?, path)
+		} else {
+			let (alias, rest) = match path.find('/') {
+				Some(i) => (&path[..i], &path[i..]),
+				None => (path, ""),
+			};
+			let aliases = self.lookup("/aliases", false)?;
+			let target = self.prop_str(&aliases, alias)?;
+			if ! target.starts_with('/') {
+				return None;
 			}
-			FDT_PROP => {
-				let proplen = memory::be::read::u32(curr);
-				let stroffset = memory::be::read::u32(curr + 4);
-				let propname = (strdata + stroffset as u64) as *const u8;
-// 				log::from_memory(propname as u64);
-				if innode {
-					let propnamelen = memory::cstring::strlen(propname);
-					if memory::cstring::bytesequals(propname, name, matchcount as usize) == propnamelen {
-						if propnamelen + matchcount >= name.len() {
-							result.addr = (curr + 8) as u64;
-							result.size = proplen as usize;
-
-							// We can't return quite yet, because we might still
-							// need to read size-cells and address-cells. We
-							// instead mark that we should return when the node
-							// end
-							nodefinished = true;
-						}
+			(self.lookup(target, false)?, rest)
+		};
+
+		let mut comps = rest.split('/').filter(|c| c.len() > 0).peekable();
+		while let Some(comp) = comps.next() {
+			let last = comps.peek().is_none();
+			node = self.children(&node).find(|c| {
+				self.name_matches(c, comp) && (! enabled || ! last || self.is_enabled(c))
+			})?;
+		}
+		return Some(node);
+	}
+
+	/**
+	* Find node by path, like "/pl011" or "/soc/uart@9000000". Unit address can
+	* be left out, the first match is then returned. Path can also start with an
+	* alias from /aliases, like "serial0".
+	*/
+	pub fn find_path(&self, path: &str) -> Option<Node> {
+		return self.lookup(path, false);
+	}
+
+	/**
+	* Same as `find_path`, but disabled nodes are skipped in the last component.
+	*/
+	pub fn find_enabled(&self, path: &str) -> Option<Node> {
+		return self.lookup(path, true);
+	}
+
+	pub fn find_phandle(&self, phandle: u32) -> Option<Node> {
+		return self.nodes().find(|n| {
+			self.prop_u32(n, "phandle") == Some(phandle) || self.prop_u32(n, "linux,phandle") == Some(phandle)
+		});
+	}
+
+	/**
+	* Find next node with `compat` in compatible, search starts after `after`
+	* or at the root if it's None.
+	*/
+	pub fn find_compatible(&self, compat: &str, after: Option<&Node>) -> Option<Node> {
+		let mut nodes = self.nodes();
+		if let Some(a) = after {
+			nodes.find(|n| n.off == a.off)?;
+		}
+		return nodes.find(|n| self.prop_strings(n, "compatible").any(|s| s == compat));
+	}
+
+	/**
+	* Get entry `idx` in the memory reservation block, None when there are no
+	* more entries.
+	*/
+	pub fn memreserve(&self, idx: usize) -> Option<(u64, u64)> {
+		let mut off = self.rsvmap;
+		for i in 0..(idx + 1) {
+			if off as u64 + 16 > self.size as u64 {
+				return None;
+			}
+			let addr = ((self.be32(off)? as u64) << 32) | self.be32(off + 4)? as u64;
+			let size = ((self.be32(off + 8)? as u64) << 32) | self.be32(off + 12)? as u64;
+
+			// List is terminated by an empty entry
+			if addr == 0 && size == 0 {
+				return None;
+			}
+			if i == idx {
+				return Some((addr, size));
+			}
+			off += 16;
+		}
+		return None;
+	}
+}
+
+impl<'a> Iterator for Nodes<'a> {
+	type Item = Node;
+	fn next(&mut self) -> Option<Node> {
+		loop {
+			let (tag, next) = self.fdt.token(self.off)?;
+			let off = self.off;
+			self.off = next;
+			match tag {
+				FDT_BEGIN_NODE => {
+					if self.depth >= MAX_DEPTH {
+						log::info("FDT is too deep");
+						self.off = u32::MAX;
+						return None;
 					}
-					
-					// Need to keep track of size-cells and address-cells. The
-					// last of these properties before the node we're looking
-					// for are the correct ones.
-					if proplen == 4 {
-						if memory::cstring::strequals(propname, "#size-cells") == lensize {
-							result.size_cells = memory::be::read::u32(curr + 8);
-						}
-						if memory::cstring::strequals(propname, "#address-cells") == addrsize {
-							result.size_cells = memory::be::read::u32(curr + 8);
-						}
-					} else if memory::cstring::strequals(propname, "status") == "status".len() {
-						result.status = curr + 8;
+					let parent = if self.depth == 0 { u32::MAX } else { self.stack[self.depth - 1] };
+					self.stack[self.depth] = off;
+					self.depth += 1;
+					return Some(Node{off: off, parent: parent});
+				}
+				FDT_END_NODE => {
+					if self.depth == 0 {
+						self.off = u32::MAX;
+						return None;
 					}
+					self.depth -= 1;
+				}
+				FDT_END => {
+					self.off = u32::MAX;
+					return None;
 				}
-				// Length specifier + str offset + property data
-				curr += 4 as u64 + 4 as u64 + math::align_pow2_up!(proplen, 4) as u64;
+				_ => { }
 			}
-			FDT_END => {
-// 				log::info("Found end of FDT structs");
+		}
+	}
+}
+
+impl<'a> Iterator for Children<'a> {
+	type Item = Node;
+	fn next(&mut self) -> Option<Node> {
+		let curr = self.next?;
+		self.next = self.fdt.next_sibling(&curr);
+		return Some(curr);
+	}
+}
+
+impl<'a> Iterator for Props<'a> {
+	type Item = (&'a str, Prop);
+	fn next(&mut self) -> Option<(&'a str, Prop)> {
+		loop {
+			let (tag, next) = self.fdt.token(self.off)?;
+			let off = self.off;
+			match tag {
+				FDT_PROP => {
+					self.off = next;
+					let len = self.fdt.be32(off + 4)?;
+					let nameoff = self.fdt.be32(off + 8)?;
+					let stroff = (self.fdt.strings as u64) + nameoff as u64;
+					if stroff >= self.fdt.strings_end as u64 {
+						continue;
+					}
+					let name = match self.fdt.cstr(stroff as u32, self.fdt.strings_end) {
+						Some(n) => n,
+						None => { continue; }
+					};
+					return Some((name, Prop{off: off + 12, len: len}));
+				}
+				FDT_NOP => { self.off = next; }
+				_ => { return None; }
 			}
-			FDT_NOP => {
-				// This does nothing and have no data
-// 				log::info("Found end of FDT NOP");
+		}
+	}
+}
+
+impl<'a> Iterator for Strings<'a> {
+	type Item = &'a str;
+	fn next(&mut self) -> Option<&'a str> {
+		loop {
+			if self.data.len() == 0 {
+				return None;
 			}
-			_ => {
-// 				log::info("Unknown FDT node");
+			// Last string must be terminated
+			let end = self.data.iter().position(|b| *b == 0)?;
+			let s = &self.data[..end];
+			self.data = &self.data[end + 1..];
+			if let Ok(s) = core::str::from_utf8(s) {
+				return Some(s);
 			}
 		}
 	}
-	// No match was found
-	return -1;
 }
 
-pub fn find_prop(name: &str, result: &mut FdtProp, skip: i32) -> i32 {
-	return unsafe { _find_prop(FDT, name, result, skip) };
+/**
+* Device tree we were booted with, None if we have none.
+*/
+pub fn fdt() -> Option<Fdt> {
+	let base = unsafe { FDT };
+	if base == 0 {
+		return None;
+	}
+	return Fdt::new(base);
 }
-pub fn get_compatible() {
-	let mut prop = FdtProp::default();
-	find_prop("/compatible", &mut prop, 0);
+
+/**
+* Get entry `idx` in reg for the first enabled node matching path.
+* (u64::MAX, u64::MAX) is returned if node or entry doesn't exist.
+*/
+pub fn get_reg(path: &str, idx: usize) -> (u64, u64) {
+	let res = fdt().and_then(|f| {
+		let node = f.find_enabled(path)?;
+		return f.reg(&node, idx);
+	});
+	return res.unwrap_or((u64::MAX, u64::MAX));
 }
-fn interpret_as_reg(prop: &FdtProp) -> (u64, u64) {
-	// Sanity check for bugs in our code or bad data from FDT
-	if prop.size != ((prop.addr_cells * 4) + (prop.size_cells * 4)) as usize {
-		log::info("Not enough space reserves for reg");
-		return (u64::MAX, u64::MAX);
-	}
-
-	let mut curr = prop.addr;
-	let mut addr = memory::be::read::u32(curr) as u64;
-	curr += 4;
-	if prop.addr_cells == 2 {
-		addr <<= 32;
-		addr += memory::be::read::u32(curr) as u64;
-		curr += 4;
-	}
-	let mut size = memory::be::read::u32(curr) as u64;
-	curr += 4;
-	if prop.size_cells == 2 {
-		size <<= 32;
-		size += memory::be::read::u32(curr) as u64;
-	}
-	return (addr, size);
+
+/**
+* Console we should use, from /secure-chosen/stdout-path. Path may be an alias
+* and can have options after ':'. (u64::MAX, u64::MAX) is returned if there is
+* no such node.
+*/
+pub fn get_stdout() -> (u64, u64) {
+	let res = fdt().and_then(|f| {
+		let chosen = f.find_path("/secure-chosen")?;
+		let path = f.prop_str(&chosen, "stdout-path")?;
+		let path = path.split(':').next()?;
+
+		// Secure devices are often disabled for normal world, so status is ignored
+		let node = f.find_path(path)?;
+		return f.reg(&node, 0);
+	});
+	return res.unwrap_or((u64::MAX, u64::MAX));
 }
-pub fn get_as_reg(name: &str, skip: i32) -> (u64, u64) {
-	let mut prop = FdtProp::default();
-	if find_prop(name, &mut prop, skip) == 0 {
-		if prop.status != 0 {
-			let statname = prop.status as *const u8;
-			if memory::cstring::strequals(statname, "disabled") == "disabled".len() {
-				return get_as_reg(name, skip+1);
-			}
-		}
-		return interpret_as_reg(&prop);
-	}
-	return (u64::MAX, u64::MAX);
+
+/**
+* Seed for placing the linear region from /secure-chosen/kaslr-seed, 0 if
+* there is no seed. Despite the property name the kernel image isn't moved. The seed comes from firmware and the device tree is also
+* given to normal world, so it must only be used if firmware removes it there.
+*/
+pub fn get_kaslr_seed() -> u64 {
+	let res = fdt().and_then(|f| {
+		let chosen = f.find_path("/secure-chosen")?;
+		return f.prop_u64(&chosen, "kaslr-seed");
+	});
+	return res.unwrap_or(0);
 }
 
 /*
@@ -225,33 +689,442 @@ pub fn get_as_reg(name: &str, skip: i32) -> (u64, u64) {
  device_type
 */
 pub fn get_secure_memory() -> (u64, u64) {
-	let mut prop = FdtProp::default();
-	if find_prop("/secram/reg", &mut prop, 0) == 0 {
-		return interpret_as_reg(&prop);
-	}
-	return (u64::MAX, u64::MAX);
+	let res = fdt().and_then(|f| {
+		let node = f.find_path("/secram")?;
+		return f.reg(&node, 0);
+	});
+	return res.unwrap_or((u64::MAX, u64::MAX));
+}
+
+/**
+* Get entry `idx` in the memory reservation block. Returns (u64::MAX, u64::MAX)
+* when there are no more entries.
+*/
+pub fn memreserve(idx: usize) -> (u64, u64) {
+	return fdt().and_then(|f| f.memreserve(idx)).unwrap_or((u64::MAX, u64::MAX));
 }
 
+/**
+* Get region `idx` among the children of /reserved-memory which have the no-map
+* property. A child with multiple entries in reg returns one region per entry.
+* Returns (u64::MAX, u64::MAX) when there are no more regions.
+*/
+pub fn reserved_nomap(idx: usize) -> (u64, u64) {
+	let res = fdt().and_then(|f| {
+		let resv = f.find_path("/reserved-memory")?;
+		let mut count = 0;
+		for child in f.children(&resv) {
+			if f.prop(&child, "no-map").is_none() || ! f.is_enabled(&child) {
+				continue;
+			}
+			let entries = f.reg_count(&child);
+			if idx < count + entries {
+				return f.reg(&child, idx - count);
+			}
+			count += entries;
+		}
+		return None;
+	});
+	return res.unwrap_or((u64::MAX, u64::MAX));
+}
+
+/**
+* Use blob at `start`, which is in non-secure memory. Returns size of blob or
+* u32::MAX if header is invalid.
+*/
 pub fn init(start: u64) -> u32	{
 	log::info("Initializing FDT");
 
 	// Basic sanity check so that we don't read arbitrary values
-	let magic = memory::be::read::u32(start);
-	if magic != FDT_MAGIC	{
-		log::info("Incorrect FDT header");
-		return u32::MAX;
-	}
+	let fdt = match Fdt::new_bounded(start, MAX_FDT_SIZE) {
+		Some(f) => f,
+		None => {
+			log::info("Incorrect FDT header");
+			return u32::MAX;
+		}
+	};
 
 	// Store header in global variable
 	unsafe { FDT = start; }
+	return fdt.totalsize();
+}
 
-	// We got our own secure-chosen environment we could parse
-	/*
-	/secure-chosen
-	 stdout-path
-	 kaslr-seed
-	*/
-	let ret = memory::be::read::u32(unsafe{FDT} + 4);
-	return ret;
+/**
+* Copy blob to secure memory, so that normal world can't change it after we've
+* parsed it. Must be called after pmm is initialized and before MMU is enabled.
+*/
+pub fn relocate() -> i32 {
+	let src = match fdt() { Some(f) => f, None => { return -1; } };
+	let size = src.totalsize();
+	let dst = pmm::carve_out("fdt", size as u64);
+	if dst == u64::MAX {
+		log::info("Unable to allocate memory for FDT");
+		return -1;
+	}
+	unsafe { memcpy(dst, src.base, size as u64); }
+
+	// Blob may have changed after we validated it, so the copy is validated again
+	match Fdt::new_bounded(dst, size) {
+		Some(f) if f.totalsize() == size => { unsafe { FDT = dst; } }
+		_ => {
+			log::info("FDT changed while it was copied");
+			unsafe { FDT = 0; }
+			return -1;
+		}
+	}
+	return 0;
+}
+
+/**
+* Blob must be accessed through linear region after MMU is enabled
+*/
+pub fn lateinit(linear: u64) {
+	unsafe {
+		if FDT != 0 {
+			FDT += linear;
+		}
+	}
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/**
+	* Build blobs in the same format as dtc. Data is stored in u32 so that the
+	* blob is aligned.
+	*/
+	struct Builder {
+		structs: Vec<u8>,
+		strings: Vec<u8>,
+		rsv: Vec<(u64, u64)>,
+	}
+
+	impl Builder {
+		fn new() -> Builder {
+			Builder { structs: Vec::new(), strings: Vec::new(), rsv: Vec::new() }
+		}
+		fn u32(&mut self, v: u32) {
+			self.structs.extend_from_slice(&v.to_be_bytes());
+		}
+		fn pad(&mut self) {
+			while self.structs.len() % 4 != 0 {
+				self.structs.push(0);
+			}
+		}
+		fn begin(&mut self, name: &str) -> &mut Builder {
+			self.u32(FDT_BEGIN_NODE);
+			self.structs.extend_from_slice(name.as_bytes());
+			self.structs.push(0);
+			self.pad();
+			return self;
+		}
+		fn end(&mut self) -> &mut Builder {
+			self.u32(FDT_END_NODE);
+			return self;
+		}
+		fn prop(&mut self, name: &str, data: &[u8]) -> &mut Builder {
+			let nameoff = self.strings.len() as u32;
+			self.strings.extend_from_slice(name.as_bytes());
+			self.strings.push(0);
+			self.u32(FDT_PROP);
+			self.u32(data.len() as u32);
+			self.u32(nameoff);
+			self.structs.extend_from_slice(data);
+			self.pad();
+			return self;
+		}
+		fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Builder {
+			let data: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes().to_vec()).collect();
+			return self.prop(name, &data);
+		}
+		fn string(&mut self, name: &str, val: &str) -> &mut Builder {
+			let mut data = val.as_bytes().to_vec();
+			data.push(0);
+			return self.prop(name, &data);
+		}
+		fn finish(&mut self) -> Vec<u32> {
+			self.u32(FDT_END);
+			let rsvoff = HDR_LEN as usize;
+			let structoff = rsvoff + ((self.rsv.len() + 1) * 16);
+			let stringoff = structoff + self.structs.len();
+			let total = math::align_pow2_up!(stringoff + self.strings.len(), 4);
+
+			let mut blob = vec![0u8; total];
+			let hdr = [FDT_MAGIC, total as u32, structoff as u32, stringoff as u32, rsvoff as u32,
+				17, 16, 0, self.strings.len() as u32, self.structs.len() as u32];
+			for (i, v) in hdr.iter().enumerate() {
+				blob[i * 4..(i + 1) * 4].copy_from_slice(&v.to_be_bytes());
+			}
+			for (i, (a, s)) in self.rsv.iter().enumerate() {
+				let off = rsvoff + (i * 16);
+				blob[off..off + 8].copy_from_slice(&a.to_be_bytes());
+				blob[off + 8..off + 16].copy_from_slice(&s.to_be_bytes());
+			}
+			blob[structoff..stringoff].copy_from_slice(&self.structs);
+			blob[stringoff..stringoff + self.strings.len()].copy_from_slice(&self.strings);
+			return blob.chunks(4).map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]])).collect();
+		}
+	}
+
+	/* Tree similar to the one qemu generates for virt with secure=on */
+	fn qemu_like() -> Vec<u32> {
+		let mut b = Builder::new();
+		b.rsv.push((0x48000000, 0x1000));
+		b.begin("")
+			.cells("#address-cells", &[2]).cells("#size-cells", &[2])
+			.prop("compatible", b"linux,dummy-virt\0");
+		b.begin("aliases").string("serial0", "/pl011@9000000").string("serial1", "/pl011@9040000").end();
+		b.begin("secure-chosen").string("stdout-path", "serial1:115200n8").end();
+		b.begin("secram@e000000")
+			.string("status", "disabled")
+			.cells("reg", &[0, 0x0e000000, 0, 0x01000000])
+			.end();
+		b.begin("pl011@9040000")
+			.string("status", "disabled")
+			.string("secure-status", "okay")
+			.prop("compatible", b"arm,pl011\0arm,primecell\0")
+			.cells("reg", &[0, 0x09040000, 0, 0x1000])
+			.end();
+		b.begin("pl011@9000000")
+			.prop("compatible", b"arm,pl011\0arm,primecell\0")
+			.cells("reg", &[0, 0x09000000, 0, 0x1000])
+			.cells("clocks", &[0x8000])
+			.end();
+		b.begin("apb-pclk").cells("phandle", &[0x8000]).end();
+		b.begin("soc")
+			.cells("#address-cells", &[1]).cells("#size-cells", &[1]);
+		b.begin("gpio@1000")
+			.prop("compatible", b"arm,pl061\0arm,primecell\0")
+			.cells("reg", &[0x1000, 0x100, 0x3000, 0x200])
+			.end();
+		b.end();
+		b.begin("reserved-memory")
+			.cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+		b.begin("fw@e800000").prop("no-map", &[]).cells("reg", &[0, 0x0e800000, 0, 0x2000]).end();
+		b.begin("shared@e900000").cells("reg", &[0, 0x0e900000, 0, 0x2000]).end();
+		b.end();
+		b.end();
+		return b.finish();
+	}
+
+	fn parse(blob: &Vec<u32>) -> Option<Fdt> {
+		return Fdt::new_bounded(blob.as_ptr() as u64, (blob.len() * 4) as u32);
+	}
+
+	#[test]
+	fn iterate_nodes_and_children() {
+		let blob = qemu_like();
+		let f = parse(&blob).unwrap();
+		let root = f.root().unwrap();
+		assert_eq!(f.name(&root), "");
+		assert_eq!(f.nodes().count(), 12);
+
+		let names: Vec<&str> = f.children(&root).map(|n| f.name(&n)).collect();
+		assert_eq!(names, vec!["aliases", "secure-chosen", "secram@e000000", "pl011@9040000", "pl011@9000000",
+			"apb-pclk", "soc", "reserved-memory"]);
+
+		let gpio = f.find_path("/soc/gpio").unwrap();
+		let soc = f.parent(&gpio).unwrap();
+		assert_eq!(f.name(&soc), "soc");
+		assert_eq!(f.parent(&soc).unwrap(), root);
+		assert!(f.parent(&root).is_none());
+		assert!(f.first_child(&gpio).is_none());
+	}
+
+	#[test]
+	fn lookup_by_path_alias_phandle_compatible() {
+		let blob = qemu_like();
+		let f = parse(&blob).unwrap();
+
+		let first = f.find_path("/pl011").unwrap();
+		assert_eq!(f.name(&first), "pl011@9040000");
+		let enabled = f.find_enabled("/pl011").unwrap();
+		assert_eq!(f.name(&enabled), "pl011@9000000");
+		assert_eq!(f.find_path("/pl011@9000000"), Some(enabled));
+		assert!(f.is_secure_enabled(&first) && f.is_secure_enabled(&enabled));
+		assert!(! f.is_secure_enabled(&f.find_path("/secram").unwrap()));
+		assert!(f.is_secure_assigned(&first) && ! f.is_secure_assigned(&enabled));
+		assert_eq!(f.find_path("serial0"), Some(enabled));
+		assert!(f.find_path("/pl011@1").is_none());
+		assert!(f.find_path("missing").is_none());
+
+		let clk = f.prop_u32(&enabled, "clocks").unwrap();
+		assert_eq!(f.prop_cell(&enabled, "clocks", 0), Some(clk));
+		assert_eq!(f.prop_cell(&enabled, "clocks", 1), None);
+		assert_eq!(f.name(&f.find_phandle(clk).unwrap()), "apb-pclk");
+
+		let c1 = f.find_compatible("arm,primecell", None).unwrap();
+		let c2 = f.find_compatible("arm,primecell", Some(&c1)).unwrap();
+		let c3 = f.find_compatible("arm,primecell", Some(&c2)).unwrap();
+		assert_eq!(f.name(&c3), "gpio@1000");
+		assert!(f.find_compatible("arm,primecell", Some(&c3)).is_none());
+	}
+
+	#[test]
+	fn typed_properties() {
+		let blob = qemu_like();
+		let f = parse(&blob).unwrap();
+		let root = f.root().unwrap();
+
+		assert_eq!(f.cells(&root), (2, 2));
+		assert_eq!(f.prop_str(&root, "compatible"), Some("linux,dummy-virt"));
+		assert_eq!(f.prop_u32(&root, "compatible"), None);
+		let uart = f.find_enabled("/pl011").unwrap();
+		let compat: Vec<&str> = f.prop_strings(&uart, "compatible").collect();
+		assert_eq!(compat, vec!["arm,pl011", "arm,primecell"]);
+		assert_eq!(f.reg(&uart, 0), Some((0x09000000, 0x1000)));
+		assert_eq!(f.reg(&uart, 1), None);
+
+		// Cells in soc are 1 and there are two entries
+		let gpio = f.find_path("/soc/gpio@1000").unwrap();
+		assert_eq!(f.reg_count(&gpio), 2);
+		assert_eq!(f.reg(&gpio, 1), Some((0x3000, 0x200)));
+
+		assert_eq!(f.memreserve(0), Some((0x48000000, 0x1000)));
+		assert_eq!(f.memreserve(1), None);
+	}
+
+	#[test]
+	fn stdout_from_secure_chosen() {
+		let blob = qemu_like();
+		unsafe { FDT = blob.as_ptr() as u64; }
+		assert_eq!(get_stdout(), (0x09040000, 0x1000));
+		assert_eq!(get_reg("/pl011", 0), (0x09000000, 0x1000));
+		unsafe { FDT = 0; }
+	}
+
+	#[test]
+	fn rejects_bad_header() {
+		let mut blob = qemu_like();
+		blob[0] = 0;
+		assert!(parse(&blob).is_none());
+
+		let mut blob = qemu_like();
+		blob[1] = u32::MAX.to_be();
+		assert!(parse(&blob).is_none());
+
+		let mut blob = qemu_like();
+		let len = blob.len() as u32;
+		blob[2] = ((len * 4) - 4).to_be();
+		assert!(parse(&blob).is_none());
+
+		// Larger than buffer
+		let mut blob = qemu_like();
+		blob[1] = ((len * 4) + 4).to_be();
+		assert!(parse(&blob).is_none());
+
+		// Incompatible version
+		let mut blob = qemu_like();
+		blob[6] = 18u32.to_be();
+		assert!(parse(&blob).is_none());
+	}
+
+	// Deterministic pseudo-random numbers
+	struct XorShift(u64);
+	impl XorShift {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			return self.0;
+		}
+	}
+
+	/* Call all functions which read from blob, nothing should panic or hang */
+	fn exercise(f: &Fdt) {
+		for n in f.nodes() {
+			f.name(&n);
+			for (_name, p) in f.props(&n) {
+				f.prop_data(&p);
+			}
+			f.prop_strings(&n, "compatible").count();
+			f.prop_str(&n, "status");
+			f.prop_u64(&n, "reg");
+			for i in 0..f.reg_count(&n) {
+				f.reg(&n, i);
+			}
+			f.children(&n).count();
+			f.parent(&n);
+		}
+		f.find_path("/soc/gpio");
+		f.find_path("serial0/x");
+		f.find_enabled("/pl011");
+		f.find_phandle(0x8000);
+		f.find_compatible("arm,pl011", None);
+		let mut i = 0;
+		while f.memreserve(i).is_some() {
+			i += 1;
+		}
+	}
+
+	fn fuzz(orig: &Vec<u32>, rounds: usize) {
+		let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+		let bytes = orig.len() * 4;
+		for _i in 0..rounds {
+			let mut blob = orig.clone();
+			let data = unsafe { core::slice::from_raw_parts_mut(blob.as_mut_ptr() as *mut u8, bytes) };
+			for _j in 0..(1 + rng.next() % 8) {
+				// Header is hit more often, since it contains all offsets
+				let pos = if rng.next() % 4 == 0 { rng.next() as usize % 40 } else { rng.next() as usize % bytes };
+				data[pos] = rng.next() as u8;
+			}
+			if let Some(f) = parse(&blob) {
+				exercise(&f);
+			}
+		}
+	}
+
+	#[test]
+	fn fuzz_mutated_blobs() {
+		fuzz(&qemu_like(), 20000);
+	}
+
+	/* Copy raw blob into u32 storage so it's aligned like a blob from firmware */
+	fn load(raw: &[u8]) -> Vec<u32> {
+		let mut blob = vec![0u32; (raw.len() + 3) / 4];
+		let data = unsafe { core::slice::from_raw_parts_mut(blob.as_mut_ptr() as *mut u8, raw.len()) };
+		data.copy_from_slice(raw);
+		return blob;
+	}
+
+	/**
+	* Fuzz blobs in dtc layout, sources are in testdata/ next to the blobs.
+	*/
+	#[test]
+	fn fuzz_dtc_blobs() {
+		let corpus: [&[u8]; 2] = [
+			include_bytes!("testdata/qemu-virt.dtb"),
+			include_bytes!("testdata/fvp-base.dtb"),
+		];
+		for raw in corpus.iter() {
+			let blob = load(raw);
+			let f = parse(&blob).unwrap();
+			assert_eq!(f.totalsize() as usize, raw.len());
+			exercise(&f);
+			fuzz(&blob, 2000);
+		}
+	}
+
+	#[test]
+	fn dtc_blob_contents() {
+		let blob = load(include_bytes!("testdata/qemu-virt.dtb"));
+		let f = parse(&blob).unwrap();
+		assert_eq!(f.memreserve(0), Some((0x0e000000, 0x1000)));
+		assert_eq!(f.memreserve(1), None);
+		let uart = f.find_path("/pl011@9040000").unwrap();
+		assert!(f.is_secure_enabled(&uart));
+		assert!(! f.is_enabled(&uart));
+		assert_eq!(f.reg(&uart, 0), Some((0x9040000, 0x1000)));
+		let gic = f.find_compatible("arm,cortex-a15-gic", None).unwrap();
+		assert_eq!(f.reg(&gic, 1), Some((0x8010000, 0x10000)));
+
+		let blob = load(include_bytes!("testdata/fvp-base.dtb"));
+		let f = parse(&blob).unwrap();
+		let gic = f.find_compatible("arm,gic-v3", None).unwrap();
+		assert_eq!(f.reg_count(&gic), 5);
+		assert_eq!(f.prop_u32(&gic, "#redistributor-regions"), Some(1));
+		let cpus = f.find_path("/cpus").unwrap();
+		assert_eq!(f.children(&cpus).count(), 2);
+	}
+}
@@ -1,16 +1,14 @@
 use lib::memory;
+use driver::dtb;
 
 mod gicc {
 	use platform;
-	use platform::Platform;
 	use lib::memory;
 	pub fn read(off: u64) -> u32	{
-		//return memory::dma::read::u32(platform::gicc::BASE + off);
-		return memory::dma::read::u32(platform::Impl::base_gicc() + off);
+		return memory::dma::read::u32(platform::base_gicc() + off);
 	}
 	pub fn write(off: u64, val: u32)	{
-		//memory::dma::write::u32(platform::gicc::BASE + off, val);
-		memory::dma::write::u32(platform::Impl::base_gicc() + off, val);
+		memory::dma::write::u32(platform::base_gicc() + off, val);
 	}
 	pub mod off {
 		pub const IAR1: u64 = 3 * 4;
@@ -19,7 +17,79 @@ mod gicc {
 	}
 }
 
+mod gicd {
+	use platform;
+	use lib::memory;
+	pub fn read(off: u64) -> u32	{
+		return memory::dma::read::u32(platform::base_gicd() + off);
+	}
+	pub fn write(off: u64, val: u32)	{
+		memory::dma::write::u32(platform::base_gicd() + off, val);
+	}
+	pub fn write_u8(off: u64, val: u8)	{
+		memory::dma::write::u8(platform::base_gicd() + off, val);
+	}
+	pub mod off {
+		pub const IGROUPR: u64 = 0x080;
+		pub const ISENABLER: u64 = 0x100;
+		pub const ICENABLER: u64 = 0x180;
+		pub const IPRIORITYR: u64 = 0x400;
+		pub const ITARGETSR: u64 = 0x800;
+	}
+}
+
+// First shared peripheral interrupt, lower IDs are private to each core
+pub const SPI_START: u32 = 32;
+pub const PPI_START: u32 = 16;
+
+// ID returned by acknowledge when there is no pending interrupt
+pub const SPURIOUS: u32 = 1020;
+
+const PRIORITY_DEFAULT: u8 = 0x80;
+
+/**
+* Convert interrupt specifier from device tree (type, number) to interrupt ID,
+* u32::MAX if the type is unknown.
+*/
+pub fn dt_to_id(typ: u32, num: u32) -> u32 {
+	match typ {
+		0 => { return num + SPI_START; }
+		1 => { return num + PPI_START; }
+		_ => { return u32::MAX; }
+	}
+}
+
+/**
+* Interrupt ID for entry `idx` in interrupts property of node, u32::MAX if it
+* doesn't exist. Entries are 3 cells as specified by the GIC binding.
+*/
+pub fn dt_irq(fdt: &dtb::Fdt, node: &dtb::Node, idx: usize) -> u32 {
+	let typ = fdt.prop_cell(node, "interrupts", idx * 3);
+	let num = fdt.prop_cell(node, "interrupts", idx * 3 + 1);
+	match (typ, num) {
+		(Some(typ), Some(num)) => { return dt_to_id(typ, num); }
+		_ => { return u32::MAX; }
+	}
+}
+
 pub trait GIC {
+	/**
+	* Configure interrupt as secure (group 0), route it to core 0 and enable it.
+	*/
+	fn enable_secure(id: u32) {
+		let reg = ((id / 32) * 4) as u64;
+		let bit = 1 << (id % 32);
+		let group = gicd::read(gicd::off::IGROUPR + reg);
+		gicd::write(gicd::off::IGROUPR + reg, group & !bit);
+		gicd::write_u8(gicd::off::IPRIORITYR + id as u64, PRIORITY_DEFAULT);
+		if id >= SPI_START {
+			gicd::write_u8(gicd::off::ITARGETSR + id as u64, 1);
+		}
+		gicd::write(gicd::off::ISENABLER + reg, bit);
+	}
+	fn disable(id: u32) {
+		gicd::write(gicd::off::ICENABLER + ((id / 32) * 4) as u64, 1 << (id % 32));
+	}
 	fn acknowledge() -> u32 {
 		return gicc::read(gicc::off::IAR1) & 0xffffff;
 	}
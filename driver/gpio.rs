@@ -1,9 +1,57 @@
+/**
+* Generic GPIO definitions and the secure ownership table.
+*
+* GPIOs are numbered across all registered controllers, controller `n` has
+* GPIOs `n * 8` to `n * 8 + 7`. Only pins listed in the platform ownership
+* table can be driven or configured by the TEE, and only pins owned by
+* applets can be used through SYSNO_GPIO.
+*/
+use platform;
+
 pub const DIR_IN:  u32 = 0;
 pub const DIR_OUT: u32 = 1;
 pub const HIGH: u8 = 1;
 pub const LOW:  u8 = 0;
 
+/* Interrupt triggers */
+pub const EDGE_RISING:  u32 = 0;
+pub const EDGE_FALLING: u32 = 1;
+pub const EDGE_BOTH:    u32 = 2;
+pub const LEVEL_HIGH:   u32 = 3;
+pub const LEVEL_LOW:    u32 = 4;
+
+/* Who can use the pin */
+pub const OWNER_NONE:   u8 = 0;
+pub const OWNER_KERNEL: u8 = 1;
+pub const OWNER_APPLET: u8 = 2;
+
+/* Operations in SYSNO_GPIO */
+pub mod op {
+	pub const DIRECTION: u64 = 0;
+	pub const SET:       u64 = 1;
+	pub const GET:       u64 = 2;
+	/* Start counting events with trigger in argument */
+	pub const WATCH:     u64 = 3;
+	/*
+	* Get and reset number of events since last call, only for applet watching.
+	* Level triggered pins are masked after an event until this is called.
+	*/
+	pub const EVENTS:    u64 = 4;
+}
+
 pub mod qemu {
 	pub const POWEROFF: u32 = 0;
 	pub const RESET:    u32 = 1;
 }
+
+/**
+* Owner of pin according to platform table, OWNER_NONE if TEE can't use it.
+*/
+pub fn owner(gpio: u32) -> u8 {
+	for &(pin, owner) in platform::board().secure_pins.iter() {
+		if pin == gpio {
+			return owner;
+		}
+	}
+	return OWNER_NONE;
+}
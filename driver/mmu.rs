@@ -13,9 +13,28 @@
 * secure memory. Any code which need to access physical memory should translate
 * address using `paddr2linear`. 
 *
+* The linear region is mapped with the largest blocks possible (1GB or 2MB),
+* except around the image which is mapped with pages. Blocks are split on
+* demand if a single page inside it must be changed.
+*
 * The linear region is set up with RW permission for EL1. It is therefore
 * possible to write to the .text segment using the linear region. A future
 * improvement is to limit permission on the regions falling into the image.
+*
+* Every applet address space in TTBR1 is tagged with an ASID, all pages in the
+* upper half are mapped as non-global. The ASID is allocated the first time the
+* address space is switched in and is kept until we run out of ASIDs, at which
+* point all ASIDs are released and the entire TLB is flushed. Any change to an
+* existing entry is followed by TLB invalidation of that VA.
+*
+* Number of valid entries in each table is counted in the pmm page counter, when
+* the last entry is removed the table is freed. Each address space registered
+* through `alloc_pgd` also keeps statistics of pages and tables used.
+*
+* Memory attributes are set up in MAIR_EL1 with index 0 as normal write-back
+* memory. All mappings therefore default to normal cacheable memory, device
+* memory must explicitly be mapped with `ATTR_DEVICE`. Memory shared with
+* normal world is mapped with `ATTR_NORMAL_NC`.
 */
 use cpu;
 use lib::memory;
@@ -24,6 +43,7 @@ use lib::log;
 use lib::sizes;
 use driver::pmm;
 use driver::dtb;
+use platform;
 
 extern "C" {
 	fn image_fill_map(map: &ImageMap);
@@ -39,12 +59,16 @@ const TCR_OFFSET_ORGN0: u64 = 10;
 const TCR_OFFSET_SH0:   u64 = 12;
 const TCR_OFFSET_TG0:   u64 = 14;
 const TCR_OFFSET_T1SZ:  u64 = 16;
+const TCR_OFFSET_A1:    u64 = 22;
 const TCR_OFFSET_EPD1:  u64 = 23;
 const TCR_OFFSET_IRGN1: u64 = 24;
 const TCR_OFFSET_ORGN1: u64 = 26;
 const TCR_OFFSET_SH1:   u64 = 28;
 const TCR_OFFSET_TG1:   u64 = 30;
 const TCR_OFFSET_IPS:   u64 = 32;
+const TCR_OFFSET_AS:    u64 = 36;
+
+const TTBR_OFFSET_ASID: u64 = 48;
 
 const MMU_NORMAL_NC:       u64 = 0;
 const MMU_NORMAL_WB_RA_WA: u64 = 1;
@@ -55,6 +79,18 @@ const MMU_NON_SHAREABLE:  u64 = 0;
 const MMU_OUT_SHAREABLE:  u64 = 2;
 const MMU_INN_SHAREABLE:  u64 = 3;
 
+// Memory attributes in MAIR_EL1, the index is used as AttrIndx in descriptor
+const MAIR_IDX_NORMAL:    u64 = 0;
+const MAIR_IDX_NORMAL_NC: u64 = 1;
+const MAIR_IDX_DEVICE:    u64 = 2;
+
+const MAIR_NORMAL_WB:     u64 = 0xff;
+const MAIR_NORMAL_NC:     u64 = 0x44;
+const MAIR_DEVICE_NGNRNE: u64 = 0x00;
+
+const MMU_OFFSET_ATTR: u64 = 2;
+const MMU_OFFSET_SH:   u64 = 8;
+
 const MMU_GRANULE_4KB:  u64 = 0;
 const MMU_GRANULE_64KB: u64 = 1;
 const MMU_GRANULE_16KB: u64 = 2;
@@ -69,6 +105,11 @@ const MMU_IPS_52B: u64 = 6;
 
 const MMU_ENTRY_NEXT_TBL: u64 = 1 | 2;
 const MMU_ENTRY_NEXT_PAGE: u64 = 1 | 2 | (1 << 10);
+const MMU_ENTRY_BLOCK: u64 = 1 | (1 << 10);
+const MMU_ENTRY_BLOCK_TYPE: u64 = 1;
+
+// Output address in bits [47:12]
+const MMU_OA_MASK: u64 = ((1 << (48 - 12)) - 1) << 12;
 
 
 
@@ -79,6 +120,14 @@ const MMU_UXN: u64 = 1 << 54;
 const MMU_PXN: u64 = 1 << 53;
 
 const MMU_NS:  u64 = 1 << 5;
+const MMU_NG:  u64 = 1 << 11;
+
+/*
+* Memory type, can be combined with permissions below
+*/
+pub const ATTR_NORMAL:    u64 = MAIR_IDX_NORMAL << MMU_OFFSET_ATTR;
+pub const ATTR_NORMAL_NC: u64 = MAIR_IDX_NORMAL_NC << MMU_OFFSET_ATTR;
+pub const ATTR_DEVICE:    u64 = MAIR_IDX_DEVICE << MMU_OFFSET_ATTR;
 
 /*
 * Possible permissions when called from outside the MMU
@@ -91,13 +140,41 @@ pub const EL1_RW: u64 = AP_RW | MMU_UXN | MMU_PXN;
 pub const EL1_RO: u64 = AP_RO | MMU_UXN | MMU_PXN;
 pub const EL1_RX: u64 = AP_RO;
 
-// Start linear region at index 2 in pud
+// Memory in normal world shared with us, it's only read
+const PROT_NS_SHARED: u64 = EL1_RO | MMU_NS | ATTR_NORMAL_NC;
+
+// Start linear region at index 2 in pud, with random_linear it's placed at a
+// random offset below the temp region. Only the linear region is randomized,
+// the image stays at the address firmware loaded it to.
 pub const START_LINEAR_REGION:    u64 = 1 << 31;
+const LINEAR_ALIGN:               u64 = sizes::MB * 2;
 pub const START_TEMP_REGION:      u64 = 1 << 34;
 
+// Kernel heap grows upwards from here
+pub const START_HEAP_REGION:      u64 = 1 << 35;
+pub const HEAP_REGION_SIZE:       u64 = sizes::GB;
+
+// Page below heap is used to access a table entry whose linear mapping is being
+// replaced, see LinearMem::replace
+const FIXMAP_VADDR:               u64 = START_HEAP_REGION - PAGE_SIZE;
+
 //pub const START_NS_LINEAR_REGION: u64 = 1 << 32;
 pub const PAGE_SIZE: u64 = 4096;
-pub const VA_BITS: u64 = 39;
+
+// 39 bits gives 3 levels of page tables, 48 bits gives 4 levels
+#[cfg(not(va_bits = "48"))] pub const VA_BITS: u64 = 39;
+#[cfg(va_bits = "48")]      pub const VA_BITS: u64 = 48;
+
+// First level used in table walk, depends on VA_BITS
+const START_LEVEL: u64 = 3 - ((VA_BITS - 12 - 1) / 9);
+
+// Lowest level where we can use blocks, 1GB with 4KB granule
+const BLOCK_MIN_LEVEL: u64 = 1;
+
+// Use 8-bit ASIDs since that is supported on all implementations, ASID 0 is
+// reserved for when no applet is mapped in
+pub const ASID_BITS: u64 = 8;
+const MAX_ASID: usize = 1 << ASID_BITS;
 
 pub fn mask_prot_el0(prot: u64) -> u64 {
 	return prot & (AP_EL0 | MMU_UXN | MMU_PXN | AP_RO | AP_RW)
@@ -130,6 +207,33 @@ struct MmuData {
 	image: ImageMap,
 }
 
+struct AsidData {
+	/* TTBR which currently owns each ASID, 0 if ASID is free */
+	owners: [u64; MAX_ASID],
+}
+
+static mut ASIDS: AsidData = AsidData{owners: [0; MAX_ASID]};
+
+// Max number of address spaces we keep statistics for
+const MAX_ADDR_SPACES: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct AddrSpace {
+	/* Page directory, 0 if slot is free */
+	ttbr: u64,
+
+	/* Number of 4KB pages mapped, blocks count as multiple pages */
+	pages: u64,
+
+	/* Number of tables, including page directory */
+	tables: u64,
+
+	/* Maximum number of pages and tables, 0 means no limit */
+	quota: u64,
+}
+
+static mut SPACES: [AddrSpace; MAX_ADDR_SPACES] = [AddrSpace{ttbr: 0, pages: 0, tables: 0, quota: 0}; MAX_ADDR_SPACES];
+
 // All data associated with MMU
 static mut MMUDATA: MmuData = MmuData{
 	ttbr: 0,
@@ -143,18 +247,25 @@ static mut MMUDATA: MmuData = MmuData{
 };
 
 
+// Number of bytes mapped by one entry at level
+macro_rules! level_size {
+	($level:expr) => {
+		(1 << (12 + (9 * (3 - $level))))
+	};
+}
+// Index in table at level, the first level may have less than 512 entries
 macro_rules! mmu_idx {
-	($vaddr:expr) => {
-		(
-			(($vaddr & (511 << 30)) >> 30),
-			(($vaddr & (511 << 21)) >> 21),
-			(($vaddr & (511 << 12)) >> 12)
-		)
+	($vaddr:expr, $level:expr) => {
+		if $level == START_LEVEL {
+			($vaddr >> (12 + (9 * (3 - $level)))) & ((1 << (VA_BITS - (12 + (9 * (3 - $level))))) - 1)
+		} else {
+			($vaddr >> (12 + (9 * (3 - $level)))) & 511
+		}
 	};
 }
 macro_rules! mmu_oa {
 	($vaddr:expr) => {
-		$vaddr & (((1<<VA_BITS-12)-1)<<12)
+		$vaddr & MMU_OA_MASK
 	};
 }
 #[macro_export]
@@ -193,10 +304,20 @@ pub const VA_RESERVED_STOP:  u64 = VA_RESERVED_START + VA_RESERVED_SIZE;
 
 #[no_mangle]
 pub extern "C" fn mmu_init_cpu(pud: u64) {
-	let tcr = ((64-VA_BITS) << TCR_OFFSET_T0SZ) | (MMU_GRANULE_4KB << MMU_GRANULE_4KB) |
+	let mair = (MAIR_NORMAL_WB << (MAIR_IDX_NORMAL * 8)) |
+		(MAIR_NORMAL_NC << (MAIR_IDX_NORMAL_NC * 8)) |
+		(MAIR_DEVICE_NGNRNE << (MAIR_IDX_DEVICE * 8));
+
+	// ASID is taken from TTBR1 since that's where applets are mapped
+	// Table walks use same attributes as normal memory
+	let tcr = ((64-VA_BITS) << TCR_OFFSET_T0SZ) | (MMU_GRANULE_4KB << TCR_OFFSET_TG0) |
 		(MMU_GRANULE_4KB << TCR_OFFSET_TG1) | ((64-VA_BITS) << TCR_OFFSET_T1SZ) |
-		(MMU_IPS_32B << TCR_OFFSET_IPS);
+		(MMU_NORMAL_WB_RA_WA << TCR_OFFSET_IRGN0) | (MMU_NORMAL_WB_RA_WA << TCR_OFFSET_ORGN0) |
+		(MMU_NORMAL_WB_RA_WA << TCR_OFFSET_IRGN1) | (MMU_NORMAL_WB_RA_WA << TCR_OFFSET_ORGN1) |
+		(MMU_INN_SHAREABLE << TCR_OFFSET_SH0) | (MMU_INN_SHAREABLE << TCR_OFFSET_SH1) |
+		(ips() << TCR_OFFSET_IPS) | (1 << TCR_OFFSET_A1) | (0 << TCR_OFFSET_AS);
 
+	cpu::register::write_mair_el1!(mair);
 	cpu::register::write_tcr_el1!(tcr);
 
 	cpu::register::write_ttbr0_el1!(pud);
@@ -205,12 +326,27 @@ pub extern "C" fn mmu_init_cpu(pud: u64) {
 	memory::isb!();
 	memory::dsb::ish!();
 
-	// Enable MMU
-	let sctlr: u64 = cpu::register::read_sctlr_el1!() | 1;
+	// Remove anything left behind by earlier boot stages
+	memory::tlbi::vmalle1is!();
+	memory::dsb::ish!();
+	memory::isb!();
+
+	// Enable MMU and caches, attributes are correct so this is now safe
+	let sctlr: u64 = cpu::register::read_sctlr_el1!() |
+		cpu::register::sctlr::M | cpu::register::sctlr::C | cpu::register::sctlr::I;
 	cpu::register::write_sctlr_el1!(sctlr);
 	memory::isb!();
 }
 
+/**
+* Physical address size supported by the CPU, limited to 48 bits since that is
+* the largest output address we can put in a descriptor.
+*/
+fn ips() -> u64 {
+	let parange = cpu::register::read::id_aa64mmfr0_el1() & 0xf;
+	return core::cmp::min(parange, MMU_IPS_48B);
+}
+
 pub fn init(imgstart: u64, imgend: u64) -> u64	{
 
 	//let map = ImageMap::default();
@@ -219,40 +355,27 @@ pub fn init(imgstart: u64, imgend: u64) -> u64	{
 	unsafe { image_fill_map(&MMUDATA.image) };
 	let map = unsafe { &MMUDATA.image };
 
-	// We assume 3-level page tables in the code below
-	assert!(VA_BITS > 30 && VA_BITS <= 39);
+	// 3 or 4 levels are supported
+	assert!(VA_BITS > 30 && VA_BITS <= 48);
 
-	let (l1a, l2a, _l3a) = mmu_idx!(imgstart);
-	let (l1b, l2b, _l3b) = mmu_idx!(imgend);
-
-	// TODO: Should support this, but unlikely to happen
-	assert!(l1a == l1b && l2a == l2b);
-
-	// We don't have any linear memory region set up, so we follow a different
-	// procedure to set up the first identity map
-//	let pud = pmm::allocz();
+	// We don't have any linear memory region set up yet, so linear offset is
+	// 0 and all tables are accessed with their physical address until MMU is
+	// enabled.
 	let pud = unsafe { get_pgd_el1() };
-	let pmd = pmm::allocz();
-	let ptd = pmm::allocz();
-
-	// Write entries up until PTD
-	memory::dma::write::u64(pud + (l1a * 8), pmd | MMU_ENTRY_NEXT_TBL);
-	memory::dma::write::u64(pmd + (l2a * 8), ptd | MMU_ENTRY_NEXT_TBL);
-
 	unsafe { MMUDATA.ttbr = pud; }
+	pmm::counter_set(pud, 0);
+	assert!(space_register(pud) == 0);
+	let m = &mut LinearMem;
 
+	// Identity map image region
 	for addr in (imgstart..imgend).step_by(PAGE_SIZE as usize) {
-		let (_, _, l) = mmu_idx!(addr);
-		memory::dma::write::u64(
-			ptd + (l * 8),
-			mmu_oa!(addr) | mmu_image_prot(map, addr, EL1_RO) | MMU_ENTRY_NEXT_PAGE
-		);
+		map_page(m, pud, addr, addr, mmu_image_prot(map, addr, EL1_RO));
 	}
 	// Ensure that table is filled before continuing
 	memory::smp_mb!();
 
 	// Create linear region
-	let (ramstart, ramsize) = dtb::get_secure_memory();
+	let (ramstart, ramsize) = platform::secure_memory();
 	assert!(ramstart != u64::MAX && ramsize != u64::MAX);
 
 	let ramend = ramstart + ramsize;
@@ -261,21 +384,21 @@ pub fn init(imgstart: u64, imgend: u64) -> u64	{
 	assert!((ramstart % PAGE_SIZE) == 0);
 	assert!((ramend % PAGE_SIZE) == 0);
 
-	//map_region(pud, START_LINEAR_REGION + ramstart, ramstart, ramend, AP_RW | MMU_UXN | MMU_PXN);
-
-	for addr in (ramstart..ramend).step_by(PAGE_SIZE as usize) {
-		let vaddr = START_LINEAR_REGION + addr;
+	let linear = if cfg!(random_linear) {
+		linear_offset(dtb::get_kaslr_seed(), ramend)
+	} else {
+		START_LINEAR_REGION
+	};
+	map_linear(m, pud, linear, ramstart, ramend, map);
 
-		// If we're in image region, we match the protection from image region,
-		// otherwise it's RW
-		map_page(pud, vaddr, addr, mmu_image_prot(map, addr, EL1_RW));
-	}
+	// Tables for fixmap are allocated now so that using it never allocates
+	assert!(walk(m, pud, FIXMAP_VADDR, 3, true) != 0);
 
 	// Set rest of MMUDATA, image has already been set
 	unsafe {
 		MMUDATA.phys.start = ramstart;
 		MMUDATA.phys.stop = ramend;
-		MMUDATA.linear = START_LINEAR_REGION;
+		MMUDATA.linear = linear;
 		MMUDATA.ttbr = pud;
 	}
 
@@ -283,6 +406,18 @@ pub fn init(imgstart: u64, imgend: u64) -> u64	{
 
 	return unsafe { MMUDATA.linear };
 }
+/**
+* Offset for linear region chosen from `seed`. Region is placed between
+* START_LINEAR_REGION and START_TEMP_REGION, aligned so that blocks can be used.
+* Seed of 0 gives the default offset.
+*/
+fn linear_offset(seed: u64, ramend: u64) -> u64 {
+	if seed == 0 || ramend >= START_TEMP_REGION - START_LINEAR_REGION {
+		return START_LINEAR_REGION;
+	}
+	let slots = (START_TEMP_REGION - START_LINEAR_REGION - ramend) / LINEAR_ALIGN + 1;
+	return START_LINEAR_REGION + (seed % slots) * LINEAR_ALIGN;
+}
 pub fn paddr2linear(paddr: u64) -> u64 {
 	return paddr_to_linear!(paddr);
 }
@@ -293,12 +428,86 @@ pub fn map_dma(addrstart: u64, addrend: u64) -> i32 {
 	assert!(rend > rstart);
 	let pud = unsafe { MMUDATA.ttbr };
 
-	// TODO: Should also set non-cacheable
-	map_region(pud, rstart, rstart, rend, AP_RW | MMU_UXN | MMU_PXN);
+	map_region(&mut LinearMem, pud, rstart, rstart, rend, EL1_RW | ATTR_DEVICE);
 
 	return 0;
 }
-pub fn alloc_pgd() -> u64 { return pmm::allocz(); }
+/**
+* Allocate page directory for a new address space. Returns u64::MAX if we're out
+* of memory or already keep statistics for MAX_ADDR_SPACES address spaces, since
+* the quota can't be enforced for an address space we don't know about.
+*/
+pub fn alloc_pgd() -> u64 {
+	let pgd = pmm::allocz();
+	if pgd == u64::MAX {
+		return u64::MAX;
+	}
+	pmm::counter_set(pgd, 0);
+	if space_register(pgd) < 0 {
+		pmm::free(pgd);
+		return u64::MAX;
+	}
+	return pgd;
+}
+
+/**
+* Free all pages and tables in address space and the page directory itself.
+*
+* Only used for applet address spaces, every page mapped at the lowest level is
+* assumed to be owned by the address space and is returned to pmm. Address
+* space must not be active in TTBR1.
+*/
+pub fn destroy_address_space(ttbr: u64) {
+	if ttbr == 0 || ttbr == u64::MAX || ttbr == unsafe { MMUDATA.ttbr } {
+		log::bug("Tried to destroy invalid address space");
+		return;
+	}
+	free_table(&mut LinearMem, ttbr, START_LEVEL);
+
+	// Must be released after tables are gone, but before page directory is
+	// freed and can be reused
+	asid_release(ttbr);
+	pmm::free(ttbr);
+
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &mut SPACES[i] };
+		if space.ttbr == ttbr {
+			*space = AddrSpace::default();
+		}
+	}
+}
+
+/**
+* Get statistics for address space as (pages mapped, tables used).
+*
+* Returns (u64::MAX, u64::MAX) if address space is unknown.
+*/
+pub fn stats(ttbr: u64) -> (u64, u64) {
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &SPACES[i] };
+		if space.ttbr != 0 && space.ttbr == ttbr {
+			return (space.pages, space.tables);
+		}
+	}
+	return (u64::MAX, u64::MAX);
+}
+/**
+* Limit number of pages address space can use, page tables are included. 0
+* removes the limit. Returns -1 if address space is unknown.
+*/
+pub fn set_quota(ttbr: u64, pages: u64) -> i32 {
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &mut SPACES[i] };
+		if space.ttbr != 0 && space.ttbr == ttbr {
+			space.quota = pages;
+			return 0;
+		}
+	}
+	return -1;
+}
+pub fn kernel_ttbr() -> u64 {
+	return unsafe { MMUDATA.ttbr };
+}
 
 pub fn alloc_pages_el1(vaddr: u64, pages: i32, prot: u64) -> i32 {
 	let pud = cpu::register::read_ttbr0_el1!();
@@ -310,22 +519,67 @@ pub fn alloc_page_el1(vaddr: u64, prot: u64) -> i32 {
 }
 pub fn page_available_el1(vaddr: u64) -> bool {
 	let pud = cpu::register::read_ttbr0_el1!();
-	return pages_available(pud, vaddr, 1) == 1;
+	return pages_available(&mut LinearMem, pud, vaddr, 1) == 1;
+}
+/**
+* Check if EL1 can read `vaddr` without faulting or causing side effects. Only
+* the linear region and the image are accepted, and the page must not be device
+* memory.
+*/
+pub fn el1_readable(vaddr: u64) -> bool {
+	let d = unsafe { &MMUDATA };
+	let inlinear = vaddr >= d.phys.start + d.linear && vaddr < d.phys.stop + d.linear;
+	let inimage = vaddr >= d.image.text.start && vaddr <= d.image.data.stop;
+	if ! inlinear && ! inimage {
+		return false;
+	}
+	return mapped_normal(&mut LinearMem, d.ttbr, vaddr);
 }
 
 pub fn alloc_page(pud: u64, vaddr: u64, prot: u64) -> i32 {
-	let page = pmm::allocz();
-	map_page(pud, vaddr, page, prot);
-	return 0;
+	return map_new_page(&mut LinearMem, pud, vaddr, prot);
 }
+/**
+* Switch address space in TTBR1, an ASID is allocated for `ttbr` if it doesn't
+* already have one. Passing 0 removes the current address space.
+*/
 pub fn switch_ttbr1(ttbr: u64) {
-	cpu::register::write_ttbr1_el1!(ttbr);
+	let mut val = 0;
+	if ttbr != 0 {
+		let asid = asid_alloc(ttbr);
+		val = ttbr | (asid << TTBR_OFFSET_ASID);
+	}
+	cpu::register::write_ttbr1_el1!(val);
+	memory::isb!();
+}
+
+/**
+* Release ASID held by `ttbr` and remove all its entries from the TLB. Must be
+* called before the page directory is freed, otherwise a new address space may
+* get the same physical address and inherit the ASID.
+*/
+pub fn asid_release(ttbr: u64) {
+	let asid = asid_lookup(ttbr);
+	if asid != 0 {
+		memory::dsb::ishst!();
+		memory::tlbi::aside1is!(asid << TTBR_OFFSET_ASID);
+		memory::dsb::ish!();
+		memory::isb!();
+		unsafe { ASIDS.owners[asid as usize] = 0; }
+	}
 }
+/**
+* Allocate and map `pages` pages at `vaddr`. If we run out of memory or hit the
+* quota, pages mapped so far are released and -1 is returned.
+*/
 pub fn alloc_pages(pud: u64, vaddr: u64, pages: i32, prot: u64) -> i32 {
 	// Check if free first
-	if pages_available(pud, vaddr, pages) == pages {
+	if pages_available(&mut LinearMem, pud, vaddr, pages) == pages {
 		for i in 0..pages {
-			alloc_page(pud, vaddr + (i as u64 * PAGE_SIZE), prot);
+			if alloc_page(pud, vaddr + (i as u64 * PAGE_SIZE), prot) < 0 {
+				unmap_pages(pud, vaddr, i);
+				return -1;
+			}
 		}
 	} else {
 		log::info("Tried to allocate pages from region already allocated");
@@ -333,147 +587,752 @@ pub fn alloc_pages(pud: u64, vaddr: u64, pages: i32, prot: u64) -> i32 {
 	}
 	return 0;
 }
+/**
+* Unmap pages and return them to pmm. Returns -1 if a block containing one of
+* the pages couldn't be split, that page and the ones after it are left mapped.
+*/
 pub fn unmap_pages(pud: u64, vaddr: u64, pages: i32) -> i32 {
 	// Unmap and free from pmm
 	for i in 0..pages {
 		let nvaddr = vaddr + (i as u64 * PAGE_SIZE);
-		try_unmap_page(pud, nvaddr, true);
+		if try_unmap_page(&mut LinearMem, pud, nvaddr, true) < 0 {
+			return -1;
+		}
 	}
 	return 0;
 }
 
-fn ensure_mapped_in(pud: u64, vaddr: u64, len: u64, prot: u64) {
+/**
+* Unmap pages without returning the physical pages to pmm, used for memory we
+* don't own, like non-secure memory.
+*/
+fn unmap_region<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, pages: u64) {
+	for i in 0..pages {
+		clear_page(m, pud, vaddr + (i * PAGE_SIZE));
+	}
+}
+
+fn ensure_mapped_in<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, len: u64, prot: u64) -> i32 {
 	let avaddr = math::align_pow2_down!(vaddr, PAGE_SIZE);
 	let blocks = math::align_pow2_up!(len + (vaddr - avaddr), PAGE_SIZE);
 	let pages = blocks / PAGE_SIZE;
 	for i in 0..pages {
 		let nvaddr = avaddr + (i * PAGE_SIZE);
-		if ! try_unmap_page(pud, nvaddr, false) {
-			alloc_page(pud, nvaddr, prot);
+		if try_unmap_page(m, pud, nvaddr, false) == 0 {
+			if map_new_page(m, pud, nvaddr, prot) < 0 {
+				return -1;
+			}
 		}
 	}
-
+	return 0;
 }
 fn map_nonsecure_memory(ttbr: u64, paddr: u64, len: u64) -> (u64, u64)	{
 	let rpaddr = math::align_pow2_down!(paddr, PAGE_SIZE);
-	let offstart = rpaddr - paddr;
-	let rlen = math::align_pow2_up!(paddr + len, PAGE_SIZE);
-	let pages = rlen / PAGE_SIZE;
+	let offstart = paddr - rpaddr;
+	let rend = math::align_pow2_up!(paddr + len, PAGE_SIZE);
+	let pages = (rend - rpaddr) / PAGE_SIZE;
+	let m = &mut LinearMem;
 
 	// Ensure all pages are unmapped
-	unmap_pages(ttbr, START_TEMP_REGION, pages as i32);
+	unmap_region(m, ttbr, START_TEMP_REGION, pages);
 
-	map_region(ttbr, START_TEMP_REGION, rpaddr, rpaddr + (pages * PAGE_SIZE), EL1_RO | MMU_NS);
+	// Normal world may still have the memory in its caches, so lines are
+	// written back before we read through the non-cacheable alias
+	map_region(m, ttbr, START_TEMP_REGION, rpaddr, rpaddr + (pages * PAGE_SIZE), PROT_NS_SHARED);
+	memory::cache::clean_inval(START_TEMP_REGION, pages * PAGE_SIZE);
 	return (START_TEMP_REGION + offstart, pages);
 
 }
 pub fn memcpy_ns(pud: u64, vaddr: u64, paddr: u64, len: u64, mapin: bool) -> i32	{
 	if len == 0 || paddr == u64::MAX { return 0; }
 
+	// Address and length come from normal world, temp region must have room
+	// for all pages
+	match paddr.checked_add(len + PAGE_SIZE) {
+		Some(_) if len < FIXMAP_VADDR - START_TEMP_REGION - PAGE_SIZE => { }
+		_ => { return -1; }
+	}
+
 	let ttbr = cpu::register::read_ttbr0_el1!();
-	if mapin {
-		ensure_mapped_in(pud, vaddr, len, EL0_RO);
+	let m = &mut LinearMem;
+	if mapin && ensure_mapped_in(m, pud, vaddr, len, EL0_RO) < 0 {
+		return -1;
 	}
 	let (npaddr, pages) = map_nonsecure_memory(ttbr, paddr, len);
 
-	_memcpy_ns(pud, vaddr, npaddr, len);
+	let ret = _memcpy_ns(m, pud, vaddr, npaddr, len);
 
 	if mapin {
 		// Unmap normal memory after we're done
-		unmap_pages(ttbr, npaddr, pages as i32);
+		unmap_region(m, ttbr, math::align_pow2_down!(npaddr, PAGE_SIZE), pages);
 	}
-	return 0;
+	return ret;
 }
 
-fn _memcpy_ns(pud: u64, vaddr: u64, paddr: u64, len: u64)	{
+/**
+* Copy `len` bytes from `paddr` to `vaddr` in `pud`, returns -1 if part of the
+* destination isn't mapped.
+*/
+fn _memcpy_ns<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, paddr: u64, len: u64) -> i32 {
 	let avaddr = math::align_pow2_down!(vaddr, PAGE_SIZE);
 	let off = vaddr - avaddr;
-	let p1 = vaddr_to_paddr(pud, avaddr);
-	let p2 = vaddr_to_paddr(pud, math::align_pow2_down!(vaddr + len, PAGE_SIZE));
+	let p1 = vaddr_to_paddr(m, pud, avaddr);
+	if p1 == u64::MAX {
+		return -1;
+	}
 
-	let lin = paddr_to_linear!(p1 + off);
-	if p1 == p2 {
-		unsafe { memcpy(lin, paddr, len) };
-	} else {
-		let copy = math::align_pow2_up!(vaddr, PAGE_SIZE) - vaddr;
-		unsafe { memcpy(lin, paddr, copy) };
-		return _memcpy_ns(pud, vaddr + copy, paddr + copy, len - copy);
+	// Copy what fits in this page and continue on next page
+	let copy = if len < PAGE_SIZE - off { len } else { PAGE_SIZE - off };
+	m.copy_to(p1 + off, paddr, copy);
+	if copy < len {
+		return _memcpy_ns(m, pud, vaddr + copy, paddr + copy, len - copy);
 	}
+	return 0;
+}
+
+/**
+* Map the pages at `src` in address space `from` at `dst` in address space `to`,
+* used to share memory between applets. Source pages must be accessible from
+* EL0 and writable if `prot` is writable. Returns -1 with nothing mapped if a
+* page isn't mapped, destination is in use or a table couldn't be allocated.
+*
+* Every shared page holds a reference to the physical page, so it stays
+* allocated until it has been unmapped from both address spaces. Shared pages
+* are removed with unshare_pages, or freed like other pages if they are
+* unmapped with unmap_pages.
+*/
+pub fn share_pages(from: u64, src: u64, to: u64, dst: u64, pages: u64, prot: u64) -> i32 {
+	return share_region(&mut LinearMem, from, src, to, dst, pages, prot);
+}
+pub fn unshare_pages(pud: u64, vaddr: u64, pages: u64) {
+	unshare_region(&mut LinearMem, pud, vaddr, pages);
+}
+
+/**
+* Read u64 values at `vaddr` in applet address space, memory must be readable
+* from EL0. Returns -1 if it isn't.
+*/
+pub fn copy_from_el0(pud: u64, vaddr: u64, out: &mut [u64]) -> i32 {
+	return read_el0(&mut LinearMem, pud, vaddr, out);
+}
+/**
+* Write u64 values to `vaddr` in applet address space, memory must be writable
+* from EL0. Returns -1 if it isn't.
+*/
+pub fn copy_to_el0(pud: u64, vaddr: u64, data: &[u64]) -> i32 {
+	return write_el0(&mut LinearMem, pud, vaddr, data);
 }
 
 
 // ---------------------------- Internal functions ------------------------ //
 
-fn get_tbl(tbl: u64, idx: u64, create: bool) -> u64 {
-	let e1 = memory::dma::read::u64(paddr_to_linear!(tbl + (idx * 8)));
+/**
+* Access to physical memory and TLB used by the page table code.
+*
+* Kernel uses `LinearMem`, which goes through the linear region and pmm. This
+* allows the page table logic to be tested on the host with fake memory.
+*/
+pub trait PhysMem {
+	fn read(&self, paddr: u64) -> u64;
+	fn write(&mut self, paddr: u64, val: u64);
+
+	/* Allocate a zeroed page, returns u64::MAX if out of memory */
+	fn alloc(&mut self) -> u64;
+	fn free(&mut self, paddr: u64);
+	/* Page is only freed when free has been called once for every reference */
+	fn addref(&mut self, paddr: u64);
+
+	/* Per-page counter, see pmm::counter */
+	fn counter_set(&mut self, paddr: u64, val: u16);
+	fn counter_add(&mut self, paddr: u64, val: i32) -> u16;
+
+	/* Copy `len` bytes from virtual address `src` to physical `paddr` */
+	fn copy_to(&mut self, paddr: u64, src: u64, len: u64);
+
+	/* Ensure tables writes are visible to table walker */
+	fn barrier(&mut self);
+	fn tlb_inval(&mut self, pud: u64, vaddr: u64);
+
+	/*
+	* Replace valid entry at `eaddr` with `val` using break-before-make, the
+	* old entry maps `size` bytes including `vaddr`.
+	*/
+	fn replace(&mut self, pud: u64, eaddr: u64, vaddr: u64, size: u64, val: u64) {
+		self.write(eaddr, 0);
+		self.tlb_inval(pud, math::align_pow2_down!(vaddr, size));
+		self.write(eaddr, val);
+		self.barrier();
+	}
+}
+
+pub struct LinearMem;
+
+impl PhysMem for LinearMem {
+	fn read(&self, paddr: u64) -> u64 {
+		return memory::dma::read::u64(paddr_to_linear!(paddr));
+	}
+	fn write(&mut self, paddr: u64, val: u64) {
+		memory::dma::write::u64(paddr_to_linear!(paddr), val);
+	}
+	fn alloc(&mut self) -> u64 { return pmm::allocz(); }
+	fn free(&mut self, paddr: u64) { pmm::free(paddr); }
+	fn addref(&mut self, paddr: u64) { pmm::addref(paddr); }
+	fn counter_set(&mut self, paddr: u64, val: u16) { pmm::counter_set(paddr, val); }
+	fn counter_add(&mut self, paddr: u64, val: i32) -> u16 { return pmm::counter_add(paddr, val); }
+	fn copy_to(&mut self, paddr: u64, src: u64, len: u64) {
+		unsafe { memcpy(paddr_to_linear!(paddr), src, len) };
+	}
+	fn barrier(&mut self) { memory::smp_mb!(); }
+	fn tlb_inval(&mut self, pud: u64, vaddr: u64) { tlb_inval_page(pud, vaddr); }
+
+	/*
+	* If the entry itself is in memory mapped by the old entry, it can't be
+	* written through the linear region while the old entry is gone. It's
+	* written through FIXMAP_VADDR instead, table walks use physical addresses
+	* so the fixmap works even if its own tables are in the same memory.
+	*/
+	fn replace(&mut self, pud: u64, eaddr: u64, vaddr: u64, size: u64, val: u64) {
+		let start = math::align_pow2_down!(vaddr, size);
+		let linear = paddr_to_linear!(eaddr);
+		let (ttbr, mmu_on) = unsafe { (MMUDATA.ttbr, MMUDATA.linear != 0) };
+		if ! mmu_on || pud != ttbr || linear < start || linear >= start + size {
+			self.write(eaddr, 0);
+			self.tlb_inval(pud, start);
+			self.write(eaddr, val);
+			self.barrier();
+			return;
+		}
+
+		// Fixmap entry isn't counted, so the table is never freed
+		let fix = walk(self, pud, FIXMAP_VADDR, 3, false);
+		let alias = FIXMAP_VADDR + (eaddr & (PAGE_SIZE - 1));
+		self.write(fix, mmu_oa!(eaddr) | page_prot(FIXMAP_VADDR, EL1_RW) | MMU_ENTRY_NEXT_PAGE);
+		self.tlb_inval(pud, FIXMAP_VADDR);
+
+		memory::dma::write::u64(alias, 0);
+		self.tlb_inval(pud, start);
+		memory::dma::write::u64(alias, val);
+		self.barrier();
+
+		self.write(fix, 0);
+		self.tlb_inval(pud, FIXMAP_VADDR);
+	}
+}
+
+fn entry_is_block(entry: u64, level: u64) -> bool {
+	return level < 3 && (entry & 0b11) == MMU_ENTRY_BLOCK_TYPE;
+}
+
+/**
+* Get next level table from entry at `idx` in `tbl`, allocating a new table if
+* `create` is set. Returns 0 if no table exists. Must not be called on an entry
+* containing a block.
+*/
+fn get_tbl<M: PhysMem>(m: &mut M, pud: u64, tbl: u64, idx: u64, create: bool) -> u64 {
+	let e1 = m.read(tbl + (idx * 8));
 	if e1 != 0 {
 		return mmu_oa!(e1);
 	} else if create {
-		let t1 = pmm::allocz();
-		memory::dma::write::u64(paddr_to_linear!(tbl + (idx * 8)), t1 | MMU_ENTRY_NEXT_TBL);
-		memory::smp_mb!();
+		if ! space_has_room(pud, 1) {
+			return 0;
+		}
+		let t1 = m.alloc();
+		if t1 == u64::MAX {
+			return 0;
+		}
+		m.counter_set(t1, 0);
+		m.write(tbl + (idx * 8), t1 | MMU_ENTRY_NEXT_TBL);
+		m.barrier();
 
+		m.counter_add(tbl, 1);
+		space_account(pud, 0, 1);
 		return t1;
 	}
 	return 0;
 }
-fn map_page(pud: u64, vaddr: u64, paddr: u64, prot: u64) {
-	let (l1, l2, l3) = mmu_idx!(vaddr);
-	let t2 = get_tbl(pud, l1, true);
-	let t3 = get_tbl(t2, l2, true);
-	let ridx = paddr_to_linear!(t3 + (l3 * 8));
-	let entry = memory::dma::read::u64(ridx);
+
+/**
+* Walk page tables down to `level` and return physical address of the entry
+* for `vaddr` in that level. If `create` is set, missing tables are allocated
+* and blocks above `level` are split. Returns 0 if the walk ended before
+* reaching `level`, this includes failing to allocate a table.
+*/
+fn walk<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, level: u64, create: bool) -> u64 {
+	let mut tbl = pud;
+	for lvl in START_LEVEL..level {
+		let idx = mmu_idx!(vaddr, lvl);
+		let entry = m.read(tbl + (idx * 8));
+		if entry_is_block(entry, lvl) {
+			if ! create { return 0; }
+			if ! split_block(m, pud, tbl + (idx * 8), vaddr, lvl) { return 0; }
+		}
+		tbl = get_tbl(m, pud, tbl, idx, create);
+		if tbl == 0 { return 0; }
+	}
+	return tbl + (mmu_idx!(vaddr, level) * 8);
+}
+
+/**
+* Find the entry which translates `vaddr`, this may be a page or a block.
+*
+* Returns physical address of the entry and level it was found at, or (0, 0) if
+* nothing is mapped.
+*/
+fn find_leaf<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64) -> (u64, u64) {
+	let mut tbl = pud;
+	for lvl in START_LEVEL..4 {
+		let eaddr = tbl + (mmu_idx!(vaddr, lvl) * 8);
+		let entry = m.read(eaddr);
+		if entry == 0 {
+			return (0, 0);
+		}
+		if lvl == 3 || entry_is_block(entry, lvl) {
+			return (eaddr, lvl);
+		}
+		tbl = mmu_oa!(entry);
+	}
+	return (0, 0);
+}
+
+/**
+* Replace block at `eaddr` with a table mapping the same region using entries
+* one level down. Attributes are copied to all new entries. Returns false if
+* the table couldn't be allocated, the block is then left intact.
+*/
+fn split_block<M: PhysMem>(m: &mut M, pud: u64, eaddr: u64, vaddr: u64, level: u64) -> bool {
+	let entry = m.read(eaddr);
+	let oa = entry & MMU_OA_MASK & !(level_size!(level) - 1);
+	let attrs = entry & !MMU_OA_MASK & !0b11;
+	let nsize = level_size!(level + 1);
+	let ntype = if level + 1 == 3 { MMU_ENTRY_NEXT_PAGE } else { MMU_ENTRY_BLOCK };
+
+	let tbl = m.alloc();
+	if tbl == u64::MAX {
+		return false;
+	}
+	m.counter_set(tbl, 512);
+	space_account(pud, 0, 1);
+	for i in 0..512 {
+		m.write(tbl + (i * 8), (oa + (i * nsize)) | attrs | ntype);
+	}
+
+	// Break-before-make, the old block must be gone from the TLB before the
+	// table is written. Table is filled before the block is removed, since the
+	// table itself may be in memory mapped by the block.
+	m.replace(pud, eaddr, vaddr, level_size!(level), tbl | MMU_ENTRY_NEXT_TBL);
+	return true;
+}
+
+fn page_prot(vaddr: u64, prot: u64) -> u64 {
+	// Shareability is ignored for device memory, so this is always safe
+	let mut nprot = prot | (MMU_INN_SHAREABLE << MMU_OFFSET_SH);
+
+	// Pages for applets are tagged with ASID
+	if inupper!(vaddr) {
+		nprot |= MMU_NG;
+	}
+	return nprot;
+}
+
+/**
+* Map `paddr` at `vaddr`, any page already mapped is replaced with
+* break-before-make and freed. Returns -1 if a table couldn't be allocated.
+*/
+fn map_page<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, paddr: u64, prot: u64) -> i32 {
+	let eaddr = walk(m, pud, vaddr, 3, true);
+	if eaddr == 0 {
+		return -1;
+	}
+	let entry = m.read(eaddr);
+	let nentry = mmu_oa!(paddr) | page_prot(vaddr, prot) | MMU_ENTRY_NEXT_PAGE;
+
 	if entry != 0 {
-		pmm::free( mmu_oa!(entry) );
+		m.replace(pud, eaddr, vaddr, PAGE_SIZE, nentry);
+		m.free( mmu_oa!(entry) );
+	} else {
+		m.write(eaddr, nentry);
+		m.barrier();
+		m.counter_add(math::align_pow2_down!(eaddr, PAGE_SIZE), 1);
+		space_account(pud, 1, 0);
 	}
-	memory::dma::write::u64(ridx, mmu_oa!(paddr) | prot | MMU_ENTRY_NEXT_PAGE);
-	memory::smp_mb!();
+	return 0;
+}
+
+/**
+* Map a block at `level`, both addresses must be aligned to the block size.
+* Returns false if something is already mapped in the region.
+*/
+fn map_block<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, paddr: u64, level: u64, prot: u64) -> bool {
+	assert!(level >= BLOCK_MIN_LEVEL && level < 3);
+	let eaddr = walk(m, pud, vaddr, level, true);
+	if eaddr == 0 || m.read(eaddr) != 0 {
+		return false;
+	}
+	m.write(eaddr, mmu_oa!(paddr) | page_prot(vaddr, prot) | MMU_ENTRY_BLOCK);
+	m.barrier();
+
+	m.counter_add(math::align_pow2_down!(eaddr, PAGE_SIZE), 1);
+	space_account(pud, (level_size!(level) / PAGE_SIZE) as i64, 0);
+	return true;
+}
+/**
+* Number of tables which must be allocated before a page can be mapped at
+* `vaddr`, a block is split into tables all the way down.
+*/
+fn tables_missing<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64) -> u64 {
+	let mut tbl = pud;
+	for lvl in START_LEVEL..3 {
+		let entry = m.read(tbl + (mmu_idx!(vaddr, lvl) * 8));
+		if entry == 0 || entry_is_block(entry, lvl) {
+			return 3 - lvl;
+		}
+		tbl = mmu_oa!(entry);
+	}
+	return 0;
+}
+/**
+* Allocate a new page and map it at `vaddr`. Returns -1 if we're out of memory
+* or the address space has reached its quota.
+*/
+fn map_new_page<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, prot: u64) -> i32 {
+	if ! space_has_room(pud, 1 + tables_missing(m, pud, vaddr)) {
+		log::info("Address space has reached its quota");
+		return -1;
+	}
+	let page = m.alloc();
+	if page == u64::MAX {
+		return -1;
+	}
+	if map_page(m, pud, vaddr, page, prot) < 0 {
+		m.free(page);
+		return -1;
+	}
+	return 0;
 }
-fn map_region(pud: u64, startvaddr: u64, start: u64, stop: u64, prot: u64)	{
+fn map_region<M: PhysMem>(m: &mut M, pud: u64, startvaddr: u64, start: u64, stop: u64, prot: u64)	{
 	for addr in (start..stop).step_by(PAGE_SIZE as usize) {
 		let vaddr = startvaddr + (addr - start);
-		map_page(pud, vaddr, addr, prot);
+		map_page(m, pud, vaddr, addr, prot);
 	}
 }
-fn vaddr_to_paddr(pud: u64, vaddr: u64) -> u64	{
-	let (l1, l2, l3) = mmu_idx!(vaddr);
-	let t2 = get_tbl(pud, l1, false);
-	if t2 == 0 { return u64::MAX; }
 
-	let t3 = get_tbl(t2, l2, false);
-	if t3 == 0 { return u64::MAX; }
+/**
+* Map physical secure memory in linear region. Largest possible blocks are
+* used, except around the image, which is mapped with pages so that the
+* permissions for each image segment can be used.
+*/
+fn map_linear<M: PhysMem>(m: &mut M, pud: u64, linear: u64, ramstart: u64, ramend: u64, map: &ImageMap) {
+	let mut addr = ramstart;
+	while addr < ramend {
+		let vaddr = linear + addr;
+		let mut size = PAGE_SIZE;
+		for level in BLOCK_MIN_LEVEL..3 {
+			let bsize = level_size!(level);
+			let aligned = (addr % bsize) == 0 && (vaddr % bsize) == 0;
+			let inimage = addr <= map.data.stop && addr + bsize > map.text.start;
+			if aligned && addr + bsize <= ramend && ! inimage {
+				if map_block(m, pud, vaddr, addr, level, EL1_RW) {
+					size = bsize;
+					break;
+				}
+			}
+		}
+		if size == PAGE_SIZE {
+			// If we're in image region, we match the protection from image
+			// region, otherwise it's RW
+			map_page(m, pud, vaddr, addr, mmu_image_prot(map, addr, EL1_RW));
+		}
+		addr += size;
+	}
+}
+fn vaddr_to_paddr<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64) -> u64	{
+	let (eaddr, level) = find_leaf(m, pud, vaddr);
+	if eaddr == 0 { return u64::MAX; }
 
-	let ridx = paddr_to_linear!(t3 + (l3 * 8));
-	let entry = memory::dma::read::u64(ridx);
-	let ret = mmu_oa!(entry);
+	let entry = m.read(eaddr);
+	let bsize = level_size!(level);
+	let ret = (mmu_oa!(entry) & !(bsize - 1)) + math::align_pow2_down!(vaddr & (bsize - 1), PAGE_SIZE);
 	if ret == 0 { return u64::MAX; }
 
 	return ret;
 }
 
 /**
-* Return true if page is mapped and false if it isn't.
+* Check if `vaddr` is mapped with a memory type other than device memory.
+*/
+fn mapped_normal<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64) -> bool {
+	let (eaddr, _level) = find_leaf(m, pud, vaddr);
+	if eaddr == 0 {
+		return false;
+	}
+	return (m.read(eaddr) & (0b111 << MMU_OFFSET_ATTR)) != ATTR_DEVICE;
+}
+
+/**
+* Physical address of `vaddr` if it's mapped for EL0 and writable if `write` is
+* set, u64::MAX otherwise.
+*/
+fn el0_paddr<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, write: bool) -> u64 {
+	let (eaddr, level) = find_leaf(m, pud, vaddr);
+	if eaddr == 0 || level != 3 {
+		return u64::MAX;
+	}
+	let entry = m.read(eaddr);
+	if (entry & AP_EL0) == 0 || (write && (entry & AP_RO) != 0) {
+		return u64::MAX;
+	}
+	return mmu_oa!(entry) + (vaddr & (PAGE_SIZE - 1));
+}
+fn read_el0<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, out: &mut [u64]) -> i32 {
+	if vaddr % 8 != 0 {
+		return -1;
+	}
+	for i in 0..out.len() {
+		let paddr = el0_paddr(m, pud, vaddr + (i as u64 * 8), false);
+		if paddr == u64::MAX {
+			return -1;
+		}
+		out[i] = m.read(paddr);
+	}
+	return 0;
+}
+fn write_el0<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, data: &[u64]) -> i32 {
+	if vaddr % 8 != 0 {
+		return -1;
+	}
+	// Check everything first so that we don't write partially
+	for i in 0..data.len() {
+		if el0_paddr(m, pud, vaddr + (i as u64 * 8), true) == u64::MAX {
+			return -1;
+		}
+	}
+	for i in 0..data.len() {
+		let paddr = el0_paddr(m, pud, vaddr + (i as u64 * 8), true);
+		m.write(paddr, data[i]);
+	}
+	return 0;
+}
+
+fn share_region<M: PhysMem>(m: &mut M, from: u64, src: u64, to: u64, dst: u64, pages: u64, prot: u64) -> i32 {
+	let write = (prot & AP_RO) == 0;
+	for i in 0..pages {
+		let off = i * PAGE_SIZE;
+		let paddr = el0_paddr(m, from, src + off, write);
+		if paddr == u64::MAX || find_leaf(m, to, dst + off).0 != 0 || map_page(m, to, dst + off, paddr, prot) < 0 {
+			unshare_region(m, to, dst, i);
+			return -1;
+		}
+		m.addref(paddr);
+	}
+	return 0;
+}
+
+/* Unmap pages mapped with share_region and drop their references */
+fn unshare_region<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, pages: u64) {
+	for i in 0..pages {
+		match clear_page(m, pud, vaddr + (i * PAGE_SIZE)) {
+			Some(paddr) if paddr != u64::MAX => { m.free(paddr); }
+			_ => { }
+		}
+	}
+}
+
+/**
+* Return 1 if page is mapped and 0 if it isn't.
+*
+* If dounmap is true, it will unmap the page as well. Returns -1 if page is part
+* of a block which couldn't be split, the page is then still mapped.
+*/
+fn try_unmap_page<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, dounmap: bool) -> i32 {
+	let (eaddr, _level) = find_leaf(m, pud, vaddr);
+	if eaddr == 0 {
+		return 0;
+	}
+	if dounmap {
+		match clear_page(m, pud, vaddr) {
+			Some(paddr) => { m.free(paddr); }
+			None => { return -1; }
+		}
+	}
+	return 1;
+}
+
+/**
+* Remove entry for page and invalidate it in TLB. If page is part of a block,
+* the block is first split.
 *
-* If dounmap is true, it will unmap the page as well.
+* Returns the physical address which was mapped, or u64::MAX if no page was
+* mapped. Returns None if the block couldn't be split, it's then left intact.
+*/
+fn clear_page<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64) -> Option<u64> {
+	let (eaddr, level) = find_leaf(m, pud, vaddr);
+	if eaddr == 0 { return Some(u64::MAX); }
+
+	let mut paddr = eaddr;
+	if level != 3 {
+		paddr = walk(m, pud, vaddr, 3, true);
+		if paddr == 0 {
+			return None;
+		}
+	}
+	let entry = m.read(paddr);
+
+	m.write(paddr, 0);
+	m.tlb_inval(pud, vaddr);
+	space_account(pud, -1, 0);
+	release_entry(m, pud, vaddr, paddr, 3);
+	return Some(mmu_oa!(entry));
+}
+
+/**
+* Called after entry at `eaddr` in table at `level` has been cleared. If table
+* is now empty, it's freed and removed from the table above.
 */
-fn try_unmap_page(pud: u64, vaddr: u64, dounmap: bool) -> bool {
-	let (l1, l2, l3) = mmu_idx!(vaddr);
-	let t2 = get_tbl(pud, l1, false);
-	if t2 == 0 { return false; }
+fn release_entry<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, eaddr: u64, level: u64) {
+	let tbl = math::align_pow2_down!(eaddr, PAGE_SIZE);
+	if m.counter_add(tbl, -1) != 0 || level == START_LEVEL {
+		return;
+	}
 
-	let t3 = get_tbl(t2, l2, false);
-	if t3 == 0 { return false; }
+	// Table is empty, so we remove it from the table above. Invalidating the VA
+	// also removes any cached table walk for it.
+	let peaddr = walk(m, pud, vaddr, level - 1, false);
+	m.write(peaddr, 0);
+	m.tlb_inval(pud, vaddr);
+	m.free(tbl);
+	space_account(pud, 0, -1);
 
-	let ridx = paddr_to_linear!(t3 + (l3 * 8));
-	let entry = memory::dma::read::u64(ridx);
-	if entry != 0 {
-		if dounmap {
-			pmm::free( mmu_oa!(entry) );
+	release_entry(m, pud, vaddr, peaddr, level - 1);
+}
+
+/**
+* Recursively free table at `level` and everything it points to.
+*/
+fn free_table<M: PhysMem>(m: &mut M, tbl: u64, level: u64) {
+	for i in 0..512 {
+		let entry = m.read(tbl + (i * 8));
+		if entry == 0 {
+			continue;
+		}
+		if level == 3 {
+			m.free(mmu_oa!(entry));
+		} else if ! entry_is_block(entry, level) {
+			let next = mmu_oa!(entry);
+			free_table(m, next, level + 1);
+			m.free(next);
+		}
+		// Blocks are only used for memory we don't own, like linear region
+	}
+}
+
+/**
+* Start keeping statistics for address space, returns -1 if there is no free
+* slot.
+*/
+fn space_register(ttbr: u64) -> i32 {
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &mut SPACES[i] };
+		if space.ttbr == 0 {
+			space.ttbr = ttbr;
+			space.pages = 0;
+			space.tables = 1;
+			space.quota = 0;
+			return 0;
+		}
+	}
+	log::info("No more room for address space statistics");
+	return -1;
+}
+
+/**
+* Check if address space can use `pages` more pages, either for data or tables.
+* Address spaces we don't keep statistics for have no limit.
+*/
+fn space_has_room(ttbr: u64, pages: u64) -> bool {
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &SPACES[i] };
+		if space.ttbr == ttbr {
+			return space.quota == 0 || space.pages + space.tables + pages <= space.quota;
 		}
-		return true
 	}
+	return true;
+}
 
-	return false;
+fn space_account(ttbr: u64, pages: i64, tables: i64) {
+	for i in 0..MAX_ADDR_SPACES {
+		let space = unsafe { &mut SPACES[i] };
+		if space.ttbr == ttbr {
+			space.pages = (space.pages as i64 + pages) as u64;
+			space.tables = (space.tables as i64 + tables) as u64;
+			return;
+		}
+	}
+}
+
+/**
+* Invalidate TLB entry for a single page. Pages in upper half are only
+* invalidated for the ASID used by `pud`.
+*/
+fn tlb_inval_page(pud: u64, vaddr: u64) {
+	let page = (vaddr >> 12) & ((1 << 44) - 1);
+	if inupper!(vaddr) {
+		let asid = asid_lookup(pud);
+
+		// No ASID means the address space hasn't been used since the last
+		// rollover and the TLB can't hold any of its entries
+		if asid == 0 { return; }
+
+		memory::dsb::ishst!();
+		memory::tlbi::vae1is!((asid << TTBR_OFFSET_ASID) | page);
+	} else {
+		memory::dsb::ishst!();
+		memory::tlbi::vaae1is!(page);
+	}
+	memory::dsb::ish!();
+	memory::isb!();
+}
+
+fn asid_lookup(ttbr: u64) -> u64 {
+	for i in 1..MAX_ASID {
+		if unsafe { ASIDS.owners[i] } == ttbr {
+			return i as u64;
+		}
+	}
+	return 0;
+}
+
+fn asid_alloc(ttbr: u64) -> u64 {
+	let asid = asid_lookup(ttbr);
+	if asid != 0 {
+		return asid;
+	}
+	for i in 1..MAX_ASID {
+		let owner = unsafe { &mut ASIDS.owners[i] };
+		if *owner == 0 {
+			*owner = ttbr;
+			return i as u64;
+		}
+	}
+	asid_rollover();
+	unsafe { ASIDS.owners[1] = ttbr; }
+	return 1;
+}
+
+/**
+* Release all ASIDs and flush the entire TLB. Address spaces are assigned new
+* ASIDs the next time they are switched in.
+*/
+fn asid_rollover() {
+	log::info("Ran out of ASIDs, flushing TLB");
+	unsafe {
+		for i in 0..MAX_ASID {
+			ASIDS.owners[i] = 0;
+		}
+	}
+	memory::dsb::ishst!();
+	memory::tlbi::vmalle1is!();
+	memory::dsb::ish!();
+	memory::isb!();
 }
 fn mmu_image_prot(map: &ImageMap, addr: u64, default: u64) -> u64 {
 	if addr >= map.text.start && addr <= map.text.stop {
@@ -494,11 +1353,442 @@ fn mmu_image_prot(map: &ImageMap, addr: u64, default: u64) -> u64 {
 	}
 }
 
-fn pages_available(pud: u64, vaddr: u64, pages: i32) -> i32 {
+fn pages_available<M: PhysMem>(m: &mut M, pud: u64, vaddr: u64, pages: i32) -> i32 {
 	for i in 0..pages {
-		if try_unmap_page(pud, vaddr + (i as u64 * PAGE_SIZE), false) {
+		let (eaddr, _level) = find_leaf(m, pud, vaddr + (i as u64 * PAGE_SIZE));
+		if eaddr != 0 {
 			return i;
 		}
 	}
 	return pages;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicU64, Ordering};
+	use std::sync::Mutex;
+
+	const RAM_BASE:  u64 = 0x40000000;
+	const RAM_PAGES: u64 = 64;
+
+	// Each FakeMem gets its own physical range, address space statistics are
+	// global and tests running in parallel must not share page directories
+	static NEXT_BASE: AtomicU64 = AtomicU64::new(RAM_BASE);
+
+	// Held by tests which change the slots in SPACES directly
+	static SPACES_LOCK: Mutex<()> = Mutex::new(());
+
+	// Applet VA and EL1 VA used in tests
+	const UVA: u64 = 0xffffffffffe00000;
+	const KVA: u64 = VA_RESERVED_START;
+
+	/**
+	* Physical memory backed by a buffer, pages are handed out from the start
+	* of the buffer.
+	*/
+	struct FakeMem {
+		base: u64,
+		ram: Vec<u8>,
+		used: Vec<bool>,
+		refs: Vec<u16>,
+		counters: Vec<u16>,
+		invalidations: u64,
+	}
+
+	impl FakeMem {
+		fn new() -> FakeMem {
+			FakeMem {
+				base: NEXT_BASE.fetch_add(RAM_PAGES * PAGE_SIZE, Ordering::Relaxed),
+				ram: vec![0; (RAM_PAGES * PAGE_SIZE) as usize],
+				used: vec![false; RAM_PAGES as usize],
+				refs: vec![0; RAM_PAGES as usize],
+				counters: vec![0; RAM_PAGES as usize],
+				invalidations: 0,
+			}
+		}
+		fn offset(&self, paddr: u64) -> usize {
+			assert!(paddr >= self.base && paddr < self.base + (RAM_PAGES * PAGE_SIZE));
+			return (paddr - self.base) as usize;
+		}
+		fn page(&self, paddr: u64) -> usize {
+			return self.offset(paddr) / PAGE_SIZE as usize;
+		}
+		fn used_pages(&self) -> usize {
+			return self.used.iter().filter(|x| **x).count();
+		}
+		fn bytes(&self, paddr: u64, len: usize) -> &[u8] {
+			let off = self.offset(paddr);
+			return &self.ram[off..off + len];
+		}
+		fn new_pgd(&mut self) -> u64 {
+			let pgd = self.alloc();
+			self.counter_set(pgd, 0);
+			return pgd;
+		}
+	}
+
+	impl PhysMem for FakeMem {
+		fn read(&self, paddr: u64) -> u64 {
+			let off = self.offset(paddr);
+			let mut buf = [0u8; 8];
+			buf.copy_from_slice(&self.ram[off..off + 8]);
+			return u64::from_le_bytes(buf);
+		}
+		fn write(&mut self, paddr: u64, val: u64) {
+			let off = self.offset(paddr);
+			self.ram[off..off + 8].copy_from_slice(&val.to_le_bytes());
+		}
+		fn alloc(&mut self) -> u64 {
+			for i in 0..RAM_PAGES as usize {
+				if ! self.used[i] {
+					self.used[i] = true;
+					self.refs[i] = 1;
+					let off = i * PAGE_SIZE as usize;
+					for b in &mut self.ram[off..off + PAGE_SIZE as usize] {
+						*b = 0;
+					}
+					return self.base + (i as u64 * PAGE_SIZE);
+				}
+			}
+			return u64::MAX;
+		}
+		fn free(&mut self, paddr: u64) {
+			let page = self.page(paddr);
+			assert!(self.used[page], "double free");
+			self.refs[page] -= 1;
+			self.used[page] = self.refs[page] != 0;
+		}
+		fn addref(&mut self, paddr: u64) {
+			let page = self.page(paddr);
+			assert!(self.used[page]);
+			self.refs[page] += 1;
+		}
+		fn counter_set(&mut self, paddr: u64, val: u16) {
+			let page = self.page(paddr);
+			self.counters[page] = val;
+		}
+		fn counter_add(&mut self, paddr: u64, val: i32) -> u16 {
+			let page = self.page(paddr);
+			self.counters[page] = (self.counters[page] as i32 + val) as u16;
+			return self.counters[page];
+		}
+		fn copy_to(&mut self, paddr: u64, src: u64, len: u64) {
+			let off = self.offset(paddr);
+			let data = unsafe { core::slice::from_raw_parts(src as *const u8, len as usize) };
+			self.ram[off..off + len as usize].copy_from_slice(data);
+		}
+		fn barrier(&mut self) { }
+		fn tlb_inval(&mut self, _pud: u64, _vaddr: u64) {
+			self.invalidations += 1;
+		}
+	}
+
+	#[test]
+	fn map_and_translate() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let p1 = m.alloc();
+		let p2 = m.alloc();
+
+		map_page(m, pud, UVA, p1, EL0_RW);
+		map_page(m, pud, KVA, p2, EL1_RW);
+
+		assert_eq!(vaddr_to_paddr(m, pud, UVA), p1);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA + 0x123), p1);
+		assert_eq!(vaddr_to_paddr(m, pud, KVA), p2);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA + PAGE_SIZE), u64::MAX);
+		assert_eq!(vaddr_to_paddr(m, pud, KVA - PAGE_SIZE), u64::MAX);
+
+		// Only applet pages are non-global
+		let (ueaddr, ulevel) = find_leaf(m, pud, UVA);
+		let (keaddr, klevel) = find_leaf(m, pud, KVA);
+		assert_eq!((ulevel, klevel), (3, 3));
+		assert!(m.read(ueaddr) & MMU_NG != 0);
+		assert!(m.read(keaddr) & MMU_NG == 0);
+	}
+
+	#[test]
+	fn memory_attributes() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		map_region(m, pud, KVA, 0x80000000, 0x80000000 + PAGE_SIZE, PROT_NS_SHARED);
+		map_region(m, pud, KVA + PAGE_SIZE, 0x9000000, 0x9000000 + PAGE_SIZE, EL1_RW | ATTR_DEVICE);
+		map_region(m, pud, KVA + (2 * PAGE_SIZE), 0x80001000, 0x80002000, EL1_RW);
+
+		let attr = |m: &mut FakeMem, vaddr: u64| {
+			let (eaddr, _level) = find_leaf(m, pud, vaddr);
+			return (m.read(eaddr) >> MMU_OFFSET_ATTR) & 0b111;
+		};
+		assert_eq!(attr(m, KVA), MAIR_IDX_NORMAL_NC);
+		assert_eq!(attr(m, KVA + PAGE_SIZE), MAIR_IDX_DEVICE);
+		assert_eq!(attr(m, KVA + (2 * PAGE_SIZE)), MAIR_IDX_NORMAL);
+		let (eaddr, _level) = find_leaf(m, pud, KVA);
+		assert!(m.read(eaddr) & MMU_NS != 0);
+
+		assert!(mapped_normal(m, pud, KVA + (2 * PAGE_SIZE)));
+		assert!(! mapped_normal(m, pud, KVA + PAGE_SIZE));
+		assert!(! mapped_normal(m, pud, KVA + (3 * PAGE_SIZE)));
+	}
+
+	#[test]
+	fn remap_frees_old_page() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let p1 = m.alloc();
+		let p2 = m.alloc();
+
+		map_page(m, pud, UVA, p1, EL0_RW);
+		let used = m.used_pages();
+		let inval = m.invalidations;
+
+		map_page(m, pud, UVA, p2, EL0_RO);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA), p2);
+		assert_eq!(m.used_pages(), used - 1);
+		assert!(! m.used[m.page(p1)]);
+		assert_eq!(m.invalidations, inval + 1);
+	}
+
+	#[test]
+	fn unmap_frees_page_and_tables() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+
+		assert_eq!(map_new_page(m, pud, UVA, EL0_RW), 0);
+		assert_eq!(map_new_page(m, pud, UVA + PAGE_SIZE, EL0_RW), 0);
+		assert!(m.used_pages() > 3);
+
+		assert_eq!(try_unmap_page(m, pud, UVA, true), 1);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA), u64::MAX);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA + PAGE_SIZE) == u64::MAX, false);
+
+		// Last page in table is gone, only page directory should be left
+		assert_eq!(try_unmap_page(m, pud, UVA + PAGE_SIZE, true), 1);
+		assert_eq!(try_unmap_page(m, pud, UVA + PAGE_SIZE, true), 0);
+		assert_eq!(m.used_pages(), 1);
+		assert_eq!(m.read(pud + (mmu_idx!(UVA, START_LEVEL) * 8)), 0);
+	}
+
+	#[test]
+	fn check_pages_available() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+
+		assert_eq!(pages_available(m, pud, UVA, 4), 4);
+		map_new_page(m, pud, UVA + (2 * PAGE_SIZE), EL0_RW);
+		assert_eq!(pages_available(m, pud, UVA, 4), 2);
+		assert_eq!(pages_available(m, pud, UVA + (3 * PAGE_SIZE), 1), 1);
+	}
+
+	#[test]
+	fn ensure_mapped_keeps_existing() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let p1 = m.alloc();
+
+		map_page(m, pud, UVA + PAGE_SIZE, p1, EL0_RO);
+		ensure_mapped_in(m, pud, UVA + 100, (2 * PAGE_SIZE) + 100, EL0_RO);
+
+		assert_eq!(pages_available(m, pud, UVA, 3), 0);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA + PAGE_SIZE), p1);
+		assert_eq!(pages_available(m, pud, UVA + (3 * PAGE_SIZE), 1), 1);
+	}
+
+	#[test]
+	fn memcpy_across_pages() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let src: Vec<u8> = (0..(2 * PAGE_SIZE) as usize).map(|x| (x % 251) as u8).collect();
+
+		// Start close to end of page so that we cover three pages
+		let vaddr = UVA + PAGE_SIZE - 10;
+		let len = src.len() as u64;
+		assert_eq!(_memcpy_ns(m, pud, vaddr, src.as_ptr() as u64, len), -1);
+		ensure_mapped_in(m, pud, vaddr, len, EL0_RO);
+		assert_eq!(_memcpy_ns(m, pud, vaddr, src.as_ptr() as u64, len), 0);
+
+		let mut off = 0;
+		while off < len {
+			let va = vaddr + off;
+			let inpage = PAGE_SIZE - (va % PAGE_SIZE);
+			let copy = if len - off < inpage { len - off } else { inpage };
+			let paddr = vaddr_to_paddr(m, pud, va) + (va % PAGE_SIZE);
+			assert_eq!(m.bytes(paddr, copy as usize), &src[off as usize..(off + copy) as usize]);
+			off += copy;
+		}
+	}
+
+	#[test]
+	fn memcpy_full_page() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let src = vec![0xa5u8; PAGE_SIZE as usize];
+
+		ensure_mapped_in(m, pud, UVA, PAGE_SIZE, EL0_RO);
+		assert_eq!(_memcpy_ns(m, pud, UVA, src.as_ptr() as u64, PAGE_SIZE), 0);
+
+		let paddr = vaddr_to_paddr(m, pud, UVA);
+		assert_eq!(m.bytes(paddr, PAGE_SIZE as usize), &src[..]);
+		assert_eq!(pages_available(m, pud, UVA + PAGE_SIZE, 1), 1);
+	}
+
+	#[test]
+	fn out_of_memory_and_quota() {
+		let _lock = SPACES_LOCK.lock().unwrap();
+		let m = &mut FakeMem::new();
+
+		let pud = m.new_pgd();
+		unsafe { SPACES[MAX_ADDR_SPACES - 1] = AddrSpace{ttbr: pud, pages: 0, tables: 1, quota: 8}; }
+
+		// Two tables are needed for 39-bit VA and three for 48-bit VA
+		let mut mapped = 0;
+		while map_new_page(m, pud, UVA + (mapped * PAGE_SIZE), EL0_RW) == 0 {
+			mapped += 1;
+		}
+		assert_eq!(mapped + (3 - START_LEVEL) + 1, 8);
+		assert_eq!(stats(pud), (mapped, (3 - START_LEVEL) + 1));
+		assert_eq!(m.used_pages() as u64, 1 + mapped + (3 - START_LEVEL));
+
+		// Room for one more page, but not for the table it needs
+		set_quota(pud, 9);
+		let used = m.used_pages();
+		assert_eq!(map_new_page(m, pud, UVA - level_size!(2), EL0_RW), -1);
+		assert_eq!(m.used_pages(), used);
+		assert_eq!(map_new_page(m, pud, UVA + (mapped * PAGE_SIZE), EL0_RW), 0);
+		mapped += 1;
+
+		// Without a quota, we run out of physical pages instead
+		set_quota(pud, 0);
+		while map_new_page(m, pud, UVA + (mapped * PAGE_SIZE), EL0_RW) == 0 {
+			mapped += 1;
+		}
+		assert_eq!(m.used_pages() as u64, RAM_PAGES);
+		assert_eq!(vaddr_to_paddr(m, pud, UVA + (mapped * PAGE_SIZE)), u64::MAX);
+		unsafe { SPACES[MAX_ADDR_SPACES - 1].ttbr = 0; }
+	}
+
+	#[test]
+	fn register_too_many_spaces() {
+		let _lock = SPACES_LOCK.lock().unwrap();
+
+		// Page directories are never accessed, so they don't need to be in RAM
+		let ttbr = |i: usize| 0x10000000 + (i as u64 * PAGE_SIZE);
+		for i in 0..MAX_ADDR_SPACES {
+			assert_eq!(space_register(ttbr(i)), 0);
+		}
+		assert_eq!(space_register(ttbr(MAX_ADDR_SPACES)), -1);
+		assert_eq!(stats(ttbr(MAX_ADDR_SPACES)), (u64::MAX, u64::MAX));
+		assert_eq!(set_quota(ttbr(MAX_ADDR_SPACES), 1), -1);
+		assert_eq!(stats(ttbr(0)), (0, 1));
+
+		for i in 0..MAX_ADDR_SPACES {
+			unsafe { SPACES[i] = AddrSpace::default(); }
+		}
+	}
+
+	#[test]
+	fn split_block_on_unmap() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+
+		// Physical address is never accessed, so it doesn't need to be in RAM
+		let bsize = level_size!(2);
+		let paddr = 0x80000000;
+		assert!(map_block(m, pud, KVA, paddr, 2, EL1_RW));
+		assert!(! map_block(m, pud, KVA, paddr, 2, EL1_RW));
+		assert_eq!(vaddr_to_paddr(m, pud, KVA + (5 * PAGE_SIZE) + 7), paddr + (5 * PAGE_SIZE));
+		assert_eq!(find_leaf(m, pud, KVA).1, 2);
+
+		assert_eq!(clear_page(m, pud, KVA + (5 * PAGE_SIZE)), Some(paddr + (5 * PAGE_SIZE)));
+		assert_eq!(find_leaf(m, pud, KVA).1, 3);
+		assert_eq!(vaddr_to_paddr(m, pud, KVA + (5 * PAGE_SIZE)), u64::MAX);
+		assert_eq!(vaddr_to_paddr(m, pud, KVA + (6 * PAGE_SIZE)), paddr + (6 * PAGE_SIZE));
+		assert_eq!(vaddr_to_paddr(m, pud, KVA + bsize - PAGE_SIZE), paddr + bsize - PAGE_SIZE);
+	}
+
+	#[test]
+	fn split_without_memory() {
+		let m = &mut FakeMem::new();
+		let pud = m.new_pgd();
+		let paddr = 0x80000000;
+		assert!(map_block(m, pud, KVA, paddr, 2, EL1_RW));
+		while m.alloc() != u64::MAX { }
+
+		// Block stays in place when the table for the split can't be allocated
+		assert_eq!(clear_page(m, pud, KVA + PAGE_SIZE), None);
+		assert_eq!(try_unmap_page(m, pud, KVA + PAGE_SIZE, true), -1);
+		assert_eq!(find_leaf(m, pud, KVA).1, 2);
+		assert_eq!(vaddr_to_paddr(m, pud, KVA + PAGE_SIZE), paddr + PAGE_SIZE);
+	}
+
+	#[test]
+	fn share_between_address_spaces() {
+		let m = &mut FakeMem::new();
+		let from = m.new_pgd();
+		let to = m.new_pgd();
+
+		assert_eq!(map_new_page(m, from, UVA, EL0_RW), 0);
+		assert_eq!(map_new_page(m, from, UVA + PAGE_SIZE, EL0_RO), 0);
+		let dst = UVA - (16 * PAGE_SIZE);
+
+		// Read-only page can't be shared writable
+		assert_eq!(share_region(m, from, UVA, to, dst, 2, EL0_RW), -1);
+		assert_eq!(vaddr_to_paddr(m, to, dst), u64::MAX);
+
+		assert_eq!(share_region(m, from, UVA, to, dst, 2, EL0_RO), 0);
+		assert_eq!(vaddr_to_paddr(m, to, dst + PAGE_SIZE), vaddr_to_paddr(m, from, UVA + PAGE_SIZE));
+		assert_eq!(share_region(m, from, UVA, to, dst, 1, EL0_RO), -1);
+
+		// Writes through one mapping are seen through the other
+		assert_eq!(write_el0(m, from, UVA + 8, &[42, 43]), 0);
+		assert_eq!(write_el0(m, to, dst + 8, &[1]), -1);
+		let mut out = [0u64; 2];
+		assert_eq!(read_el0(m, to, dst + 8, &mut out), 0);
+		assert_eq!(out, [42, 43]);
+		assert_eq!(read_el0(m, to, dst + 4, &mut out), -1);
+
+		// Pages are still owned by source
+		let used = m.used_pages();
+		let paddr = vaddr_to_paddr(m, from, UVA);
+		assert_eq!(m.refs[m.page(paddr)], 2);
+		unshare_region(m, to, dst, 2);
+		assert_eq!(vaddr_to_paddr(m, to, dst), u64::MAX);
+		assert!(vaddr_to_paddr(m, from, UVA) != u64::MAX);
+		assert!(m.used_pages() < used);
+		assert!(m.used[m.page(paddr)]);
+		assert_eq!(m.refs[m.page(paddr)], 1);
+	}
+
+	#[test]
+	fn unmap_shared_page() {
+		let m = &mut FakeMem::new();
+		let from = m.new_pgd();
+		let to = m.new_pgd();
+		assert_eq!(map_new_page(m, from, UVA, EL0_RW), 0);
+		let paddr = vaddr_to_paddr(m, from, UVA);
+		let dst = UVA - (16 * PAGE_SIZE);
+		assert_eq!(share_region(m, from, UVA, to, dst, 1, EL0_RW), 0);
+
+		// Unmapping like munmap in the callee only drops its reference
+		assert_eq!(try_unmap_page(m, to, dst, true), 1);
+		assert!(m.used[m.page(paddr)]);
+		assert_eq!(write_el0(m, from, UVA, &[42]), 0);
+
+		assert_eq!(try_unmap_page(m, from, UVA, true), 1);
+		assert!(! m.used[m.page(paddr)]);
+	}
+
+	#[test]
+	fn randomized_linear_offset() {
+		let ramend = 0x0f000000;
+		assert_eq!(linear_offset(0, ramend), START_LINEAR_REGION);
+		assert_eq!(linear_offset(1234, START_TEMP_REGION), START_LINEAR_REGION);
+		for seed in [1, 2, 0xdeadbeef, u64::MAX].iter() {
+			let off = linear_offset(*seed, ramend);
+			assert_eq!(off % LINEAR_ALIGN, 0);
+			assert!(off >= START_LINEAR_REGION);
+			assert!(off + ramend <= START_TEMP_REGION);
+		}
+		assert!(linear_offset(1, ramend) != linear_offset(2, ramend));
+	}
+}
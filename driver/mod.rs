@@ -1,7 +1,12 @@
 pub mod pl061;
+pub mod sp805;
 pub mod serial;
+pub mod pl011;
+#[cfg(debug_console)]
+pub mod console;
 pub mod gpio;
 pub mod gic;
 pub mod dtb;
 pub mod pmm;
 pub mod mmu;
+pub mod registry;
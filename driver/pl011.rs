@@ -0,0 +1,212 @@
+/**
+* Driver for ARM PL011 UART, used as console.
+*
+* Output is polled. Input is buffered by the RX interrupt handler if the UART
+* has an interrupt in the device tree and interrupts are enabled, otherwise
+* it's read directly from the FIFO.
+*
+* TRM: <https://developer.arm.com/documentation/ddi0183/latest>
+*/
+use lib::memory;
+use lib::log;
+use driver::dtb;
+#[cfg(debug_console)]
+use driver::gic;
+use cpu::interrupt;
+use platform;
+
+mod reg {
+	pub const DR:    u64 = 0x00;
+	pub const FR:    u64 = 0x18;
+	pub const IBRD:  u64 = 0x24;
+	pub const FBRD:  u64 = 0x28;
+	pub const LCR_H: u64 = 0x2c;
+	pub const CR:    u64 = 0x30;
+	pub const IMSC:  u64 = 0x38;
+	pub const ICR:   u64 = 0x44;
+}
+mod fr {
+	pub const BUSY: u32 = 1 << 3;
+	pub const RXFE: u32 = 1 << 4;
+	pub const TXFF: u32 = 1 << 5;
+}
+mod lcr {
+	pub const FEN:  u32 = 1 << 4;
+	pub const WLEN_8: u32 = 0b11 << 5;
+}
+mod cr {
+	pub const UARTEN: u32 = 1 << 0;
+	pub const TXE:    u32 = 1 << 8;
+	pub const RXE:    u32 = 1 << 9;
+}
+mod int {
+	pub const RX: u32 = 1 << 4;
+	pub const RT: u32 = 1 << 6;
+}
+
+const DEFAULT_BAUD: u32 = 115200;
+const RXBUF_SIZE: usize = 256;
+
+struct Pl011 {
+	base: u64,
+
+	/* Received data not yet read, only used with RX interrupt */
+	rxbuf: [u8; RXBUF_SIZE],
+	head: usize,
+	tail: usize,
+
+	/* Called after data has been received */
+	notify: Option<fn()>,
+}
+
+static mut UART: Pl011 = Pl011{
+	base: platform::DEFAULT.uart, rxbuf: [0; RXBUF_SIZE], head: 0, tail: 0, notify: None
+};
+
+fn read(off: u64) -> u32 {
+	return memory::dma::read::u32(unsafe { UART.base } + off);
+}
+fn write(off: u64, val: u32) {
+	memory::dma::write::u32(unsafe { UART.base } + off, val);
+}
+
+pub fn set_base(base: u64) {
+	unsafe { UART.base = base; }
+}
+pub fn base() -> u64 {
+	return unsafe { UART.base };
+}
+
+pub fn putc(c: u8) {
+	while (read(reg::FR) & fr::TXFF) != 0 { }
+	write(reg::DR, c as u32);
+}
+
+/**
+* Wait until all data has been sent.
+*/
+pub fn flush() {
+	while (read(reg::FR) & fr::BUSY) != 0 { }
+}
+
+pub fn rx_waiting() -> bool {
+	let uart = unsafe { &UART };
+	return uart.head != uart.tail || (read(reg::FR) & fr::RXFE) == 0;
+}
+
+/**
+* Get next received byte, None if nothing is waiting.
+*/
+pub fn getc() -> Option<u8> {
+	let uart = unsafe { &mut UART };
+	if uart.head != uart.tail {
+		let c = uart.rxbuf[uart.tail];
+		uart.tail = (uart.tail + 1) % RXBUF_SIZE;
+		return Some(c);
+	}
+	if (read(reg::FR) & fr::RXFE) == 0 {
+		return Some(read(reg::DR) as u8);
+	}
+	return None;
+}
+
+/**
+* Integer and fractional baud rate divisor. Divisor is clk / (16 * baud), the
+* fraction is in 1/64 and rounded.
+*/
+fn divisors(clk: u32, baud: u32) -> (u32, u32) {
+	let div = ((clk as u64 * 8) / baud as u64 + 1) / 2;
+	return ((div >> 6) as u32, (div & 0x3f) as u32);
+}
+
+/**
+* Set baud rate and use 8 data bits, no parity, one stop bit and FIFOs.
+*/
+pub fn configure(clk: u32, baud: u32) {
+	let (ibrd, fbrd) = divisors(clk, baud);
+	if ibrd == 0 || ibrd > 0xffff {
+		log::info("PL011 baud rate is out of range");
+		return;
+	}
+	flush();
+	write(reg::CR, 0);
+	write(reg::IBRD, ibrd);
+	write(reg::FBRD, fbrd);
+	write(reg::LCR_H, lcr::WLEN_8 | lcr::FEN);
+	write(reg::CR, cr::UARTEN | cr::TXE | cr::RXE);
+}
+
+fn irq_handler(_id: u32) {
+	let uart = unsafe { &mut UART };
+	while (read(reg::FR) & fr::RXFE) == 0 {
+		let c = read(reg::DR) as u8;
+		let next = (uart.head + 1) % RXBUF_SIZE;
+
+		// Drop data if buffer is full
+		if next != uart.tail {
+			uart.rxbuf[uart.head] = c;
+			uart.head = next;
+		}
+	}
+	write(reg::ICR, int::RX | int::RT);
+	if let Some(notify) = uart.notify {
+		notify();
+	}
+}
+
+/**
+* Receive data with interrupt `id`, `notify` is called when new data has
+* arrived.
+*/
+pub fn enable_rx_irq(id: u32, notify: Option<fn()>) -> i32 {
+	unsafe { UART.notify = notify; }
+	if interrupt::register_irq(id, irq_handler) < 0 {
+		return -1;
+	}
+	write(reg::ICR, int::RX | int::RT);
+	write(reg::IMSC, int::RX | int::RT);
+	return 0;
+}
+
+/**
+* Probe function used by driver registry. Only the UART used as console is
+* configured, other UARTs belong to normal world.
+*/
+pub fn probe(fdt: &dtb::Fdt, node: &dtb::Node) -> i32 {
+	let (base, _size) = match fdt.reg(node, 0) {
+		Some(r) => r,
+		None => { return -1; }
+	};
+	if base != self::base() {
+		return 0;
+	}
+
+	// Keep configuration from firmware if we don't know the clock
+	if let Some(clk) = fdt.clock_frequency(node) {
+		let baud = fdt.prop_u32(node, "current-speed").unwrap_or(DEFAULT_BAUD);
+		configure(clk, baud);
+	}
+
+	// Input is only used by debug console
+	#[cfg(debug_console)]
+	{
+		let irq = gic::dt_irq(fdt, node, 0);
+		if irq != u32::MAX && enable_rx_irq(irq, Some(::driver::console::input)) < 0 {
+			log::info("Unable to enable RX interrupt for console");
+		}
+	}
+	return 0;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn baud_divisors() {
+		// Examples from TRM
+		assert_eq!(divisors(4000000, 230400), (1, 5));
+		assert_eq!(divisors(24000000, 115200), (13, 1));
+		assert_eq!(divisors(24000000, 9600), (156, 16));
+	}
+}
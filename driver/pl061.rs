@@ -1,44 +1,104 @@
-use log;
+/**
+* Driver for ARM PL061 GPIO controllers.
+*
+* Controllers are registered from the device tree in the order they are found.
+* Only controllers reserved for secure world are used, those enabled for normal
+* world are left alone. See driver::gpio for numbering and ownership.
+*
+* TRM: <https://developer.arm.com/documentation/ddi0190/latest>
+*/
+use lib::log;
+use lib::memory;
+use driver::dtb;
+use driver::gic;
+use driver::gpio;
+use cpu::interrupt;
 
-const MAX_PL061: usize = 1;
+const MAX_PL061: usize = 4;
 const GPIOS_PER_DEV: u32 = 8;
-const OFFSET_DIR: u64 = 0x400;
+pub const MAX_GPIOS: u32 = MAX_PL061 as u32 * GPIOS_PER_DEV;
 
-static mut BASES: [u64; MAX_PL061] = [0; MAX_PL061];
+mod reg {
+	pub const DIR: u64 = 0x400;
+	/* Interrupt sense, 1 is level */
+	pub const IS:  u64 = 0x404;
+	/* Interrupt on both edges */
+	pub const IBE: u64 = 0x408;
+	/* Interrupt event, 1 is rising edge or high level */
+	pub const IEV: u64 = 0x40c;
+	pub const IE:  u64 = 0x410;
+	pub const MIS: u64 = 0x418;
+	pub const IC:  u64 = 0x41c;
+}
+
+type GpioHandler = fn(u32);
+
+#[derive(Clone, Copy)]
+struct Controller {
+	base: u64,
+
+	/* Interrupt ID in GIC, u32::MAX if controller has no interrupt */
+	irq: u32,
+	handlers: [Option<GpioHandler>; GPIOS_PER_DEV as usize],
+}
+
+static mut CONTROLLERS: [Controller; MAX_PL061] = [
+	Controller{base: 0, irq: u32::MAX, handlers: [None; GPIOS_PER_DEV as usize]}; MAX_PL061
+];
 
 fn get_gpio_vals(gpio: u32) -> (u64, u32) {
 	let idx = (gpio / GPIOS_PER_DEV) as usize;
 	let offset = gpio % GPIOS_PER_DEV;
 	if idx >= MAX_PL061	{ return (u64::MAX, 0) }
 
-	unsafe { return (BASES[idx], offset); }
+	let base = unsafe { CONTROLLERS[idx].base };
+	if base == 0 { return (u64::MAX, 0) }
+	return (base, offset);
+}
+
+/**
+* Base address and offset for pin the TEE is allowed to drive.
+*/
+fn get_owned_vals(gpio: u32) -> (u64, u32) {
+	if gpio::owner(gpio) == gpio::OWNER_NONE {
+		log::info("Tried to use GPIO not owned by secure world");
+		return (u64::MAX, 0);
+	}
+	return get_gpio_vals(gpio);
+}
+
+fn update_bit(addr: u64, offset: u32, set: bool) {
+	let mut data = memory::dma::read::u8(addr);
+	if set {
+		data |= 1 << offset;
+	}
+	else {
+		data &= !(1 << offset);
+	}
+	memory::dma::write::u8(addr, data);
 }
 
 pub mod set	{
 	use driver::gpio;
 	use lib::memory;
-	pub fn direction(gpio: u32, dir: u32)	{
-		let (base, offset) = super::get_gpio_vals(gpio);
-		if base == u64::MAX {
-			return;
-		}
 
-		let mut data = memory::dma::read::u8(base + super::OFFSET_DIR);
-	
-		if dir == gpio::DIR_OUT {
-			data |= 1 << offset
-		}
-		else	{
-			data &= !(1 << offset)
+	/**
+	* Returns -1 if pin doesn't exist or isn't owned by secure world.
+	*/
+	pub fn direction(gpio: u32, dir: u32) -> i32	{
+		let (base, offset) = super::get_owned_vals(gpio);
+		if base == u64::MAX {
+			return -1;
 		}
-		memory::dma::write::u8(base + super::OFFSET_DIR, data);
+		super::update_bit(base + super::reg::DIR, offset, dir == gpio::DIR_OUT);
+		return 0;
 	}
-	pub fn value(gpio: u32, val: u8)	{
-		let (base, offset) = super::get_gpio_vals(gpio);
+	pub fn value(gpio: u32, val: u8) -> i32	{
+		let (base, offset) = super::get_owned_vals(gpio);
 		if base == u64::MAX {
-			return;
+			return -1;
 		}
-	
+
 		let rval;
 		if val == gpio::HIGH {
 			rval = 1 << offset;
@@ -46,7 +106,9 @@ pub mod set	{
 		else {
 			rval = 0;
 		}
+		// Address bits [9:2] mask which pins are written
 		memory::dma::write::u8(base + (1 << (offset + 2)), rval);
+		return 0;
 	}
 }
 pub mod get {
@@ -58,7 +120,7 @@ pub mod get {
 			return u32::MAX;
 		}
 
-		let val = memory::dma::read::u8(base + super::OFFSET_DIR);
+		let val = memory::dma::read::u8(base + super::reg::DIR);
 
 		if val & (1 << offset) != 0 {
 			return gpio::DIR_OUT;
@@ -80,11 +142,135 @@ pub mod get {
 	}
 }
 
-pub fn register(idx: usize, base: u64)	{
-	if idx < MAX_PL061	{
-		unsafe { BASES[idx] = base; }
+/**
+* Call `handler` with the GPIO number when `trigger` happens on input pin.
+* Returns -1 if pin isn't owned by secure world, the controller has no
+* interrupt or trigger is invalid.
+*/
+pub fn request_irq(gpio: u32, trigger: u32, handler: GpioHandler) -> i32 {
+	let (base, offset) = get_owned_vals(gpio);
+	if base == u64::MAX {
+		return -1;
+	}
+	let ctrl = unsafe { &mut CONTROLLERS[(gpio / GPIOS_PER_DEV) as usize] };
+	if ctrl.irq == u32::MAX {
+		log::info("GPIO controller has no interrupt");
+		return -1;
+	}
+
+	// (level, both edges, rising or high)
+	let (is, ibe, iev) = match trigger {
+		gpio::EDGE_RISING  => (false, false, true),
+		gpio::EDGE_FALLING => (false, false, false),
+		gpio::EDGE_BOTH    => (false, true,  false),
+		gpio::LEVEL_HIGH   => (true,  false, true),
+		gpio::LEVEL_LOW    => (true,  false, false),
+		_ => { return -1; }
+	};
+
+	// Configure with interrupt masked so we don't get spurious events
+	update_bit(base + reg::IE, offset, false);
+	update_bit(base + reg::DIR, offset, false);
+	update_bit(base + reg::IS, offset, is);
+	update_bit(base + reg::IBE, offset, ibe);
+	update_bit(base + reg::IEV, offset, iev);
+	memory::dma::write::u8(base + reg::IC, 1 << offset);
+
+	ctrl.handlers[offset as usize] = Some(handler);
+	update_bit(base + reg::IE, offset, true);
+	return 0;
+}
+
+/**
+* Unmask interrupt from pin again after a level triggered event has been
+* handled, nothing is done if no handler is registered.
+*/
+pub fn unmask_irq(gpio: u32) {
+	let (base, offset) = get_owned_vals(gpio);
+	if base == u64::MAX {
+		return;
+	}
+	if unsafe { CONTROLLERS[(gpio / GPIOS_PER_DEV) as usize].handlers[offset as usize] }.is_some() {
+		update_bit(base + reg::IE, offset, true);
+	}
+}
+
+/**
+* Stop interrupts from pin.
+*/
+pub fn free_irq(gpio: u32) {
+	let (base, offset) = get_owned_vals(gpio);
+	if base == u64::MAX {
+		return;
 	}
-	else	{
-		log::info("Tried to register PL061 controller outside index bounds\n");
+	update_bit(base + reg::IE, offset, false);
+	unsafe { CONTROLLERS[(gpio / GPIOS_PER_DEV) as usize].handlers[offset as usize] = None; }
+}
+
+fn irq_handler(id: u32) {
+	for idx in 0..MAX_PL061 {
+		let ctrl = unsafe { CONTROLLERS[idx] };
+		if ctrl.base == 0 || ctrl.irq != id {
+			continue;
+		}
+		let pending = memory::dma::read::u8(ctrl.base + reg::MIS);
+		let level = memory::dma::read::u8(ctrl.base + reg::IS);
+		for offset in 0..GPIOS_PER_DEV {
+			if pending & (1 << offset) == 0 {
+				continue;
+			}
+			// Clearing doesn't deassert a level interrupt, so pin is masked
+			// until the event has been consumed, see unmask_irq
+			if level & (1 << offset) != 0 {
+				update_bit(ctrl.base + reg::IE, offset, false);
+			}
+			memory::dma::write::u8(ctrl.base + reg::IC, 1 << offset);
+			if let Some(handler) = ctrl.handlers[offset as usize] {
+				handler(idx as u32 * GPIOS_PER_DEV + offset);
+			}
+		}
+	}
+}
+
+/**
+* Probe function used by driver registry, controller is put in first free slot.
+*/
+pub fn probe(fdt: &dtb::Fdt, node: &dtb::Node) -> i32 {
+	// Controller is shared with or belongs to normal world
+	if fdt.is_enabled(node) {
+		return 0;
+	}
+	let (base, _size) = match fdt.reg(node, 0) {
+		Some(r) => r,
+		None => { return -1; }
+	};
+	let irq = gic::dt_irq(fdt, node, 0);
+	for idx in 0..MAX_PL061 {
+		if unsafe { CONTROLLERS[idx].base } == 0 {
+			register(idx, base, irq);
+			return 0;
+		}
+	}
+	log::info("No more room for PL061 controllers");
+	return -1;
+}
+
+/**
+* Register controller at `idx`, `irq` is u32::MAX if it has no interrupt.
+*/
+pub fn register(idx: usize, base: u64, irq: u32)	{
+	if idx >= MAX_PL061	{
+		log::info("Tried to register PL061 controller outside index bounds");
+		return;
+	}
+	unsafe {
+		CONTROLLERS[idx].base = base;
+		CONTROLLERS[idx].irq = irq;
+	}
+
+	// All interrupts are masked until requested
+	memory::dma::write::u8(base + reg::IE, 0);
+	if irq != u32::MAX && interrupt::register_irq(irq, irq_handler) < 0 {
+		unsafe { CONTROLLERS[idx].irq = u32::MAX; }
 	}
 }
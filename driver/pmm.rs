@@ -1,8 +1,24 @@
+/**
+* Physical Memory Manager (PMM)
+*
+* Secure memory is handed out with a buddy allocator. Free blocks of 2^order
+* pages are kept in one list per order and the links are stored in the free
+* pages themselves. Allocating and freeing is therefore O(log n) in the number
+* of pages. Buddies are computed relative to an address aligned to the largest
+* block, so a block of order N is always physically aligned to its size.
+*
+* Each page has an entry in a metadata array placed right after the image. The
+* entry holds a reference count, so that pages can be shared between address
+* spaces, and a counter which can be used by the owner of the page.
+*
+* Regions in the FDT memory reservation block and /reserved-memory nodes with
+* no-map are never handed out.
+*/
 use driver::dtb;
 use lib::math;
-use lib::memory;
 use lib::log;
 use driver::mmu;
+use platform;
 
 extern "C" {
 	fn memset(addr: u64, c: i8, size: u64);
@@ -10,152 +26,784 @@ extern "C" {
 
 const PHYS_PAGE_SIZE: u64 = 4096;
 
-// TODO: Don't change this w/o changing the algorithm below
-const BITS_PER_PAGE:  u64 = 1;
+// Largest block is 2^MAX_ORDER pages, 4MB with 4KB pages
+pub const MAX_ORDER: u64 = 10;
+
+const FLAG_FREE: u8 = 1 << 0;
+const FLAG_POISONED: u8 = 1 << 1;
+
+// Written to freed pages when built with pmm_poison
+const POISON: u8 = 0xa5;
+
+const MAX_CARVEOUTS: usize = 8;
+
+// Regions which are left out when free lists are built at boot
+const MAX_EXCLUDED: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct PageInfo {
+	/* Number of users of the page, 0 if page is free */
+	refs:    u16,
+
+	/* Can be used freely by owner of page */
+	counter: u16,
+
+	/* Order of block, only valid for first page in block */
+	order:   u8,
+	flags:   u8,
+	_pad:    u16,
+}
+
+/* Stored in the first page of every free block */
+#[repr(C)]
+struct FreeBlock {
+	next: u64,
+	prev: u64,
+}
 
 struct PmmData {
-	/* Address to a bitmap */
-	bitmap:   u64,
+	/* Address to array with one PageInfo per page */
+	meta:     u64,
 
-	/* Real start of memory */
+	/* First page we keep track of, aligned to largest block */
 	startmem: u64,
 
-	/* Number of real pages we keep track of */
+	/* Number of pages we keep track of */
 	pages:    u64,
-}
-static mut DATA: PmmData = PmmData{bitmap: 0, startmem: 0, pages: 0};
 
-macro_rules! pages_per_byte {
-	() => { (8 / BITS_PER_PAGE) };
+	/* Number of free pages */
+	free:     u64,
+
+	/* First block in free list for each order, u64::MAX if empty */
+	lists:    [u64; MAX_ORDER as usize + 1],
+}
+/* Region set aside at boot for a specific subsystem */
+#[derive(Clone, Copy)]
+struct Carveout {
+	name: &'static str,
+	addr: u64,
+	size: u64,
 }
+static mut CARVEOUTS: [Carveout; MAX_CARVEOUTS] = [
+	Carveout{name: "", addr: 0, size: 0}; MAX_CARVEOUTS
+];
+
+static mut DATA: PmmData = PmmData{
+	meta: 0, startmem: 0, pages: 0, free: 0, lists: [u64::MAX; MAX_ORDER as usize + 1]
+};
+
 macro_rules! addr_to_page {
 	($addr:expr) => {
 		($addr - _startmem()) / PHYS_PAGE_SIZE
 	};
 }
-macro_rules! get_offsets {
+macro_rules! page_to_addr {
 	($page:expr) => {
-		($page / (8 / BITS_PER_PAGE), $page / (8 / BITS_PER_PAGE))
+		_startmem() + ($page * PHYS_PAGE_SIZE)
 	};
 }
-fn _bitmap() -> u64 { return unsafe { DATA.bitmap}; }
 fn _startmem() -> u64 { return unsafe { DATA.startmem}; }
 fn _pages() -> u64 { return unsafe { DATA.pages}; }
 
+fn info(page: u64) -> &'static mut PageInfo {
+	let addr = unsafe { DATA.meta } + (page * core::mem::size_of::<PageInfo>() as u64);
+	return unsafe { &mut *(addr as *mut PageInfo) };
+}
+fn block(addr: u64) -> &'static mut FreeBlock {
+	return unsafe { &mut *(mmu::paddr2linear(addr) as *mut FreeBlock) };
+}
+fn tracked(addr: u64) -> bool {
+	return addr >= _startmem() && addr_to_page!(addr) < _pages();
+}
+
+fn list_add(page: u64, order: u64) {
+	let addr = page_to_addr!(page);
+	let head = unsafe { DATA.lists[order as usize] };
+	let b = block(addr);
+	b.next = head;
+	b.prev = u64::MAX;
+	if head != u64::MAX {
+		block(head).prev = addr;
+	}
+
+	let i = info(page);
+	i.order = order as u8;
+	i.flags |= FLAG_FREE;
+	i.refs = 0;
+	unsafe {
+		DATA.lists[order as usize] = addr;
+		DATA.free += 1 << order;
+	}
+}
+fn list_del(page: u64, order: u64) {
+	let b = block(page_to_addr!(page));
+	if b.prev != u64::MAX {
+		block(b.prev).next = b.next;
+	} else {
+		unsafe { DATA.lists[order as usize] = b.next; }
+	}
+	if b.next != u64::MAX {
+		block(b.next).prev = b.prev;
+	}
 
-fn _decref(byte: u64, offs: u64)	{
-	let bitmap = _bitmap();
-	let mut byteval = memory::dma::read::u8(bitmap + byte);
+	// Links must not be visible in page after it has been scrubbed
+	let fill = if (info(page).flags & FLAG_POISONED) != 0 { POISON } else { 0x00 };
+	unsafe { memset(mmu::paddr2linear(page_to_addr!(page)), fill as i8, core::mem::size_of::<FreeBlock>() as u64); }
+	info(page).flags &= !FLAG_FREE;
+	unsafe { DATA.free -= 1 << order; }
+}
+fn is_free_block(page: u64, order: u64) -> bool {
+	if page >= _pages() {
+		return false;
+	}
+	let i = info(page);
+	return (i.flags & FLAG_FREE) != 0 && i.order as u64 == order;
+}
 
-	// TODO: Will not work if more than 1 bits per page
-	byteval &= !(1 << offs);
-	memory::dma::write::u8(bitmap + byte, byteval);
+/**
+* Return block to free list, merging it with its buddy as long as possible.
+*/
+fn free_block(mut page: u64, mut order: u64) {
+	while order < MAX_ORDER {
+		let buddy = page ^ (1 << order);
+		if ! is_free_block(buddy, order) {
+			break;
+		}
+		list_del(buddy, order);
+		if buddy < page {
+			page = buddy;
+		}
+		order += 1;
+	}
+	list_add(page, order);
 }
 
-fn _addref(byte: u64, offs: u64)	{
-	let bitmap = _bitmap();
-	let mut byteval = memory::dma::read::u8(bitmap + byte);
+/**
+* Get free block of `order`, larger blocks are split if needed. Returns page
+* index or u64::MAX if no block is available.
+*/
+fn alloc_block(order: u64) -> u64 {
+	for o in order..(MAX_ORDER + 1) {
+		let head = unsafe { DATA.lists[o as usize] };
+		if head == u64::MAX {
+			continue;
+		}
+		let page = addr_to_page!(head);
+		list_del(page, o);
 
-	// TODO: Will not work if more than 1 bits per page
-	byteval |= 1 << offs;
-	memory::dma::write::u8(bitmap + byte, byteval);
+		// Return upper halves until we have the correct size
+		let mut cur = o;
+		while cur > order {
+			cur -= 1;
+			list_add(page + (1 << cur), cur);
+		}
+		let i = info(page);
+		i.order = order as u8;
+		i.refs = 1;
+		return page;
+	}
+	return u64::MAX;
 }
-fn find_free(bitmap: u64, pages: u64) -> i32 {
-	let bytes = pages / pages_per_byte!();
-	for i in 0..bytes {
-		let byteval = memory::dma::read::u8(bitmap + i);
-		if byteval != 0xff {
-			for j in 0..8 {
-				if ((1 << j) & byteval) == 0 {
-					memory::dma::write::u8(bitmap + i, byteval | (1 << j));
 
-					return ((i * 8) + j) as i32;
-				}
+/**
+* Remove single page from whichever free block it's part of. Returns false if
+* page isn't free.
+*/
+fn take_page(page: u64) -> bool {
+	for order in 0..(MAX_ORDER + 1) {
+		let head = page & !((1 << order) - 1);
+		if ! is_free_block(head, order) {
+			continue;
+		}
+		list_del(head, order);
+
+		// Split block and return all halves not containing page
+		let mut h = head;
+		let mut o = order;
+		while o > 0 {
+			o -= 1;
+			let half = h + (1 << o);
+			if page >= half {
+				list_add(h, o);
+				h = half;
+			} else {
+				list_add(half, o);
 			}
 		}
+		let i = info(page);
+		i.order = 0;
+		i.refs = 1;
+		return true;
 	}
-	return -1;
+	return false;
 }
 
-fn _init(startmem: u64, bitmap: u64, pages: u64) {
+/**
+* Add all pages in range to free lists, using the largest blocks possible.
+*/
+fn free_range(from: u64, to: u64) {
+	let mut page = addr_to_page!(math::align_pow2_up!(from, PHYS_PAGE_SIZE));
+	let end = addr_to_page!(math::align_pow2_down!(to, PHYS_PAGE_SIZE));
+	while page < end {
+		let mut order = MAX_ORDER;
+		while (page % (1 << order)) != 0 || page + (1 << order) > end {
+			order -= 1;
+		}
+		free_block(page, order);
+		page += 1 << order;
+	}
+}
+
+fn _init(startmem: u64, meta: u64, pages: u64) {
 	unsafe {
 		DATA.startmem = startmem;
-		DATA.bitmap = bitmap;
+		DATA.meta = meta;
 		DATA.pages = pages;
+		DATA.free = 0;
+		for i in 0..(MAX_ORDER + 1) {
+			DATA.lists[i as usize] = u64::MAX;
+		}
 	}
 
-	// Mark entire region as free
-	let bytes = pages / (8 / BITS_PER_PAGE);
-	for i in 0..bytes {
-		memory::dma::write::u8(bitmap + i as u64, 0);
+	// All pages are marked as used until they are freed
+	unsafe { memset(meta, 0x00, pages * core::mem::size_of::<PageInfo>() as u64); }
+}
+
+/**
+* Each page has a 16-bit counter which can be used by the current owner of the
+* page, MMU uses it to count number of entries in a table. Counter is not reset
+* on alloc or free, so owner must set it to a known value.
+*/
+pub fn counter(addr: u64) -> u16 {
+	return info(addr_to_page!(addr)).counter;
+}
+pub fn counter_set(addr: u64, val: u16) {
+	info(addr_to_page!(addr)).counter = val;
+}
+pub fn counter_add(addr: u64, val: i32) -> u16 {
+	let nval = (counter(addr) as i32 + val) as u16;
+	counter_set(addr, nval);
+	return nval;
+}
+
+/**
+* Add a reference to an allocated block, the block is not freed until `free`
+* has been called once for every reference.
+*/
+pub fn addref(addr: u64) {
+	if ! tracked(addr) {
+		log::bug("Tried to add reference to page not managed by pmm");
+		return;
 	}
+	info(addr_to_page!(addr)).refs += 1;
+}
+pub fn refs(addr: u64) -> u16 {
+	return info(addr_to_page!(addr)).refs;
 }
 
-fn addref(addr: u64)	{
-	let page = addr_to_page!(addr);
-	let (byte, offs) = get_offsets!(page);
-	_addref(byte, offs);
+/**
+* Remove data from a block which is about to be freed. By default the block is
+* zeroed, so that no data leaks between applets. With pmm_scrub="alloc" this
+* is instead done when the block is allocated. With pmm_poison, the block is
+* filled with a pattern which is verified on next allocation.
+*/
+fn scrub_on_free(page: u64, order: u64) {
+	if cfg!(pmm_poison) {
+		unsafe { memset(mmu::paddr2linear(page_to_addr!(page)), POISON as i8, PHYS_PAGE_SIZE << order); }
+		for p in page..(page + (1 << order)) {
+			info(p).flags |= FLAG_POISONED;
+		}
+	} else if ! cfg!(pmm_scrub = "alloc") {
+		unsafe { memset(mmu::paddr2linear(page_to_addr!(page)), 0x00, PHYS_PAGE_SIZE << order); }
+	}
+}
+
+/**
+* Counterpart of scrub_on_free, called on every allocated block.
+*/
+fn scrub_on_alloc(page: u64, order: u64) {
+	if cfg!(pmm_poison) {
+		for p in page..(page + (1 << order)) {
+			if (info(p).flags & FLAG_POISONED) == 0 {
+				continue;
+			}
+			info(p).flags &= !FLAG_POISONED;
+			let data = unsafe {
+				core::slice::from_raw_parts(mmu::paddr2linear(page_to_addr!(p)) as *const u8, PHYS_PAGE_SIZE as usize)
+			};
+			if data.iter().any(|b| *b != POISON) {
+				log::bug("Freed page has been written to");
+			}
+		}
+	}
+	if cfg!(pmm_poison) || cfg!(pmm_scrub = "alloc") {
+		unsafe { memset(mmu::paddr2linear(page_to_addr!(page)), 0x00, PHYS_PAGE_SIZE << order); }
+	}
 }
 
 pub fn free(addr: u64)	{
+	if ! tracked(addr) {
+		log::bug("Tried to free page not managed by pmm");
+		return;
+	}
 	let page = addr_to_page!(addr);
-	let (byte, offs) = get_offsets!(page);
-	_decref(byte, offs);
+	let i = info(page);
+
+	// Also catches pages in the middle of an allocated block
+	if i.refs == 0 {
+		log::bug("Double free or page is not allocated");
+		return;
+	}
+	i.refs -= 1;
+	if i.refs == 0 {
+		scrub_on_free(page, i.order as u64);
+		free_block(page, i.order as u64);
+	}
 }
 
+/**
+* Mark all pages in region as used, pages which are already in use are
+* ignored.
+*/
 pub fn mark(from: u64, to: u64)	{
 	let afrom = math::align_pow2_down!(from, PHYS_PAGE_SIZE);
 	let ato = math::align_pow2_up!(to, PHYS_PAGE_SIZE);
 	for addr in (afrom..ato).step_by(PHYS_PAGE_SIZE as usize) {
-		addref(addr);
+		if tracked(addr) {
+			take_page(addr_to_page!(addr));
+		}
 	}
 }
-pub fn alloc() -> u64 {
-	let idx = find_free(_bitmap(), _pages());
-	if idx < 0 {
+
+/**
+* Allocate 2^order physically contiguous pages, aligned to the size of the
+* block. Returns u64::MAX if there is no block available.
+*/
+pub fn alloc_order(order: u64) -> u64 {
+	if order > MAX_ORDER {
+		return u64::MAX;
+	}
+	let page = alloc_block(order);
+	if page == u64::MAX {
 		log::info("Unable find block");
 		return u64::MAX;
 	}
-	return _startmem() + (idx as u64 * PHYS_PAGE_SIZE);
+	scrub_on_alloc(page, order);
+	return page_to_addr!(page);
 }
-pub fn allocz() -> u64 {
-	let ret = alloc();
+pub fn allocz_order(order: u64) -> u64 {
+	let ret = alloc_order(order);
 	if ret != u64::MAX {
-		unsafe { memset(mmu::paddr2linear(ret), 0x00, PHYS_PAGE_SIZE); }
+		unsafe { memset(mmu::paddr2linear(ret), 0x00, PHYS_PAGE_SIZE << order); }
 	}
 	return ret;
 }
+pub fn alloc() -> u64 {
+	return alloc_order(0);
+}
+pub fn allocz() -> u64 {
+	return allocz_order(0);
+}
 
-pub fn init(imgstart: u64, imgend: u64) -> i32 {
-	let (ramaddr, size) = dtb::get_secure_memory();
-	let rstart = math::align_pow2_down!(imgstart, PHYS_PAGE_SIZE);
-	let rend = math::align_pow2_up!(imgend, PHYS_PAGE_SIZE);
+fn carveout_add(name: &'static str, addr: u64, size: u64) -> i32 {
+	for c in unsafe { CARVEOUTS.iter_mut() } {
+		if c.size == 0 {
+			c.name = name;
+			c.addr = addr;
+			c.size = size;
+			return 0;
+		}
+	}
+	log::info("No more space for carve-outs");
+	return -1;
+}
+
+/**
+* Allocate a contiguous region which is never freed and which other subsystems
+* can look up by name. Should only be called during boot. Returns u64::MAX on
+* failure.
+*/
+pub fn carve_out(name: &'static str, size: u64) -> u64 {
+	let npages = math::align_pow2_up!(size, PHYS_PAGE_SIZE) / PHYS_PAGE_SIZE;
+	let mut order = 0;
+	while (1 << order) < npages {
+		order += 1;
+	}
+	let addr = alloc_order(order);
+	if addr == u64::MAX {
+		return u64::MAX;
+	}
+
+	// Make every page a separate allocation and return the tail we don't need
+	let page = addr_to_page!(addr);
+	for p in page..(page + (1 << order)) {
+		let i = info(p);
+		i.order = 0;
+		i.refs = 1;
+	}
+	for p in (page + npages)..(page + (1 << order)) {
+		free(page_to_addr!(p));
+	}
+
+	if carveout_add(name, addr, npages * PHYS_PAGE_SIZE) < 0 {
+		for p in page..(page + npages) {
+			free(page_to_addr!(p));
+		}
+		return u64::MAX;
+	}
+	return addr;
+}
+
+/**
+* Record a region at a fixed address as a carve-out, pages inside secure memory
+* are marked as used.
+*/
+pub fn carve_out_at(name: &'static str, addr: u64, size: u64) -> i32 {
+	if carveout_add(name, addr, size) < 0 {
+		return -1;
+	}
+	mark(addr, addr + size);
+	return 0;
+}
+
+/**
+* Find carve-out by name, returns (u64::MAX, u64::MAX) if not found.
+*/
+pub fn carveout(name: &str) -> (u64, u64) {
+	for c in unsafe { CARVEOUTS.iter() } {
+		if c.size != 0 && c.name == name {
+			return (c.addr, c.size);
+		}
+	}
+	return (u64::MAX, u64::MAX);
+}
+
+/**
+* Get (pages in total, free pages)
+*/
+pub fn stats() -> (u64, u64) {
+	return unsafe { (DATA.pages, DATA.free) };
+}
+
+/**
+* Zero data in all free pages, only the free list links are kept. Used before
+* power down when pages are scrubbed on allocation instead of on free.
+*/
+pub fn scrub_free() {
+	if ! cfg!(pmm_scrub = "alloc") || cfg!(pmm_poison) {
+		return;
+	}
+	let hdr = core::mem::size_of::<FreeBlock>() as u64;
+	for order in 0..=MAX_ORDER {
+		let mut addr = unsafe { DATA.lists[order as usize] };
+		while addr != u64::MAX {
+			let next = block(addr).next;
+			unsafe { memset(mmu::paddr2linear(addr) + hdr, 0x00, (PHYS_PAGE_SIZE << order) - hdr); }
+			addr = next;
+		}
+	}
+}
 
-	_init(ramaddr, rend, size / PHYS_PAGE_SIZE);
+/**
+* Region `idx` reserved by firmware, memreserve entries come before
+* reserved-memory nodes with no-map. Returns (u64::MAX, u64::MAX) after the last
+* region.
+*/
+fn firmware_reserved(idx: usize) -> (u64, u64) {
+	let mut count = 0;
+	while dtb::memreserve(count).0 != u64::MAX {
+		count += 1;
+	}
+	if idx < count {
+		return dtb::memreserve(idx);
+	}
+	return dtb::reserved_nomap(idx - count);
+}
 
-	// Mark first page as taken
-	addref(ramaddr);
+/**
+* Find `bytes` of page aligned memory in [from, to) which doesn't overlap any
+* region returned by `reserved`. Returns u64::MAX if there is none.
+*/
+fn find_unreserved<F: Fn(usize) -> (u64, u64)>(from: u64, to: u64, bytes: u64, reserved: F) -> u64 {
+	let mut start = math::align_pow2_up!(from, PHYS_PAGE_SIZE);
+	'retry: while start + bytes <= to {
+		let mut i = 0;
+		loop {
+			let (addr, size) = reserved(i);
+			if addr == u64::MAX {
+				return start;
+			}
+			let end = addr.saturating_add(size);
+			if addr < start + bytes && end > start {
+				start = math::align_pow2_up!(end, PHYS_PAGE_SIZE);
+				continue 'retry;
+			}
+			i += 1;
+		}
+	}
+	return u64::MAX;
+}
+
+/**
+* Put all pages in secure memory on the free lists, except the first page, the
+* image, metadata and every region returned by `reserved`. Excluded regions are
+* sorted and only the gaps between them are freed, so pages in them are never
+* written to.
+*/
+fn free_unreserved<F: Fn(usize) -> (u64, u64)>(ramaddr: u64, size: u64, fixed: &[(u64, u64)], reserved: F) {
+	let mut excluded = [(0u64, 0u64); MAX_EXCLUDED];
+	let mut count = 0;
+	excluded[count] = (ramaddr, ramaddr + PHYS_PAGE_SIZE);
+	count += 1;
+	for r in fixed {
+		assert!(count < MAX_EXCLUDED);
+		excluded[count] = *r;
+		count += 1;
+	}
+	let mut i = 0;
+	loop {
+		let (addr, len) = reserved(i);
+		if addr == u64::MAX {
+			break;
+		}
+		assert!(count < MAX_EXCLUDED);
+		excluded[count] = (addr, addr.saturating_add(len));
+		count += 1;
+		i += 1;
+	}
+	excluded[..count].sort_unstable();
 
-	// Mark image region as taken
-	for addr in (rstart..rend).step_by(PHYS_PAGE_SIZE as usize) {
-		addref(addr);
+	// Regions may overlap and may be outside secure memory, free_range rounds
+	// inwards so partially reserved pages are kept as well
+	let end = ramaddr + size;
+	let mut start = ramaddr;
+	for &(rstart, rend) in &excluded[..count] {
+		if rstart > start && start < end {
+			free_range(start, core::cmp::min(rstart, end));
+		}
+		start = core::cmp::max(start, rend);
+	}
+	if start < end {
+		free_range(start, end);
 	}
+}
 
-	// Mark bitmap as taken
-	let bmbytes = math::align_pow2_up!(
-		(size / PHYS_PAGE_SIZE) / (8 / BITS_PER_PAGE),
+pub fn init(imgstart: u64, imgend: u64) -> i32 {
+	let (ramaddr, size) = platform::secure_memory();
+	let rstart = math::align_pow2_down!(imgstart, PHYS_PAGE_SIZE);
+	let rend = math::align_pow2_up!(imgend, PHYS_PAGE_SIZE);
+
+	let base = math::align_pow2_down!(ramaddr, PHYS_PAGE_SIZE << MAX_ORDER);
+	let pages = (ramaddr + size - base) / PHYS_PAGE_SIZE;
+
+	// Metadata is placed after image if there is room, it must not overlap any
+	// region firmware has reserved since it is written to
+	let metabytes = math::align_pow2_up!(
+		pages * core::mem::size_of::<PageInfo>() as u64,
 		PHYS_PAGE_SIZE
 	);
-	let bmpages = bmbytes / PHYS_PAGE_SIZE;
-	let bitmap = _bitmap();
-	for i in 0..bmpages {
-		addref(bitmap + (i * PHYS_PAGE_SIZE) as u64);
+	let mut meta = find_unreserved(rend, ramaddr + size, metabytes, firmware_reserved);
+	if meta == u64::MAX {
+		meta = find_unreserved(ramaddr + PHYS_PAGE_SIZE, rstart, metabytes, firmware_reserved);
 	}
+	assert!(meta != u64::MAX);
+	_init(base, meta, pages);
+
+	// First page, image, metadata and regions firmware has reserved are never
+	// freed
+	free_unreserved(ramaddr, size, &[(rstart, rend), (meta, meta + metabytes)], firmware_reserved);
 	return 0;
 }
 pub fn lateinit(linear: u64) -> i32 {
 	unsafe {
-		DATA.bitmap += linear;
+		DATA.meta += linear;
 	}
 	return 0;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+
+	// pmm is global, so tests can't run in parallel
+	static LOCK: Mutex<()> = Mutex::new(());
+
+	const PAGES: u64 = 2 << MAX_ORDER;
+
+	/**
+	* Set up pmm with memory from host, returns memory buffers which must be
+	* kept alive during the test.
+	*/
+	fn setup() -> (Vec<u8>, Vec<PageInfo>) {
+		let blocksize = (PHYS_PAGE_SIZE << MAX_ORDER) as usize;
+		let ram = vec![0u8; (PAGES * PHYS_PAGE_SIZE) as usize + blocksize];
+		let meta = vec![PageInfo::default(); PAGES as usize];
+		let base = math::align_pow2_up!(ram.as_ptr() as u64, blocksize as u64);
+		_init(base, meta.as_ptr() as u64, PAGES);
+		free_range(base, base + (PAGES * PHYS_PAGE_SIZE));
+		return (ram, meta);
+	}
+
+	#[test]
+	fn alloc_and_merge() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+		assert_eq!(stats(), (PAGES, PAGES));
+
+		let a = alloc();
+		let b = alloc();
+		assert!(a != b);
+		assert_eq!(stats().1, PAGES - 2);
+
+		free(a);
+		free(b);
+		assert_eq!(stats().1, PAGES);
+
+		// Everything should have merged back into largest blocks
+		let c = alloc_order(MAX_ORDER);
+		let d = alloc_order(MAX_ORDER);
+		assert!(c != u64::MAX && d != u64::MAX);
+		assert_eq!(stats().1, 0);
+		free(c);
+		free(d);
+	}
+
+	#[test]
+	fn contiguous_is_aligned() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+
+		let single = alloc();
+		for order in 1..(MAX_ORDER + 1) {
+			let addr = allocz_order(order);
+			assert!(addr != u64::MAX);
+			assert_eq!((addr - _startmem()) % (PHYS_PAGE_SIZE << order), 0);
+			free(addr);
+		}
+		free(single);
+		assert_eq!(stats().1, PAGES);
+	}
+
+	#[test]
+	fn shared_page_refs() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+
+		let a = alloc();
+		addref(a);
+		assert_eq!(refs(a), 2);
+		free(a);
+		assert_eq!(stats().1, PAGES - 1);
+		free(a);
+		assert_eq!(stats().1, PAGES);
+	}
+
+	#[test]
+	fn marked_pages_not_allocated() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+		let marked = _startmem() + (5 * PHYS_PAGE_SIZE);
+
+		mark(marked, marked + PHYS_PAGE_SIZE);
+		assert_eq!(stats().1, PAGES - 1);
+
+		for _i in 0..(PAGES - 1) {
+			assert!(alloc() != marked);
+		}
+		assert_eq!(stats().1, 0);
+	}
+
+	#[test]
+	fn metadata_avoids_reserved() {
+		let pg = PHYS_PAGE_SIZE;
+		let none = |_i: usize| (u64::MAX, u64::MAX);
+		assert_eq!(find_unreserved(0x1000, 0x10000, 2 * pg, none), 0x1000);
+
+		// Reserved regions in unsorted order, second one isn't page aligned
+		let resv = [(0x4000, 0x1000), (0x1800, 0x10), (0x7000, 0x9000)];
+		let lookup = |i: usize| if i < resv.len() { resv[i] } else { (u64::MAX, u64::MAX) };
+		assert_eq!(find_unreserved(0x1000, 0x10000, pg, lookup), 0x2000);
+		assert_eq!(find_unreserved(0x1000, 0x10000, 2 * pg, lookup), 0x2000);
+		assert_eq!(find_unreserved(0x3000, 0x10000, 2 * pg, lookup), 0x5000);
+		assert_eq!(find_unreserved(0x1000, 0x10000, 3 * pg, lookup), u64::MAX);
+	}
+
+	#[test]
+	fn reserved_memory_untouched() {
+		let _lock = LOCK.lock().unwrap();
+		let blocksize = (PHYS_PAGE_SIZE << MAX_ORDER) as usize;
+		let mut ram = vec![0u8; (PAGES * PHYS_PAGE_SIZE) as usize + blocksize];
+		let meta = vec![PageInfo::default(); PAGES as usize];
+		let base = math::align_pow2_up!(ram.as_ptr() as u64, blocksize as u64);
+		let off = (base - ram.as_ptr() as u64) as usize;
+		let pg = PHYS_PAGE_SIZE;
+		_init(base, meta.as_ptr() as u64, PAGES);
+
+		// Unsorted, overlapping, unaligned and outside memory
+		let resv = [(base + (40 * pg), 3 * pg), (base + (9 * pg) + 8, 16), (base + (41 * pg), 4 * pg),
+			(base + (PAGES * pg), pg), (base - pg, 3 * pg)];
+		let lookup = |i: usize| if i < resv.len() { resv[i] } else { (u64::MAX, u64::MAX) };
+		let pattern = |p: u64| p == 0 || p == 1 || p == 9 || (p >= 40 && p < 45) || p == 100;
+		for p in 0..PAGES {
+			if pattern(p) {
+				let s = off + (p * pg) as usize;
+				for b in &mut ram[s..s + pg as usize] { *b = 0xee; }
+			}
+		}
+
+		free_unreserved(base, PAGES * pg, &[(base + (100 * pg), base + (101 * pg))], lookup);
+		let excluded = (0..PAGES).filter(|p| pattern(*p)).count() as u64;
+		assert_eq!(stats().1, PAGES - excluded);
+
+		for p in 0..PAGES {
+			if pattern(p) {
+				let s = off + (p * pg) as usize;
+				assert!(ram[s..s + pg as usize].iter().all(|b| *b == 0xee));
+			}
+		}
+		for _i in 0..(PAGES - excluded) {
+			let a = alloc();
+			assert!(a != u64::MAX && ! pattern(addr_to_page!(a)));
+		}
+		assert_eq!(alloc(), u64::MAX);
+	}
+
+	#[test]
+	fn free_scrubs_and_refuses_double_free() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+
+		let a = alloc();
+		unsafe { memset(a, 0x41, PHYS_PAGE_SIZE); }
+		free(a);
+		free(a);
+		assert_eq!(stats().1, PAGES);
+
+		// Page in the middle of an allocated block
+		let b = alloc_order(2);
+		free(b + PHYS_PAGE_SIZE);
+		assert_eq!(stats().1, PAGES - 4);
+		free(b);
+
+		let c = alloc();
+		assert_eq!(c, a);
+		let data = unsafe { core::slice::from_raw_parts(c as *const u8, PHYS_PAGE_SIZE as usize) };
+		assert!(data.iter().all(|b| *b == 0));
+		free(c);
+	}
+
+	#[test]
+	fn carve_out_trims_block() {
+		let _lock = LOCK.lock().unwrap();
+		let _mem = setup();
+
+		let addr = carve_out("test", 5 * PHYS_PAGE_SIZE);
+		assert!(addr != u64::MAX);
+		assert_eq!(carveout("test"), (addr, 5 * PHYS_PAGE_SIZE));
+		assert_eq!(stats().1, PAGES - 5);
+		assert_eq!(carveout("missing"), (u64::MAX, u64::MAX));
+		unsafe { CARVEOUTS[0].size = 0; }
+	}
+}
@@ -0,0 +1,80 @@
+/**
+* Registry of drivers which are probed from the device tree.
+*
+* Each driver lists the compatible strings it supports. At boot, every node
+* with secure-status = "okay" is matched against the drivers, the MMIO
+* regions in reg are mapped and probe is called with the node. New drivers are
+* added to DRIVERS below.
+*/
+use driver::dtb;
+use driver::mmu;
+use driver::pl061;
+use driver::pl011;
+use driver::sp805;
+use lib::log;
+
+pub struct Driver {
+	pub name: &'static str,
+
+	/* Node matches if any of its compatible strings is in this list */
+	pub compatible: &'static [&'static str],
+
+	/* Called with matching node after MMIO is mapped, returns 0 on success */
+	pub probe: fn(&dtb::Fdt, &dtb::Node) -> i32,
+}
+
+static DRIVERS: [Driver; 3] = [
+	Driver{name: "pl061", compatible: &["arm,pl061"], probe: pl061::probe},
+	Driver{name: "pl011", compatible: &["arm,pl011"], probe: pl011::probe},
+	Driver{name: "sp805", compatible: &["arm,sp805"], probe: sp805::probe},
+];
+
+/**
+* Find driver for node, None if no driver supports it.
+*/
+pub fn find_driver(fdt: &dtb::Fdt, node: &dtb::Node) -> Option<&'static Driver> {
+	for compat in fdt.prop_strings(node, "compatible") {
+		for drv in DRIVERS.iter() {
+			if drv.compatible.contains(&compat) {
+				return Some(drv);
+			}
+		}
+	}
+	return None;
+}
+
+/**
+* Probe all devices in the device tree which we have a driver for. Must be
+* called after virtual memory is initialized. Returns number of devices
+* successfully probed.
+*/
+pub fn probe_all() -> usize {
+	let fdt = match dtb::fdt() {
+		Some(f) => f,
+		None => { return 0; }
+	};
+	let mut count = 0;
+	for node in fdt.nodes() {
+		if ! fdt.is_secure_assigned(&node) {
+			continue;
+		}
+		let drv = match find_driver(&fdt, &node) {
+			Some(d) => d,
+			None => { continue; }
+		};
+		for i in 0..fdt.reg_count(&node) {
+			if let Some((addr, size)) = fdt.reg(&node, i) {
+				if size > 0 {
+					mmu::map_dma(addr, addr + size);
+				}
+			}
+		}
+		if (drv.probe)(&fdt, &node) == 0 {
+			count += 1;
+		} else {
+			log::info("Probe failed for driver:");
+			log::info(drv.name);
+		}
+	}
+	return count;
+}
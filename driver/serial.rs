@@ -1,18 +1,24 @@
-use lib::memory;
-use platform;
-
-static mut UARTBASE: u64 = platform::uart::BASE0;
+/**
+* Console output, backed by the PL011 driver.
+*/
+use driver::pl011;
 
 pub fn putc(c: char) {
-	memory::dma::write::u32(unsafe {UARTBASE}, c as u32);
+	let mut buf = [0u8; 4];
+	for b in c.encode_utf8(&mut buf).bytes() {
+		if b == b'\n' { pl011::putc(b'\r'); }
+		pl011::putc(b);
+	}
 }
 pub fn write(buf: &str) {
 	for c in buf.chars() { putc(c); }
 }
 pub fn rx_waiting() -> bool {
-	let val = memory::dma::read::u32(unsafe {UARTBASE});
-	return (val & (1 << 4)) == 0;
+	return pl011::rx_waiting();
+}
+pub fn getc() -> Option<u8> {
+	return pl011::getc();
 }
 pub fn set_base(base: u64) {
-	unsafe { UARTBASE = base; }
+	pl011::set_base(base);
 }
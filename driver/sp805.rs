@@ -0,0 +1,119 @@
+/**
+* Driver for ARM SP805 watchdog.
+*
+* The counter decrements at the watchdog clock and raises an interrupt when it
+* reaches zero, it's then reloaded. If the interrupt hasn't been cleared the
+* next time it reaches zero, the system is reset. So the system is reset
+* `2 * LOAD` clock cycles after the last kick.
+*
+* The watchdog is kicked from the timer tick once every online core has
+* checked in, so a core hanging with interrupts masked stops the kicks and
+* eventually resets the system.
+*
+* TRM: <https://developer.arm.com/documentation/ddi0270/latest>
+*/
+use lib::log;
+use lib::memory;
+use driver::dtb;
+
+mod reg {
+	pub const LOAD:    u64 = 0x000;
+	pub const CONTROL: u64 = 0x008;
+	pub const INTCLR:  u64 = 0x00c;
+	pub const LOCK:    u64 = 0xc00;
+}
+mod control {
+	pub const INTEN: u32 = 1 << 0;
+	pub const RESEN: u32 = 1 << 1;
+}
+const UNLOCK: u32 = 0x1acce551;
+
+/* Used if timeout-sec isn't in device tree */
+const DEFAULT_TIMEOUT_SEC: u32 = 10;
+
+static mut BASE: u64 = 0;
+
+fn write(off: u64, val: u32) {
+	memory::dma::write::u32(unsafe { BASE } + off, val);
+}
+
+/**
+* Value to load for timeout, each load is half of the timeout.
+*/
+fn load_value(clk: u32, timeout_sec: u32) -> u32 {
+	let load = (clk as u64 * timeout_sec as u64) / 2;
+	if load > u32::MAX as u64 {
+		return u32::MAX;
+	}
+	return core::cmp::max(load, 1) as u32;
+}
+
+/**
+* Restart countdown, does nothing if there is no watchdog.
+*/
+pub fn kick() {
+	if unsafe { BASE } == 0 {
+		return;
+	}
+	write(reg::LOCK, UNLOCK);
+	write(reg::INTCLR, 1);
+	write(reg::LOCK, 0);
+}
+
+/**
+* Stop watchdog, used before a controlled power down.
+*/
+pub fn stop() {
+	if unsafe { BASE } == 0 {
+		return;
+	}
+	write(reg::LOCK, UNLOCK);
+	write(reg::CONTROL, 0);
+	write(reg::LOCK, 0);
+}
+
+/**
+* Probe function used by driver registry. A watchdog which is available to
+* normal world is left alone.
+*/
+pub fn probe(fdt: &dtb::Fdt, node: &dtb::Node) -> i32 {
+	if fdt.is_enabled(node) {
+		return 0;
+	}
+	if unsafe { BASE } != 0 {
+		log::info("Only one SP805 watchdog is supported");
+		return -1;
+	}
+	let (base, _size) = match fdt.reg(node, 0) {
+		Some(r) => r,
+		None => { return -1; }
+	};
+	let clk = match fdt.clock_frequency(node) {
+		Some(c) => c,
+		None => {
+			log::info("Unknown clock for SP805");
+			return -1;
+		}
+	};
+	let timeout = fdt.prop_u32(node, "timeout-sec").unwrap_or(DEFAULT_TIMEOUT_SEC);
+
+	unsafe { BASE = base; }
+	write(reg::LOCK, UNLOCK);
+	write(reg::LOAD, load_value(clk, timeout));
+	write(reg::INTCLR, 1);
+	write(reg::CONTROL, control::INTEN | control::RESEN);
+	write(reg::LOCK, 0);
+	return 0;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn timeout_to_load() {
+		assert_eq!(load_value(1000000, 10), 5000000);
+		assert_eq!(load_value(u32::MAX, 10), u32::MAX);
+		assert_eq!(load_value(1, 1), 1);
+	}
+}
@@ -1,109 +1,645 @@
+/**
+* Heaps for applets in EL0 and the kernel in EL1.
+*
+* Applets have no writable global variables, so all state is stored at the
+* start of the heap region, which is at a fixed VA in the applet's address
+* space. The heap grows upwards with SYSNO_MMAP and pages at the end of the
+* heap are returned with SYSNO_MUNMAP when enough memory has been freed.
+*
+* The kernel heap uses the same code, but is placed in the lower half and gets
+* its pages directly from pmm. The global allocator picks the heap based on
+* which half the stack is in, since CurrentEL can't be read from EL0.
+*
+* Memory is divided into chunks, each with a 16 byte header holding the size of
+* the previous chunk and the size of this chunk. The lowest bit of the size is
+* set when the chunk is in use. Free chunks are kept in a doubly linked list
+* stored in the chunk data and neighbouring free chunks are always merged.
+*/
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use lib::math;
-use lib::bitmap;
 use lib::memory;
+use lib::sizes;
 use driver::mmu;
 use applets;
 use cpu;
+use cpu::register;
 use applets::arch_svc;
 
-
 extern "C" {
-	fn memset(addr: u64, c: i8, size: u64);
+	fn memcpy(dst: u64, src: u64, size: u64);
 }
 
-pub struct Allocator { }
+// Heap can use everything in this range, stack is located above
+const HEAP_START: u64 = mmu::maxmem_upper!() - sizes::GB;
+const HEAP_LIMIT: u64 = mmu::maxmem_upper!() - sizes::MB;
 
-const MEMORY_REGION: u64 = mmu::maxmem_upper!() - (mmu::PAGE_SIZE * 2);
-const MALLOC_REGION: u64 = mmu::maxmem_upper!() - (mmu::PAGE_SIZE * 256);
+const HDR_SIZE:   u64 = 16;
+const MIN_ALIGN:  u64 = 16;
 
-const BLOCK_INC:  u64 = 4;
-const BLOCK_SIZE: u32 = 16;
+// Header + next and prev pointer in free list
+const MIN_CHUNK:  u64 = 32;
 
-const MAX_PAGES:  u64 = 128;
+const CHUNK_USED: u64 = 1;
 
+// Don't return memory unless this much can be unmapped at the end
+const TRIM_SIZE:  u64 = mmu::PAGE_SIZE * 4;
 
-impl Allocator {
-	pub const fn empty() -> Allocator {
-		Allocator { }
-    }
-	pub fn init(&self) -> i32 {
-		// Allocate region to store bitmap
-		let meta = applets::svc!(cpu::svc::SYSNO_MMAP, MEMORY_REGION, mmu::PAGE_SIZE, mmu::EL0_RW);
-		assert!(meta == MEMORY_REGION);
+// HeapState is placed at the start of the heap, first chunk follows
+const STATE_SIZE: u64 = 32;
+const STATE_END:  u64 = 0;
+const STATE_FREE: u64 = 8;
+const STATE_LAST: u64 = 16;
 
-		// Allocate memory for data region
-		let addr = applets::svc!(
-			cpu::svc::SYSNO_MMAP, MALLOC_REGION, BLOCK_INC * mmu::PAGE_SIZE, mmu::EL0_RW
-		);
-		assert!(addr == MALLOC_REGION);
+// Offsets in chunk
+const CHUNK_PREV: u64 = 0;
+const CHUNK_SIZE: u64 = 8;
+const FREE_NEXT:  u64 = 16;
+const FREE_PREV:  u64 = 24;
 
-		// Mark entire bitmap as free
-		unsafe { memset(meta, 0x00, mmu::PAGE_SIZE) };
+/**
+* Where heap gets memory from, applets use system calls.
+*/
+pub trait PageSource {
+	/* Map zeroed pages in region, returns false on failure */
+	fn map(&mut self, addr: u64, size: u64) -> bool;
+	fn unmap(&mut self, addr: u64, size: u64);
+}
 
-		// Allocate one block to store our own data
-		// We should get the first block
-		assert!(bitmap::alloc(meta, mmu::PAGE_SIZE, 1) == 0);
+pub struct SvcPages;
 
-		// Store the number of blocks we've allocated for data
-		memory::dma::write::u64(MALLOC_REGION, BLOCK_INC);
+impl PageSource for SvcPages {
+	fn map(&mut self, addr: u64, size: u64) -> bool {
+		let res = applets::svc!(cpu::svc::SYSNO_MMAP, addr, size, mmu::EL0_RW);
+		return res == addr;
+	}
+	fn unmap(&mut self, addr: u64, size: u64) {
+		applets::svc!(cpu::svc::SYSNO_MUNMAP, addr, size);
+	}
+}
 
-		return 0;
+/**
+* Pages for kernel heap, mapped in the EL1 address space.
+*/
+pub struct PmmPages;
+
+impl PageSource for PmmPages {
+	fn map(&mut self, addr: u64, size: u64) -> bool {
+		let pages = (size / mmu::PAGE_SIZE) as i32;
+		return mmu::alloc_pages(mmu::kernel_ttbr(), addr, pages, mmu::EL1_RW) == 0;
+	}
+	fn unmap(&mut self, addr: u64, size: u64) {
+		let pages = (size / mmu::PAGE_SIZE) as i32;
+		mmu::unmap_pages(mmu::kernel_ttbr(), addr, pages);
+	}
+}
+
+// Kernel heap can be used by all cores, applet heaps are only used by applet
+static KHEAP_LOCK: AtomicBool = AtomicBool::new(false);
+
+pub struct Heap<P: PageSource> {
+	start: u64,
+	limit: u64,
+	src:   P,
+}
+
+macro_rules! rd {
+	($addr:expr) => { memory::dma::read::u64($addr) };
+}
+macro_rules! wr {
+	($addr:expr, $val:expr) => { memory::dma::write::u64($addr, $val) };
+}
+
+impl<P: PageSource> Heap<P> {
+	pub fn new(start: u64, limit: u64, src: P) -> Heap<P> {
+		Heap { start: start, limit: limit, src: src }
+	}
+
+	fn end(&self) -> u64 { return rd!(self.start + STATE_END); }
+	fn first(&self) -> u64 { return self.start + STATE_SIZE; }
+	fn size(&self, chunk: u64) -> u64 { return rd!(chunk + CHUNK_SIZE) & !CHUNK_USED; }
+	fn used(&self, chunk: u64) -> bool { return (rd!(chunk + CHUNK_SIZE) & CHUNK_USED) != 0; }
+
+	/**
+	* Write header for chunk and update the neighbour which follows it.
+	*/
+	fn set_chunk(&mut self, chunk: u64, size: u64, used: bool) {
+		wr!(chunk + CHUNK_SIZE, size | if used { CHUNK_USED } else { 0 });
+		if chunk + size == self.end() {
+			wr!(self.start + STATE_LAST, chunk);
+		} else {
+			wr!(chunk + size + CHUNK_PREV, size);
+		}
+	}
+
+	/* Chunk before this one in memory, 0 if this is the first */
+	fn prev(&self, chunk: u64) -> u64 {
+		if chunk == self.first() {
+			return 0;
+		}
+		return chunk - rd!(chunk + CHUNK_PREV);
+	}
+	/* Chunk after this one in memory, 0 if this is the last */
+	fn next(&self, chunk: u64) -> u64 {
+		let next = chunk + self.size(chunk);
+		if next >= self.end() {
+			return 0;
+		}
+		return next;
+	}
+
+	fn list_insert(&mut self, chunk: u64) {
+		let head = rd!(self.start + STATE_FREE);
+		wr!(chunk + FREE_NEXT, head);
+		wr!(chunk + FREE_PREV, 0);
+		if head != 0 {
+			wr!(head + FREE_PREV, chunk);
+		}
+		wr!(self.start + STATE_FREE, chunk);
+	}
+	fn list_remove(&mut self, chunk: u64) {
+		let next = rd!(chunk + FREE_NEXT);
+		let prev = rd!(chunk + FREE_PREV);
+		if prev != 0 {
+			wr!(prev + FREE_NEXT, next);
+		} else {
+			wr!(self.start + STATE_FREE, next);
+		}
+		if next != 0 {
+			wr!(next + FREE_PREV, prev);
+		}
 	}
-	pub fn ensure_space(&self, blocks: u64) -> i32 {
-		if blocks > MAX_PAGES {
+
+	pub fn init(&mut self) -> i32 {
+		if ! self.src.map(self.start, mmu::PAGE_SIZE) {
 			return -1;
 		}
-		let cblks = memory::dma::read::u64(MALLOC_REGION);
-		if cblks < blocks {
-			let nblocks = blocks - cblks;
-			let nsaddr = MALLOC_REGION + (cblks * mmu::PAGE_SIZE);
-			let addr = applets::svc!(
-				cpu::svc::SYSNO_MMAP, nsaddr, nblocks * mmu::PAGE_SIZE, mmu::EL0_RW
-			);
-			assert!(addr == nsaddr);
+		wr!(self.start + STATE_END, self.start + mmu::PAGE_SIZE);
+		wr!(self.start + STATE_FREE, 0);
+
+		let first = self.first();
+		wr!(first + CHUNK_PREV, 0);
+		self.set_chunk(first, mmu::PAGE_SIZE - STATE_SIZE, false);
+		self.list_insert(first);
+		return 0;
+	}
+
+	/**
+	* Map at least `bytes` more at the end of the heap. New memory is added to
+	* the last chunk if it's free.
+	*/
+	fn grow(&mut self, bytes: u64) -> bool {
+		let end = self.end();
+		let size = math::align_pow2_up!(bytes, mmu::PAGE_SIZE);
+		if size > self.limit - end || ! self.src.map(end, size) {
+			return false;
+		}
+		wr!(self.start + STATE_END, end + size);
+
+		let last = rd!(self.start + STATE_LAST);
+		if ! self.used(last) {
+			let lsize = self.size(last);
+			self.set_chunk(last, lsize + size, false);
+		} else {
+			wr!(end + CHUNK_PREV, self.size(last));
+			self.set_chunk(end, size, false);
+			self.list_insert(end);
+		}
+		return true;
+	}
 
-			// Write back number of blocks we now have
-			memory::dma::write::u64(MALLOC_REGION, blocks);
+	/**
+	* Find where a chunk of `need` bytes with data aligned to `align` can be
+	* placed inside free chunk. Returns 0 if it doesn't fit.
+	*/
+	fn fit(&self, chunk: u64, need: u64, align: u64) -> u64 {
+		let mut data = math::align_pow2_up!(chunk + HDR_SIZE, align);
+
+		// Space in front must be large enough to be its own chunk
+		if data - HDR_SIZE != chunk && data - HDR_SIZE - chunk < MIN_CHUNK {
+			data = math::align_pow2_up!(chunk + HDR_SIZE + MIN_CHUNK, align);
+		}
+		if data - HDR_SIZE + need <= chunk + self.size(chunk) {
+			return data - HDR_SIZE;
 		}
 		return 0;
 	}
-}
 
-unsafe impl GlobalAlloc for Allocator {
-	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		let size = math::align_pow2_up!(layout.size() as u32, BLOCK_SIZE) as u32;
-		let blocks = size / BLOCK_SIZE;
+	/**
+	* Split `chunk` so that it's `need` bytes, the rest is returned as a free
+	* chunk if it's large enough.
+	*/
+	fn split_tail(&mut self, chunk: u64, need: u64) {
+		let size = self.size(chunk);
+		if size - need >= MIN_CHUNK {
+			let used = self.used(chunk);
+			self.set_chunk(chunk, need, used);
+			self.set_chunk(chunk + need, size - need, true);
+			self.free_chunk(chunk + need);
+		}
+	}
+
+	/**
+	* Take free chunk into use, `at` is where the allocated chunk should start.
+	*/
+	fn carve(&mut self, chunk: u64, at: u64, need: u64) -> u64 {
+		self.list_remove(chunk);
+		let mut size = self.size(chunk);
+		if at != chunk {
+			let gap = at - chunk;
+			self.set_chunk(chunk, gap, false);
+			self.list_insert(chunk);
+			size -= gap;
+		}
+		self.set_chunk(at, size, true);
+		self.split_tail(at, need);
+		return at + HDR_SIZE;
+	}
 
+	/**
+	* Mark chunk as free, merge it with its neighbours and give back memory at
+	* the end of the heap.
+	*/
+	fn free_chunk(&mut self, mut chunk: u64) {
+		let mut size = self.size(chunk);
 
-		let idx = bitmap::alloc(MEMORY_REGION, mmu::PAGE_SIZE, blocks as u64);
-		if idx == u64::MAX {
-			return ptr::null_mut();
+		let next = self.next(chunk);
+		if next != 0 && ! self.used(next) {
+			self.list_remove(next);
+			size += self.size(next);
+		}
+		let prev = self.prev(chunk);
+		if prev != 0 && ! self.used(prev) {
+			self.list_remove(prev);
+			size += self.size(prev);
+			chunk = prev;
+		}
+
+		// Keep a minimal chunk so that the heap is never empty
+		let end = self.end();
+		if chunk + size == end {
+			let cut = math::align_pow2_up!(chunk + MIN_CHUNK, mmu::PAGE_SIZE);
+			if cut < end && end - cut >= TRIM_SIZE {
+				self.src.unmap(cut, end - cut);
+				wr!(self.start + STATE_END, cut);
+				size = cut - chunk;
+			}
+		}
+		self.set_chunk(chunk, size, false);
+		self.list_insert(chunk);
+	}
+
+	fn chunk_size(size: u64) -> u64 {
+		let data = if size < MIN_CHUNK - HDR_SIZE { MIN_CHUNK - HDR_SIZE } else { size };
+		return math::align_pow2_up!(data, MIN_ALIGN) + HDR_SIZE;
+	}
+
+	/**
+	* Allocate `size` bytes aligned to `align`, returns 0 on failure.
+	*/
+	pub fn alloc(&mut self, size: u64, align: u64) -> u64 {
+		let need = Self::chunk_size(size);
+		let align = if align < MIN_ALIGN { MIN_ALIGN } else { align };
+		loop {
+			let mut chunk = rd!(self.start + STATE_FREE);
+			while chunk != 0 {
+				let at = self.fit(chunk, need, align);
+				if at != 0 {
+					return self.carve(chunk, at, need);
+				}
+				chunk = rd!(chunk + FREE_NEXT);
+			}
+			// Worst case we must skip align bytes and leave a chunk in front
+			if ! self.grow(need + align + MIN_CHUNK) {
+				return 0;
+			}
+		}
+	}
+
+	pub fn free(&mut self, ptr: u64) {
+		self.free_chunk(ptr - HDR_SIZE);
+	}
+
+	/**
+	* Resize allocation, the data is kept in place if possible. Returns 0 on
+	* failure, the old allocation is then still valid.
+	*/
+	pub fn realloc(&mut self, ptr: u64, size: u64, align: u64) -> u64 {
+		let chunk = ptr - HDR_SIZE;
+		let need = Self::chunk_size(size);
+		let mut csize = self.size(chunk);
+
+		// Try to get more space from free chunk after us, grow heap if we're last
+		if need > csize {
+			if self.next(chunk) == 0 {
+				self.grow(need - csize);
+			}
+			let next = self.next(chunk);
+			if next != 0 && ! self.used(next) && csize + self.size(next) >= need {
+				self.list_remove(next);
+				csize += self.size(next);
+				self.set_chunk(chunk, csize, true);
+			}
+		}
+		if need <= csize {
+			self.split_tail(chunk, need);
+			return ptr;
+		}
+
+		let nptr = self.alloc(size, align);
+		if nptr != 0 {
+			unsafe { memcpy(nptr, ptr, csize - HDR_SIZE); }
+			self.free(ptr);
 		}
-		let doffset = idx * BLOCK_SIZE as u64;
-		self.ensure_space(math::align_pow2_up!(doffset, mmu::PAGE_SIZE) / mmu::PAGE_SIZE);
+		return nptr;
+	}
+}
+
+pub struct Allocator { }
 
-		let addr = MALLOC_REGION + doffset;
+/**
+* Applets always run with a stack in the upper half, all kernel stacks are in
+* the lower half.
+*/
+fn in_applet() -> bool {
+	let marker: u8 = 0;
+	return mmu::inupper!(&marker as *const u8 as u64);
+}
 
- 		return addr as *mut u8;
+impl Allocator {
+	pub const fn empty() -> Allocator {
+		Allocator { }
+	}
+	fn heap(&self) -> Heap<SvcPages> {
+		return Heap::new(HEAP_START, HEAP_LIMIT, SvcPages);
 	}
-	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-		let size = math::align_pow2_up!(layout.size() as u32, BLOCK_SIZE) as u32;
-		let rptr = ptr as u64;
-		let offset = rptr - MALLOC_REGION;
-		let blocks = size / BLOCK_SIZE;
-		let startidx = offset / BLOCK_SIZE as u64;
-		
-		bitmap::free(MEMORY_REGION, mmu::PAGE_SIZE, startidx, blocks as u64);
+	fn kheap(&self) -> Heap<PmmPages> {
+		return Heap::new(mmu::START_HEAP_REGION, mmu::START_HEAP_REGION + mmu::HEAP_REGION_SIZE, PmmPages);
+	}
+	/**
+	* Take kernel heap lock with IRQ and FIQ masked, otherwise an interrupt
+	* handler allocating on this core would spin forever. Returns DAIF which must
+	* be restored with unlock.
+	*/
+	fn lock(&self) -> u64 {
+		let daif = register::read::daif();
+		register::write::daif(daif | register::daif::I | register::daif::F);
+		while KHEAP_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+			core::hint::spin_loop();
+		}
+		return daif;
+	}
+	fn unlock(&self, daif: u64) {
+		KHEAP_LOCK.store(false, Ordering::Release);
+		register::write::daif(daif);
+	}
+	pub fn init(&self) -> i32 {
+		return self.heap().init();
+	}
+	pub fn init_kernel(&self) -> i32 {
+		return self.kheap().init();
+	}
+}
+
+unsafe impl GlobalAlloc for Allocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let addr;
+		if in_applet() {
+			addr = self.heap().alloc(layout.size() as u64, layout.align() as u64);
+		} else {
+			let daif = self.lock();
+			addr = self.kheap().alloc(layout.size() as u64, layout.align() as u64);
+			self.unlock(daif);
+		}
+		if addr == 0 {
+			return ptr::null_mut();
+		}
+		return addr as *mut u8;
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+		if mmu::inupper!(ptr as u64) {
+			self.heap().free(ptr as u64);
+		} else {
+			let daif = self.lock();
+			self.kheap().free(ptr as u64);
+			self.unlock(daif);
+		}
+	}
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let addr;
+		if mmu::inupper!(ptr as u64) {
+			addr = self.heap().realloc(ptr as u64, new_size as u64, layout.align() as u64);
+		} else {
+			let daif = self.lock();
+			addr = self.kheap().realloc(ptr as u64, new_size as u64, layout.align() as u64);
+			self.unlock(daif);
+		}
+		if addr == 0 {
+			return ptr::null_mut();
+		}
+		return addr as *mut u8;
 	}
 }
 
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: Allocator = Allocator::empty();
 
+/**
+* Set up heap for applet, must be called from applet before it uses alloc.
+*/
 pub fn init() -> i32 {
 	return ALLOCATOR.init();
 }
+
+/**
+* Set up kernel heap, must be called after virtual memory is initialized.
+*/
+pub fn init_kernel() -> i32 {
+	return ALLOCATOR.init_kernel();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PAGES: u64 = 256;
+
+	/**
+	* Memory from host, unmapped pages are filled with a pattern so that use of
+	* stale memory shows up as corrupted data.
+	*/
+	struct HostPages {
+		base: u64,
+		mapped: Vec<bool>,
+	}
+
+	impl HostPages {
+		fn page(&self, addr: u64) -> usize {
+			return ((addr - self.base) / mmu::PAGE_SIZE) as usize;
+		}
+		fn mapped_pages(&self) -> usize {
+			return self.mapped.iter().filter(|x| **x).count();
+		}
+	}
+
+	impl PageSource for HostPages {
+		fn map(&mut self, addr: u64, size: u64) -> bool {
+			for a in (addr..addr + size).step_by(mmu::PAGE_SIZE as usize) {
+				let p = self.page(a);
+				assert!(! self.mapped[p]);
+				self.mapped[p] = true;
+				unsafe { core::ptr::write_bytes(a as *mut u8, 0x00, mmu::PAGE_SIZE as usize); }
+			}
+			return true;
+		}
+		fn unmap(&mut self, addr: u64, size: u64) {
+			for a in (addr..addr + size).step_by(mmu::PAGE_SIZE as usize) {
+				let p = self.page(a);
+				assert!(self.mapped[p]);
+				self.mapped[p] = false;
+				unsafe { core::ptr::write_bytes(a as *mut u8, 0xdd, mmu::PAGE_SIZE as usize); }
+			}
+		}
+	}
+
+	fn setup(mem: &Vec<u8>) -> Heap<HostPages> {
+		let base = math::align_pow2_up!(mem.as_ptr() as u64, mmu::PAGE_SIZE);
+		let src = HostPages{ base: base, mapped: vec![false; PAGES as usize] };
+		let mut heap = Heap::new(base, base + (PAGES * mmu::PAGE_SIZE), src);
+		assert_eq!(heap.init(), 0);
+		return heap;
+	}
+
+	// Deterministic pseudo-random numbers
+	struct XorShift(u64);
+	impl XorShift {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			return self.0;
+		}
+	}
+
+	/* Live allocation, `data` is a copy made with the host allocator */
+	struct Live {
+		ptr: u64,
+		align: u64,
+		data: Vec<u8>,
+	}
+
+	fn fill(ptr: u64, data: &mut Vec<u8>, rng: &mut XorShift) {
+		for b in data.iter_mut() {
+			*b = rng.next() as u8;
+		}
+		unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len()); }
+	}
+
+	fn check(heap: &Heap<HostPages>, live: &Vec<Live>) {
+		let mut ranges: Vec<(u64, u64)> = Vec::new();
+		for l in live.iter() {
+			let len = l.data.len() as u64;
+			assert_eq!(l.ptr % l.align, 0);
+			assert!(l.ptr >= heap.start && l.ptr + len <= heap.end());
+			for a in (math::align_pow2_down!(l.ptr, mmu::PAGE_SIZE)..l.ptr + len).step_by(mmu::PAGE_SIZE as usize) {
+				assert!(heap.src.mapped[heap.src.page(a)]);
+			}
+			let cur = unsafe { core::slice::from_raw_parts(l.ptr as *const u8, len as usize) };
+			assert!(cur == &l.data[..]);
+			ranges.push((l.ptr, l.ptr + len));
+		}
+		ranges.sort();
+		for w in ranges.windows(2) {
+			assert!(w[0].1 <= w[1].0);
+		}
+	}
+
+	#[test]
+	fn random_operations() {
+		let mem = vec![0u8; ((PAGES + 1) * mmu::PAGE_SIZE) as usize];
+		let mut heap = setup(&mem);
+		let mut rng = XorShift(0x1234_5678_9abc_def1);
+		let mut live: Vec<Live> = Vec::new();
+
+		for _i in 0..4000 {
+			// Keep number of live allocations bounded so heap doesn't run out
+			let op = if live.len() >= 48 { 5 } else { rng.next() % 10 };
+			if op < 5 || live.len() == 0 {
+				let size = match rng.next() % 4 {
+					0 => rng.next() % 32,
+					1 => rng.next() % 512,
+					2 => rng.next() % 4096,
+					_ => rng.next() % (4 * 4096),
+				} + 1;
+				let align = 1 << (rng.next() % 13);
+				let ptr = heap.alloc(size, align);
+				assert!(ptr != 0);
+				let mut data = vec![0u8; size as usize];
+				fill(ptr, &mut data, &mut rng);
+				live.push(Live{ ptr: ptr, align: align, data: data });
+			} else if op < 8 {
+				let idx = (rng.next() % live.len() as u64) as usize;
+				let l = live.swap_remove(idx);
+				heap.free(l.ptr);
+			} else {
+				let idx = (rng.next() % live.len() as u64) as usize;
+				let nsize = (rng.next() % (2 * 4096)) + 1;
+				let ptr = heap.realloc(live[idx].ptr, nsize, live[idx].align);
+				assert!(ptr != 0);
+				let l = &mut live[idx];
+				let keep = core::cmp::min(nsize as usize, l.data.len());
+				l.data.truncate(keep);
+				l.ptr = ptr;
+				let cur = unsafe { core::slice::from_raw_parts(ptr as *const u8, keep) };
+				assert!(cur == &l.data[..]);
+
+				let mut data = vec![0u8; nsize as usize];
+				fill(ptr, &mut data, &mut rng);
+				l.data = data;
+			}
+			check(&heap, &live);
+		}
+
+		// Everything should be returned, except a few pages
+		for l in live.drain(..) {
+			heap.free(l.ptr);
+		}
+		assert!(heap.src.mapped_pages() as u64 <= TRIM_SIZE / mmu::PAGE_SIZE);
+		assert_eq!(rd!(heap.start + STATE_FREE), heap.first());
+		assert_eq!(heap.first() + heap.size(heap.first()), heap.end());
+	}
+
+	#[test]
+	fn realloc_in_place() {
+		let mem = vec![0u8; ((PAGES + 1) * mmu::PAGE_SIZE) as usize];
+		let mut heap = setup(&mem);
+
+		let a = heap.alloc(100, 8);
+		let b = heap.realloc(a, 2000, 8);
+		assert_eq!(a, b);
+		let c = heap.realloc(b, 40, 8);
+		assert_eq!(a, c);
+
+		// Block after us is in use, so data must be moved
+		let d = heap.alloc(16, 8);
+		let e = heap.realloc(c, 20000, 8);
+		assert!(e != c);
+		heap.free(d);
+		heap.free(e);
+	}
+
+	#[test]
+	fn out_of_memory() {
+		let mem = vec![0u8; ((PAGES + 1) * mmu::PAGE_SIZE) as usize];
+		let mut heap = setup(&mem);
+
+		assert_eq!(heap.alloc(PAGES * mmu::PAGE_SIZE, 16), 0);
+		let a = heap.alloc((PAGES - 2) * mmu::PAGE_SIZE, 16);
+		assert!(a != 0);
+		heap.free(a);
+		assert_eq!(heap.src.mapped_pages(), 1);
+	}
+}
@@ -1,19 +1,25 @@
 use driver::serial;
 
-pub fn info(buf: &str)	{
-	serial::write("I: ");
+#[cfg(not(test))]
+fn line(prefix: &str, buf: &str) {
+	serial::write(prefix);
 	serial::write(buf);
 	serial::putc('\n');
 }
+// Serial port is not mapped when running unit tests on host
+#[cfg(test)]
+fn line(prefix: &str, buf: &str) {
+	eprintln!("{}{}", prefix, buf);
+}
+
+pub fn info(buf: &str)	{
+	line("I: ", buf);
+}
 pub fn debug(buf: &str) {
-	serial::write("D: ");
-	serial::write(buf);
-	serial::putc('\n');
+	line("D: ", buf);
 }
 pub fn bug(buf: &str)	{
-	serial::write("BUG: ");
-	serial::write(buf);
-	serial::putc('\n');
+	line("BUG: ", buf);
 }
 pub fn from_memory(_buf: u64) {
 	let mut i = 0;
@@ -1,20 +1,50 @@
+/*
+* Barriers, cache and TLB maintenance. Unit tests run on the host where these
+* instructions can't be used, so they are no-ops in tests.
+*/
+#[cfg(not(test))]
+pub mod insn {
+	pub fn isb() { unsafe { asm!("isb"); } }
+	pub fn dmb_sy() { unsafe { asm!("dmb sy"); } }
+	pub fn dsb_ishst() { unsafe { asm!("dsb ishst"); } }
+	pub fn dsb_ish() { unsafe { asm!("dsb ish"); } }
+	pub fn dsb_sy() { unsafe { asm!("dsb sy"); } }
+	pub fn dc_zva(v: u64) { unsafe { asm!("dc zva, {v:x}", v = in(reg) v); } }
+	pub fn dc_civac(v: u64) { unsafe { asm!("dc civac, {v:x}", v = in(reg) v); } }
+	pub fn tlbi_vae1is(v: u64) { unsafe { asm!("tlbi vae1is, {v:x}", v = in(reg) v); } }
+	pub fn tlbi_vaae1is(v: u64) { unsafe { asm!("tlbi vaae1is, {v:x}", v = in(reg) v); } }
+	pub fn tlbi_aside1is(v: u64) { unsafe { asm!("tlbi aside1is, {v:x}", v = in(reg) v); } }
+	pub fn tlbi_vmalle1is() { unsafe { asm!("tlbi vmalle1is"); } }
+}
+#[cfg(test)]
+pub mod insn {
+	pub fn isb() { }
+	pub fn dmb_sy() { }
+	pub fn dsb_ishst() { }
+	pub fn dsb_ish() { }
+	pub fn dsb_sy() { }
+	pub fn dc_zva(_v: u64) { }
+	pub fn dc_civac(_v: u64) { }
+	pub fn tlbi_vae1is(_v: u64) { }
+	pub fn tlbi_vaae1is(_v: u64) { }
+	pub fn tlbi_aside1is(_v: u64) { }
+	pub fn tlbi_vmalle1is() { }
+}
 
 #[macro_export]
 macro_rules! isb {
-	() => { unsafe { asm!("isb"); } };
+	() => { $crate::lib::memory::insn::isb() };
 }
 
 #[macro_export]
 macro_rules! dmb {
-	() => { unsafe { asm!("dmb sy"); } };
+	() => { $crate::lib::memory::insn::dmb_sy() };
 }
 #[macro_export]
 macro_rules! dcinval {
 	($addr:expr) => {
 		memory::dmb!();
-		unsafe {
-			asm!("dc zva, {v:x}", v = in(reg) $addr);
-		}
+		$crate::lib::memory::insn::dc_zva($addr);
 		memory::dsb::sy!()
 	};
 }
@@ -26,21 +56,70 @@ pub(crate) use dmb;
 pub mod dsb {
 	#[macro_export]
 	macro_rules! ishst {
-		() => { unsafe { asm!("dsb ishst"); } };
+		() => { $crate::lib::memory::insn::dsb_ishst() };
 	}
 	#[macro_export]
 	macro_rules! ish {
-		() => { unsafe { asm!("dsb ish"); } };
+		() => { $crate::lib::memory::insn::dsb_ish() };
 	}
 	#[macro_export]
 	macro_rules! sy {
-		() => { unsafe { asm!("dsb sy"); } };
+		() => { $crate::lib::memory::insn::dsb_sy() };
 	}
 	pub(crate) use ishst;
 	pub(crate) use ish;
 	pub(crate) use sy;
 }
 
+/*
+* TLB maintenance, all operations are broadcast to the inner shareable domain.
+* Caller is responsible for the surrounding dsb/isb.
+*/
+pub mod tlbi {
+	// Invalidate VA for a single ASID, value is ASID << 48 | VA >> 12
+	#[macro_export]
+	macro_rules! vae1is {
+		($v:expr) => { $crate::lib::memory::insn::tlbi_vae1is($v) };
+	}
+	// Invalidate VA for all ASIDs, value is VA >> 12
+	#[macro_export]
+	macro_rules! vaae1is {
+		($v:expr) => { $crate::lib::memory::insn::tlbi_vaae1is($v) };
+	}
+	// Invalidate all non-global entries for ASID, value is ASID << 48
+	#[macro_export]
+	macro_rules! aside1is {
+		($v:expr) => { $crate::lib::memory::insn::tlbi_aside1is($v) };
+	}
+	#[macro_export]
+	macro_rules! vmalle1is {
+		() => { $crate::lib::memory::insn::tlbi_vmalle1is() };
+	}
+	pub(crate) use vae1is;
+	pub(crate) use vaae1is;
+	pub(crate) use aside1is;
+	pub(crate) use vmalle1is;
+}
+
+
+pub mod cache {
+	use cpu;
+
+	/**
+	* Clean and invalidate data cache lines covering `len` bytes at `addr`, used
+	* before memory which may be cached through another alias is accessed.
+	*/
+	pub fn clean_inval(addr: u64, len: u64) {
+		// CTR_EL0.DminLine is log2 of the number of words in smallest line
+		let line = 4 << ((cpu::register::read::ctr_el0() >> 16) & 0xf);
+		let mut va = addr & !(line - 1);
+		while va < addr + len {
+			super::insn::dc_civac(va);
+			va += line;
+		}
+		super::insn::dsb_sy();
+	}
+}
 
 #[macro_export]
 macro_rules! smp_mb {
@@ -2,6 +2,8 @@ pub mod memory;
 pub mod log;
 pub mod alloc;
 pub mod bitmap;
+pub mod sha256;
+pub mod rsa;
 
 pub mod math {
 	#[macro_export]
@@ -0,0 +1,217 @@
+/**
+* RSA signature verification with PKCS#1 v1.5 padding and SHA-256.
+*
+* Only verification is supported, the public exponent is always 65537.
+* Numbers are stored as little-endian arrays of 32-bit limbs and
+* exponentiation is done with Montgomery multiplication. Numbers are allocated
+* on the heap since they are too large for the kernel stack.
+*/
+use lib::sha256;
+use alloc::vec::Vec;
+use alloc::vec;
+
+/* Largest supported modulus is 4096 bits */
+const MAX_LIMBS: usize = 128;
+
+pub const MAX_KEY_SIZE: usize = MAX_LIMBS * 4;
+
+/* DER encoding of DigestInfo for SHA-256, hash follows */
+const DIGEST_INFO: [u8; 19] = [
+	0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20
+];
+
+/* Minimum number of 0xff bytes in padding */
+const MIN_PADDING: usize = 8;
+
+type Num = Vec<u32>;
+
+/**
+* Public key, `n` is the big-endian modulus.
+*/
+pub struct PublicKey<'a> {
+	pub n: &'a [u8],
+}
+
+fn from_be(bytes: &[u8]) -> Num {
+	let mut out = vec![0; (bytes.len() + 3) / 4];
+	for (i, b) in bytes.iter().rev().enumerate() {
+		out[i / 4] |= (*b as u32) << ((i % 4) * 8);
+	}
+	return out;
+}
+fn to_be(num: &[u32], out: &mut [u8]) {
+	let len = out.len();
+	for i in 0..len {
+		out[len - 1 - i] = (num[i / 4] >> ((i % 4) * 8)) as u8;
+	}
+}
+
+/* a >= b, only first `len` limbs are used */
+fn ge(a: &[u32], b: &[u32], len: usize) -> bool {
+	for i in (0..len).rev() {
+		if a[i] != b[i] {
+			return a[i] > b[i];
+		}
+	}
+	return true;
+}
+/* a -= b */
+fn sub(a: &mut [u32], b: &[u32], len: usize) {
+	let mut borrow = 0i64;
+	for i in 0..len {
+		let d = a[i] as i64 - b[i] as i64 + borrow;
+		a[i] = d as u32;
+		borrow = d >> 32;
+	}
+}
+
+/**
+* -n^-1 mod 2^32, n must be odd.
+*/
+fn mont_inv(n0: u32) -> u32 {
+	// Newton iteration doubles number of correct bits each time
+	let mut x: u32 = 1;
+	for _ in 0..5 {
+		x = x.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(x)));
+	}
+	return x.wrapping_neg();
+}
+
+/**
+* a * b * R^-1 mod n
+*/
+fn mont_mul(a: &[u32], b: &[u32], n: &[u32], ninv: u32, len: usize) -> Num {
+	let mut t = vec![0u32; len + 2];
+	for i in 0..len {
+		let mut carry = 0u64;
+		for j in 0..len {
+			let v = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+			t[j] = v as u32;
+			carry = v >> 32;
+		}
+		let v = t[len] as u64 + carry;
+		t[len] = v as u32;
+		t[len + 1] = (v >> 32) as u32;
+
+		let m = t[0].wrapping_mul(ninv);
+		let mut carry = (t[0] as u64 + m as u64 * n[0] as u64) >> 32;
+		for j in 1..len {
+			let v = t[j] as u64 + m as u64 * n[j] as u64 + carry;
+			t[j - 1] = v as u32;
+			carry = v >> 32;
+		}
+		let v = t[len] as u64 + carry;
+		t[len - 1] = v as u32;
+		t[len] = t[len + 1] + (v >> 32) as u32;
+		t[len + 1] = 0;
+	}
+	let mut res: Num = t[..len].to_vec();
+	if t[len] != 0 || ge(&res, n, len) {
+		sub(&mut res, n, len);
+	}
+	return res;
+}
+
+/**
+* sig^65537 mod n
+*/
+fn public_op(sig: &[u32], n: &[u32], len: usize) -> Num {
+	let ninv = mont_inv(n[0]);
+
+	// R^2 mod n by doubling 1 until we've multiplied with R^2
+	let mut r2: Num = vec![0; len];
+	r2[0] = 1;
+	for _ in 0..(len * 32 * 2) {
+		let mut carry = 0;
+		for i in 0..len {
+			let v = (r2[i] >> 31) as u32;
+			r2[i] = (r2[i] << 1) | carry;
+			carry = v;
+		}
+		if carry != 0 || ge(&r2, n, len) {
+			sub(&mut r2, n, len);
+		}
+	}
+
+	let x = mont_mul(sig, &r2, n, ninv, len);
+	let mut acc = x.clone();
+	for _ in 0..16 {
+		acc = mont_mul(&acc, &acc, n, ninv, len);
+	}
+	acc = mont_mul(&acc, &x, n, ninv, len);
+
+	let mut one: Num = vec![0; len];
+	one[0] = 1;
+	return mont_mul(&acc, &one, n, ninv, len);
+}
+
+/**
+* Verify that `sig` is a signature of `hash` made with key. Signature must be
+* the same size as the modulus.
+*/
+pub fn verify_hash(key: &PublicKey, hash: &[u8; sha256::DIGEST_SIZE], sig: &[u8]) -> bool {
+	let size = key.n.len();
+	if size == 0 || size > MAX_KEY_SIZE || size % 4 != 0 || sig.len() != size {
+		return false;
+	}
+	if size < 3 + MIN_PADDING + DIGEST_INFO.len() + hash.len() || (key.n[size - 1] & 1) == 0 {
+		return false;
+	}
+	let len = size / 4;
+	let n = from_be(key.n);
+	let s = from_be(sig);
+	if ge(&s, &n, len) {
+		return false;
+	}
+
+	let m = public_op(&s, &n, len);
+	let mut em = vec![0u8; size];
+	to_be(&m, &mut em);
+
+	// 00 01 ff .. ff 00 DigestInfo hash
+	let pad = size - 3 - DIGEST_INFO.len() - hash.len();
+	let mut ok = em[0] == 0x00 && em[1] == 0x01 && em[2 + pad] == 0x00;
+	ok &= em[2..2 + pad].iter().all(|b| *b == 0xff);
+	ok &= em[3 + pad..3 + pad + DIGEST_INFO.len()] == DIGEST_INFO;
+	ok &= em[size - hash.len()..] == hash[..];
+	return ok;
+}
+
+/**
+* Verify signature of `data`.
+*/
+pub fn verify(key: &PublicKey, data: &[u8], sig: &[u8]) -> bool {
+	return verify_hash(key, &sha256::digest(data), sig);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 512-bit key and signature of "abc" made with openssl
+	const N: [u8; 64] = [
+		0xd2, 0x8f, 0xe2, 0x66, 0x31, 0xe8, 0x5b, 0x56, 0x6a, 0x06, 0x29, 0x89, 0x0a, 0xd7, 0x17, 0x18,
+		0x87, 0x76, 0xca, 0xb9, 0x12, 0x55, 0x7c, 0x56, 0x77, 0x55, 0x34, 0xe1, 0x87, 0x6e, 0x9e, 0x11,
+		0x26, 0x5b, 0x99, 0xe5, 0x01, 0x57, 0xc5, 0x96, 0x2e, 0x9b, 0x3d, 0x92, 0x28, 0xc2, 0x1f, 0x71,
+		0x07, 0x56, 0xb7, 0x90, 0x9b, 0xc3, 0x22, 0x72, 0xd0, 0x34, 0xca, 0x9a, 0xd9, 0x3c, 0x3f, 0xeb,
+	];
+
+	const SIG: [u8; 64] = [
+		0x8a, 0x69, 0x9e, 0x84, 0xad, 0x55, 0xf6, 0x4f, 0x8a, 0x90, 0x90, 0xd4, 0x9f, 0xa2, 0x93, 0xec,
+		0x0f, 0xb4, 0x6b, 0x3a, 0x04, 0x34, 0x72, 0x77, 0x7d, 0xf7, 0x11, 0x24, 0x25, 0x25, 0x8a, 0x21,
+		0x3b, 0x57, 0xa5, 0xca, 0x8c, 0x67, 0x71, 0x3a, 0x89, 0x82, 0x6a, 0x81, 0x4a, 0x7e, 0x90, 0x74,
+		0xb1, 0x1d, 0x1b, 0x5e, 0x67, 0xd4, 0x30, 0x9b, 0x88, 0x51, 0xe5, 0xdc, 0x64, 0xb2, 0x49, 0x94,
+	];
+
+	#[test]
+	fn verify_signature() {
+		let key = PublicKey{n: &N};
+		assert!(verify(&key, b"abc", &SIG));
+		assert!(!verify(&key, b"abd", &SIG));
+
+		let mut bad = SIG;
+		bad[10] ^= 1;
+		assert!(!verify(&key, b"abc", &bad));
+		assert!(!verify(&key, b"abc", &SIG[1..]));
+	}
+}
@@ -0,0 +1,147 @@
+/**
+* SHA-256 as specified in FIPS 180-4.
+*
+* Data can be added in pieces with `update`, `finish` returns the digest.
+*/
+
+pub const DIGEST_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 64;
+
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+pub struct Sha256 {
+	state: [u32; 8],
+	buf: [u8; BLOCK_SIZE],
+	buflen: usize,
+	/* Total bytes added */
+	len: u64,
+}
+
+impl Sha256 {
+	pub fn new() -> Sha256 {
+		return Sha256{state: H0, buf: [0; BLOCK_SIZE], buflen: 0, len: 0};
+	}
+
+	fn compress(&mut self, block: &[u8]) {
+		let mut w = [0u32; 64];
+		for i in 0..16 {
+			w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+		}
+		for i in 16..64 {
+			let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+			let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+			w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+		}
+
+		let mut v = self.state;
+		for i in 0..64 {
+			let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+			let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+			let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+			let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+			let t2 = s0.wrapping_add(maj);
+			v[7] = v[6];
+			v[6] = v[5];
+			v[5] = v[4];
+			v[4] = v[3].wrapping_add(t1);
+			v[3] = v[2];
+			v[2] = v[1];
+			v[1] = v[0];
+			v[0] = t1.wrapping_add(t2);
+		}
+		for i in 0..8 {
+			self.state[i] = self.state[i].wrapping_add(v[i]);
+		}
+	}
+
+	pub fn update(&mut self, mut data: &[u8]) {
+		self.len += data.len() as u64;
+		if self.buflen > 0 {
+			let n = core::cmp::min(BLOCK_SIZE - self.buflen, data.len());
+			self.buf[self.buflen..self.buflen + n].copy_from_slice(&data[..n]);
+			self.buflen += n;
+			data = &data[n..];
+			if self.buflen < BLOCK_SIZE {
+				return;
+			}
+			let block = self.buf;
+			self.compress(&block);
+			self.buflen = 0;
+		}
+		while data.len() >= BLOCK_SIZE {
+			self.compress(&data[..BLOCK_SIZE]);
+			data = &data[BLOCK_SIZE..];
+		}
+		self.buf[..data.len()].copy_from_slice(data);
+		self.buflen = data.len();
+	}
+
+	pub fn finish(mut self) -> [u8; DIGEST_SIZE] {
+		let bits = self.len * 8;
+
+		// Pad with 0x80 and zeroes until there is room for the length
+		self.update(&[0x80]);
+		while self.buflen != BLOCK_SIZE - 8 {
+			self.update(&[0]);
+		}
+		self.update(&bits.to_be_bytes());
+
+		let mut out = [0u8; DIGEST_SIZE];
+		for i in 0..8 {
+			out[i * 4..i * 4 + 4].copy_from_slice(&self.state[i].to_be_bytes());
+		}
+		return out;
+	}
+}
+
+/**
+* Digest of `data` in one call.
+*/
+pub fn digest(data: &[u8]) -> [u8; DIGEST_SIZE] {
+	let mut h = Sha256::new();
+	h.update(data);
+	return h.finish();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hex(d: &[u8]) -> String {
+		return d.iter().map(|b| format!("{:02x}", b)).collect();
+	}
+
+	#[test]
+	fn known_digests() {
+		assert_eq!(hex(&digest(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(hex(&digest(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(hex(&digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+			"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+	}
+
+	#[test]
+	fn split_updates() {
+		let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+		let whole = digest(&data);
+		for split in [1, 55, 56, 63, 64, 65, 500].iter() {
+			let mut h = Sha256::new();
+			h.update(&data[..*split]);
+			h.update(&data[*split..]);
+			assert_eq!(h.finish(), whole);
+		}
+	}
+}
@@ -3,13 +3,15 @@
 
 // What must be provided for std environment
 // <https://docs.rust-embedded.org/book/intro/no-std.html>
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![crate_name="bl32"]
 #![allow(dead_code)]
 
 #![feature(alloc_error_handler)]
 
 extern crate alloc;
+#[cfg(test)]
+extern crate core;
 
 mod lib;
 mod cpu;
@@ -17,11 +19,11 @@ mod driver;
 mod platform;
 mod applets;
 mod syscall;
+mod verify;
 
 use lib::log;
 // use platform::Platform;
 use driver::mmu;
-use lib::math;
 use core::alloc::Layout;
 
 extern "C" {
@@ -30,36 +32,40 @@ extern "C" {
 
 #[no_mangle]
 pub extern "C" fn get_new_stack() -> u64 {
-	let mut nstack = mmu::maxmem_lower!() - (mmu::PAGE_SIZE * 2);
-	while ! mmu::page_available_el1(nstack) {
-		// Reserve some space for overruns
-		nstack -= mmu::PAGE_SIZE * 4;
-	}
-	assert!(mmu::alloc_page_el1(nstack, mmu::EL1_RW) == 0);
-	return nstack + mmu::PAGE_SIZE;
+	// Stack has an unmapped guard page below it to catch overruns
+	let nstack = cpu::stack::alloc_el1();
+	assert!(nstack != u64::MAX);
+	return nstack;
 }
 
 #[no_mangle]
 pub extern "C" fn rustmain(fdt: u64, imgload: u64, imgend: u64) -> u64 {
 	log::info("Reached rustmain");
 
-	// FDT is not located in secure memory, so should either copy it over or not
-	// use it after the init-routines are done
+	// FDT is located in non-secure memory, it's copied to secure memory as soon
+	// as we have a pmm
 	let _fdtsize = driver::dtb::init(fdt);
+	platform::select();
 
-	// Get address of serial device
-	let (mut plstart, mut plsize) = driver::dtb::get_as_reg("/pl011/reg", 0);
+	// Init physical memory manager
+	driver::pmm::init(imgload, imgend);
+	if driver::dtb::relocate() < 0 {
+		log::info("Unable to copy FDT to secure memory");
+	}
+
+	// Get address of serial device, secure-chosen takes precedence
+	let (mut plstart, mut plsize) = driver::dtb::get_stdout();
+	if plstart == u64::MAX {
+		(plstart, plsize) = driver::dtb::get_reg("/pl011", 0);
+	}
 	if plstart == u64::MAX {
 		// Get the default one we've already been using
 		log::info("Unable to find address for serial device in DTB");
-		plstart = platform::uart::BASE0;
+		plstart = platform::board().uart;
 		plsize = mmu::PAGE_SIZE;
 	}
 	driver::serial::set_base(plstart);
 
-	// Init physical memory manager
-	driver::pmm::init(imgload, imgend);
-
 	// Init virtual memory:
 	// - Identity map image region
 	// - set up linear region for future modifications
@@ -67,12 +73,25 @@ pub extern "C" fn rustmain(fdt: u64, imgload: u64, imgend: u64) -> u64 {
 
 	// PMM must adjust any dynamically allocated data
 	driver::pmm::lateinit(linear);
+	driver::dtb::lateinit(linear);
 
 	// Set up DMA for serial device
 	mmu::map_dma(plstart, plstart + plsize);
-// 	platform::Impl::map_dma();
+
+	// GIC is needed by drivers using interrupts
+	mmu::map_dma(platform::base_gicd(), platform::base_gicd() + mmu::PAGE_SIZE);
+	mmu::map_dma(platform::base_gicc(), platform::base_gicc() + mmu::PAGE_SIZE * 2);
 	log::info("Initialized virtual memory");
 
+	// Kernel can use alloc from here
+	assert!(lib::alloc::init_kernel() == 0);
+
+	// Map and initialize all devices in the device tree we have drivers for
+	driver::registry::probe_all();
+
+	// Periodic tick, kicks watchdog and enforces applet time budgets
+	cpu::timer::init();
+
 	// Set up new stack unique to CPU core
 	let nstack = get_new_stack();
 	unsafe { switch_stack(nstack) };
@@ -85,6 +104,7 @@ pub extern "C" fn rustmain(fdt: u64, imgload: u64, imgend: u64) -> u64 {
 	return 0;
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 pub fn panic_implementation(_info: &::core::panic::PanicInfo) -> ! {
     loop { }
@@ -93,6 +113,7 @@ pub fn panic_implementation(_info: &::core::panic::PanicInfo) -> ! {
 pub fn panic() -> ! {
 	loop { }
 }
+#[cfg(not(test))]
 #[alloc_error_handler]
 pub fn alloc_panic(_layout: Layout) -> ! {
 	panic();
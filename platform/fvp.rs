@@ -0,0 +1,22 @@
+/**
+* Arm FVP base platform with GICv2 model.
+*/
+use platform::Board;
+use platform::Power;
+
+pub const BOARD: Board = Board {
+	name: "fvp-base",
+	compatible: &["arm,fvp-base"],
+
+	/* UART2 is used by secure payloads in TF-A */
+	uart: 0x1c0b0000,
+	gic_version: 2,
+	gicd: 0x2f000000,
+	gicc: 0x2c000000,
+	timer_irq: 29,
+
+	/* Trusted DRAM */
+	secram: (0xff000000, 0x01000000),
+	power: Power::Psci,
+	secure_pins: &[],
+};
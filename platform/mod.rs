@@ -1,72 +1,193 @@
-pub mod uart {
-	pub const BASE0: u64 = 0x09000000;
+/**
+* Board descriptors.
+*
+* Each supported board has a descriptor with the devices we need before, or
+* without, a device tree. The board is selected at boot from the root
+* compatible in the device tree, if no board matches, the board chosen at
+* compile time with `--cfg platform="..."` is used.
+*
+* Devices found in the device tree take precedence over the addresses in the
+* descriptor.
+*/
+use driver;
+use driver::dtb;
+use driver::pl061;
+use lib::log;
+
+mod qemu;
+mod sbsa;
+mod fvp;
+
+/**
+* How the system is powered off and reset.
+*/
+pub enum Power {
+	/* Secure monitor handles it through PSCI */
+	Psci,
+
+	/* Pulse secure GPIO pins connected to power and reset key */
+	Gpio{off: u32, reset: u32},
+}
+
+pub struct Board {
+	pub name: &'static str,
+
+	/* Board matches if root node is compatible with any of these */
+	pub compatible: &'static [&'static str],
+
+	/* Console used before device tree has been parsed */
+	pub uart: u64,
+
+	pub gic_version: u32,
+	pub gicd: u64,
+	/* CPU interface for GICv2, redistributor for GICv3 */
+	pub gicc: u64,
+
+	/* Secure physical timer interrupt ID */
+	pub timer_irq: u32,
+
+	/* Secure RAM (start, size) if it's not in device tree */
+	pub secram: (u64, u64),
+
+	pub power: Power,
+
+	/* Pins on secure GPIO controllers the TEE is allowed to use */
+	pub secure_pins: &'static [(u32, u8)],
+}
+
+#[cfg(platform = "qemu")] pub const DEFAULT: &Board = &qemu::BOARD;
+#[cfg(platform = "sbsa")] pub const DEFAULT: &Board = &sbsa::BOARD;
+#[cfg(platform = "fvp")]  pub const DEFAULT: &Board = &fvp::BOARD;
+
+static BOARDS: [&Board; 3] = [&qemu::BOARD, &sbsa::BOARD, &fvp::BOARD];
+
+static mut BOARD: &Board = DEFAULT;
+
+/* GIC addresses found in device tree, 0 if not found */
+static mut GICD: u64 = 0;
+static mut GICC: u64 = 0;
+
+pub fn board() -> &'static Board {
+	return unsafe { BOARD };
+}
+
+/**
+* Find board matching root compatible, None if we don't know the board.
+*/
+pub fn find_board(fdt: &dtb::Fdt) -> Option<&'static Board> {
+	let root = fdt.root()?;
+	for compat in fdt.prop_strings(&root, "compatible") {
+		for b in BOARDS.iter() {
+			if b.compatible.contains(&compat) {
+				return Some(*b);
+			}
+		}
+	}
+	return None;
+}
+
+/**
+* Select board from device tree, must be called after dtb::init.
+*/
+pub fn select() {
+	let fdt = match dtb::fdt() {
+		Some(f) => f,
+		None => { return; }
+	};
+	match find_board(&fdt) {
+		Some(b) => { unsafe { BOARD = b; } },
+		None => { log::info("Unknown board in device tree, using default"); },
+	}
+	log::info(board().name);
+
+	// Only use GIC node matching the version we drive. Distributor is the first
+	// reg, second is CPU interface for GICv2 and redistributor for GICv3.
+	let compats: &[&str] = match board().gic_version {
+		3 => &["arm,gic-v3"],
+		_ => &["arm,gic-400", "arm,cortex-a15-gic"],
+	};
+	for compat in compats.iter() {
+		if let Some(node) = fdt.find_compatible(compat, None) {
+			let gicd = fdt.reg(&node, 0);
+			let gicc = fdt.reg(&node, 1);
+			if let (Some((gicd, _)), Some((gicc, _))) = (gicd, gicc) {
+				unsafe {
+					GICD = gicd;
+					GICC = gicc;
+				}
+			}
+			break;
+		}
+	}
+}
+
+pub fn base_gicd() -> u64 {
+	let gicd = unsafe { GICD };
+	return if gicd != 0 { gicd } else { board().gicd };
+}
+pub fn base_gicc() -> u64 {
+	let gicc = unsafe { GICC };
+	return if gicc != 0 { gicc } else { board().gicc };
+}
+
+/**
+* Secure RAM from device tree, or from board if it's not there.
+*/
+pub fn secure_memory() -> (u64, u64) {
+	let (start, size) = dtb::get_secure_memory();
+	if start != u64::MAX {
+		return (start, size);
+	}
+	return board().secram;
 }
 
-pub trait Platform {
-// 	fn power_off()		{ }
-// 	fn power_reset()	{ }
- 	fn base_gicc() -> u64 { return u64::MAX; }
- 	fn base_gicd() -> u64 { return u64::MAX; }
+/**
+* Pulse GPIO connected to power key, false if secure GPIO isn't available.
+*/
+fn pulse(pin: u32) -> bool {
+	if pl061::set::direction(pin, driver::gpio::DIR_OUT) < 0 {
+		return false;
+	}
+	pl061::set::value(pin, driver::gpio::HIGH);
+	pl061::set::value(pin, driver::gpio::LOW);
+	return true;
 }
 
-pub struct PlatQemu;
-
-impl Platform for PlatQemu {
-// 	fn power_off()	{
-// 		driver::pl061::set::direction(gpio::qemu::POWEROFF, gpio::DIR_OUT);
-// 		driver::pl061::set::value(gpio::qemu::POWEROFF, gpio::HIGH);
-// 		driver::pl061::set::value(gpio::qemu::POWEROFF, gpio::LOW);
-// 	}
-// 	fn power_reset()	{
-// 		driver::pl061::set::direction(gpio::qemu::RESET, gpio::DIR_OUT);
-// 		driver::pl061::set::value(gpio::qemu::RESET, gpio::HIGH);
-// 		driver::pl061::set::value(gpio::qemu::RESET, gpio::LOW);
-// 	}
-	fn base_gicc() -> u64 { return 0x8010000; }
-	fn base_gicd() -> u64 { return 0x8000000; }
+/**
+* Power off system, called when normal world has asked the secure monitor to
+* power down the system and our state has been cleaned up.
+*
+* Returns false if the secure monitor should continue with PSCI, otherwise it
+* should not return.
+*/
+pub fn power_off() -> bool {
+	match board().power {
+		Power::Gpio{off, ..} => { return pulse(off); },
+		Power::Psci => { return false; },
+	}
 }
 
-#[cfg(platform = "qemu")] pub type Impl = PlatQemu;
-
-
-
-
-// pub mod gicr {
-// 	pub const BASE: u64 = 0x80A0000;
-// }
-// pub mod rom {
-// 	pub const BASE: u64 = 0x0;
-// 	pub const SIZE: u64 = 0x00020000;
-// }
-
-// pub mod irq {
-// 	pub const SEC_PHY_TIMER: u32 = 29;
-// }
-
-/* BL32 can be loaded in dram or sram */
-
-// pub mod sec {
-// 	pub mod sram {
-// 		/* Start of BL */
-// 		pub const BASE: u64 = 0x0e000000;
-// 
-// 		// Can use memory from image address and to end
-// 		pub const SIZE: u64 = 0x00060000;
-// 
-// 
-// 		// Unsure about these values
-// 		const SHARED: u64 = 0x00001000;
-// 		pub const BL32_START: u64 = BASE + SHARED;
-// 	}
-// 
-// 	pub mod dram {
-// 		pub const BASE: u64 = 0x0e100000;
-// 		pub const SIZE: u64 = 0x00f00000;
-// 		pub const LIMIT: u64 = BASE + SIZE;
-// 	}
-// }
-// pub mod memory {
-// 	pub const BASE: u64 = super::sec::dram::BASE;
-// 	pub const SIZE: u64 = super::sec::dram::SIZE;
-// 	pub const LIMIT: u64 = super::sec::dram::LIMIT;
-// }
+/**
+* Same as power_off, but for reset.
+*/
+pub fn power_reset() -> bool {
+	match board().power {
+		Power::Gpio{reset, ..} => { return pulse(reset); },
+		Power::Psci => { return false; },
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn board_from_root_compatible() {
+		let raw = include_bytes!("../driver/testdata/fvp-base.dtb");
+		let mut blob = vec![0u32; (raw.len() + 3) / 4];
+		let data = unsafe { core::slice::from_raw_parts_mut(blob.as_mut_ptr() as *mut u8, raw.len()) };
+		data.copy_from_slice(raw);
+		let fdt = dtb::Fdt::new_bounded(blob.as_ptr() as u64, raw.len() as u32).unwrap();
+		assert_eq!(find_board(&fdt).map(|b| b.name), Some("fvp-base"));
+	}
+}
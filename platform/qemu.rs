@@ -0,0 +1,28 @@
+/**
+* QEMU virt machine with secure=on.
+*/
+use driver::gpio;
+use platform::Board;
+use platform::Power;
+
+pub const BOARD: Board = Board {
+	name: "qemu-virt",
+	compatible: &["linux,dummy-virt"],
+	uart: 0x09000000,
+	gic_version: 2,
+	gicd: 0x08000000,
+	gicc: 0x08010000,
+	timer_irq: 29,
+
+	/* BL32 is loaded in secure DRAM */
+	secram: (0x0e100000, 0x00f00000),
+
+	// Pin 0 and 1 on the secure PL061 are power off and reset
+	power: Power::Gpio{off: gpio::qemu::POWEROFF, reset: gpio::qemu::RESET},
+	secure_pins: &[
+		(gpio::qemu::POWEROFF, gpio::OWNER_KERNEL),
+		(gpio::qemu::RESET,    gpio::OWNER_KERNEL),
+		(2, gpio::OWNER_APPLET),
+		(3, gpio::OWNER_APPLET),
+	],
+};
@@ -0,0 +1,23 @@
+/**
+* QEMU sbsa-ref machine.
+*
+* This machine only has GICv3, so secure interrupts are not available until we
+* have a GICv3 driver.
+*/
+use platform::Board;
+use platform::Power;
+
+pub const BOARD: Board = Board {
+	name: "qemu-sbsa-ref",
+	compatible: &["linux,sbsa-ref"],
+
+	/* Secure UART */
+	uart: 0x60030000,
+	gic_version: 3,
+	gicd: 0x40060000,
+	gicc: 0x40080000,
+	timer_irq: 29,
+	secram: (0x20000000, 0x20000000),
+	power: Power::Psci,
+	secure_pins: &[],
+};
@@ -1,22 +1,46 @@
 use lib::math;
 use lib::log;
 use driver::mmu;
+use driver::gpio;
+use driver::pl061;
 use cpu;
+use applets;
 
 
+/**
+* Size of page aligned region covering `size` bytes at `addr` in applet address
+* space. Returns None if region is empty, outside the upper half or too large.
+*/
+fn region_size(addr: u64, size: u64) -> Option<u64> {
+	let lowest = !((1u64 << mmu::VA_BITS) - 1);
+	let end = addr.checked_add(size)?;
+	if size == 0 || addr < lowest || end > mmu::maxmem_upper!() {
+		return None;
+	}
+	let rsize = math::align_pow2_up!(end, mmu::PAGE_SIZE) - math::align_pow2_down!(addr, mmu::PAGE_SIZE);
+	if rsize / mmu::PAGE_SIZE > i32::MAX as u64 {
+		return None;
+	}
+	return Some(rsize);
+}
+
+/**
+* Map new memory for applet, returns address of mapping or u64::MAX if region
+* is already in use or applet is out of memory or quota.
+*/
 pub fn mmap(addr: u64, size: u64, prot: u64) -> u64 {
-	// Align down both address and size
-	let rsize = math::align_pow2_up!(size, mmu::PAGE_SIZE);
+	// Address and size come from user mode, range must be inside the upper
+	// half applets execute in and sizes must not overflow
+	let rsize = match region_size(addr, size) {
+		Some(s) => s,
+		None => { return u64::MAX; }
+	};
 	let raddr = math::align_pow2_down!(addr, mmu::PAGE_SIZE);
-	
-	// Check that addr range belongs to user mode
-	// We do an assert here because it's a bug in our code if it fails
-	assert!(mmu::inupper!(raddr));
-	assert!(mmu::inupper!(raddr + rsize));
 
-	// Ensure address is not in reserved range
-	assert!(raddr < mmu::VA_RESERVED_START || raddr > mmu::VA_RESERVED_STOP);
-	assert!(raddr + rsize < mmu::VA_RESERVED_START || raddr + rsize > mmu::VA_RESERVED_STOP);
+	// Stack guard page must stay unmapped
+	if applets::in_reserved(raddr, rsize) {
+		return u64::MAX;
+	}
 
 	let mut rprot = mmu::mask_prot_el0(prot);
 	if rprot != prot {
@@ -36,14 +60,105 @@ pub fn mmap(addr: u64, size: u64, prot: u64) -> u64 {
 
 pub fn munmap(addr: u64, size: u64) -> u64 {
 	// We can still get wrong size from user-mode, because we may have increased
-	// it in mmap and we do not return the real size to user
-	let rsize = math::align_pow2_up!(size, mmu::PAGE_SIZE);
+	// it in mmap and we do not return the real size to user. Addr is returned
+	// and user-mode should use the correct addr in unmap.
+	if math::align_pow2_down!(addr, mmu::PAGE_SIZE) != addr {
+		return u64::MAX;
+	}
+	let rsize = match region_size(addr, size) {
+		Some(s) => s,
+		None => { return u64::MAX; }
+	};
 
-	// Addr is returned and user-mode should use the correct addr in unmap
-	assert!(math::align_pow2_down!(addr, mmu::PAGE_SIZE) == addr);
+	// Stack and memory shared by another applet can't be unmapped
+	if applets::in_reserved(addr, rsize) {
+		return u64::MAX;
+	}
 
 
 	let ttbr = cpu::register::read_ttbr1_el1!();
-	mmu::unmap_pages(ttbr, addr, (rsize / mmu::PAGE_SIZE) as i32);
+	if mmu::unmap_pages(ttbr, addr, (rsize / mmu::PAGE_SIZE) as i32) < 0 {
+		return u64::MAX;
+	}
 	return 0;
 }
+
+/* Applet watching a pin and events since it last asked */
+#[derive(Clone, Copy)]
+struct GpioWatch {
+	applet: usize,
+	events: u64,
+}
+
+static mut GPIO_EVENTS: [GpioWatch; pl061::MAX_GPIOS as usize] =
+	[GpioWatch{applet: usize::MAX, events: 0}; pl061::MAX_GPIOS as usize];
+
+fn gpio_event(pin: u32) {
+	unsafe { GPIO_EVENTS[pin as usize].events += 1; }
+}
+
+/**
+* Operation `op` from driver::gpio::op on pin owned by applets and declared in
+* manifest of running applet. Returns u64::MAX if applet isn't allowed to use
+* pin or operation failed.
+*/
+pub fn gpio(op: u64, pin: u32, arg: u64) -> u64 {
+	if pin >= pl061::MAX_GPIOS || gpio::owner(pin) != gpio::OWNER_APPLET {
+		log::info("Applet tried to use GPIO it doesn't own");
+		return u64::MAX;
+	}
+	if ! applets::allow_gpio(pin) {
+		return u64::MAX;
+	}
+	let res = match op {
+		gpio::op::DIRECTION => pl061::set::direction(pin, arg as u32),
+		gpio::op::SET => pl061::set::value(pin, arg as u8),
+		gpio::op::GET => {
+			let val = pl061::get::value(pin);
+			return if val == u32::MAX { u64::MAX } else { val as u64 };
+		},
+		gpio::op::WATCH => {
+			// Events counted for previous watcher are not handed over
+			let watch = unsafe { &mut GPIO_EVENTS[pin as usize] };
+			watch.applet = applets::running();
+			watch.events = 0;
+			pl061::request_irq(pin, arg as u32, gpio_event)
+		},
+		gpio::op::EVENTS => {
+			let watch = unsafe { &mut GPIO_EVENTS[pin as usize] };
+			if watch.applet != applets::running() {
+				return u64::MAX;
+			}
+			let events = watch.events;
+			watch.events = 0;
+
+			// Level triggered pin is masked after each event
+			pl061::unmask_irq(pin);
+			return events;
+		},
+		_ => -1,
+	};
+	return if res < 0 { u64::MAX } else { 0 };
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn region_from_user() {
+		let top = mmu::maxmem_upper!();
+		let lowest = !((1u64 << mmu::VA_BITS) - 1);
+		let pg = mmu::PAGE_SIZE;
+
+		assert_eq!(region_size(top - (2 * pg) + 8, 16), Some(pg));
+		assert_eq!(region_size(top - (2 * pg) + 8, pg), Some(2 * pg));
+		assert_eq!(region_size(lowest, pg), Some(pg));
+		assert_eq!(region_size(top - pg, 0), None);
+		assert_eq!(region_size(top - pg, u64::MAX), None);
+		assert_eq!(region_size(top, pg), None);
+		assert_eq!(region_size(lowest - pg, pg), None);
+		assert_eq!(region_size(0x1000, pg), None);
+		assert_eq!(region_size(lowest, (i32::MAX as u64 + 1) * pg), None);
+	}
+}
@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+"""
+Create signed manifests verified by the TEE, see verify/mod.rs for the format.
+
+Sign manifest for applet, payload is optional:
+
+    ./tools/mkmanifest.py --key root.pem --uuid <uuid> \
+        --version 2 --quota 192 --budget 1000 -o applet.manifest
+
+Print public key as Rust source, the Makefile does this with ROOT_KEY when
+building the TEE. --key can be a private or public key here:
+
+    ./tools/mkmanifest.py --key root_pub.pem --rootkey
+
+Signing uses the openssl command line tool. No key is shipped, the key in
+verify/testdata is only trusted by the unit tests.
+"""
+import argparse
+import hashlib
+import struct
+import subprocess
+import sys
+import uuid
+
+MAGIC = 0x464d4154
+FORMAT = 1
+KIND = {"applet": 1, "config": 2}
+HEADER_SIZE = 128
+
+
+def modulus(key):
+	cmd = ["openssl", "rsa", "-in", key, "-noout", "-modulus"]
+	with open(key) as f:
+		if "PUBLIC KEY" in f.read():
+			cmd.append("-pubin")
+	out = subprocess.check_output(cmd)
+	return bytes.fromhex(out.decode().strip().split("=")[1])
+
+
+def sign(key, data):
+	proc = subprocess.run(["openssl", "dgst", "-sha256", "-sign", key], input=data,
+		stdout=subprocess.PIPE, check=True)
+	return proc.stdout
+
+
+def rootkey(key):
+	n = modulus(key)
+	print("/* Generated with: tools/mkmanifest.py --key %s --rootkey */" % key)
+	print("pub const ROOT_KEY: [u8; %d] = [" % len(n))
+	for i in range(0, len(n), 16):
+		print("\t" + " ".join("0x%02x," % b for b in n[i:i + 16]))
+	print("];")
+
+
+def main():
+	p = argparse.ArgumentParser()
+	p.add_argument("--key", required=True)
+	p.add_argument("--rootkey", action="store_true")
+	p.add_argument("--kind", choices=KIND.keys(), default="applet")
+	p.add_argument("--uuid")
+	p.add_argument("--version", type=int, default=0)
+	p.add_argument("--quota", type=int, default=0)
+	p.add_argument("--budget", type=int, default=0)
+	p.add_argument("--perms", type=lambda x: int(x, 0), default=0)
+	p.add_argument("--payload")
+	p.add_argument("-o", "--output")
+	args = p.parse_args()
+
+	if args.rootkey:
+		rootkey(args.key)
+		return 0
+	if args.uuid is None or args.output is None:
+		p.error("--uuid and --output are required")
+
+	payload = b""
+	if args.payload:
+		with open(args.payload, "rb") as f:
+			payload = f.read()
+
+	sig_len = len(modulus(args.key))
+	header = struct.pack("<IHH16sIIIIQII32s", MAGIC, FORMAT, KIND[args.kind],
+		uuid.UUID(args.uuid).bytes, args.version, args.quota, args.budget, 0,
+		args.perms, len(payload), sig_len, hashlib.sha256(payload).digest())
+	header += b"\0" * (HEADER_SIZE - len(header))
+
+	with open(args.output, "wb") as f:
+		f.write(header + sign(args.key, header) + payload)
+	return 0
+
+
+if __name__ == "__main__":
+	sys.exit(main())
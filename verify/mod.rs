@@ -0,0 +1,329 @@
+/**
+* Verification of images entering the TEE.
+*
+* Everything linked into the TEE is trusted since the image itself is verified
+* by the boot chain. Anything loaded at runtime must be described by a manifest
+* signed with the root key. The public key is given with ROOT_KEY when building
+* and the Makefile passes it as Rust source in TEE_ROOTKEY, the build fails if
+* it's missing. Unit tests use the key in testdata instead. Manifests are
+* created with `tools/mkmanifest.py`.
+*
+* Manifest format, all values are little endian:
+*
+* | Offset | Size | Field                                        |
+* |--------|------|----------------------------------------------|
+* | 0      | 4    | magic, "TAMF"                                |
+* | 4      | 2    | format, must be FORMAT                       |
+* | 6      | 2    | kind, KIND_APPLET or KIND_CONFIG             |
+* | 8      | 16   | UUID of applet                               |
+* | 24     | 4    | version                                      |
+* | 28     | 4    | quota in pages, 0 is no limit                |
+* | 32     | 4    | time budget in ms, 0 is no limit             |
+* | 36     | 4    | reserved                                     |
+* | 40     | 8    | allowed syscalls, see applets::manifest      |
+* | 48     | 4    | size of payload                              |
+* | 52     | 4    | size of signature                            |
+* | 56     | 32   | SHA-256 of payload                           |
+* | 88     | 40   | reserved, must be zero                       |
+*
+* Signature (RSA PKCS#1 v1.5, SHA-256) over the 128 byte header follows the
+* header and the payload comes last.
+*
+* Rollback protection is done with one monotonic counter per UUID and kind, an
+* image is rejected if its version is lower than the counter. Counters are only
+* kept in RAM and would be reset on every boot, so images are rejected with
+* ERR_NO_STORE until they are backed by OTP or RPMB storage, see
+* PERSISTENT_COUNTERS.
+*/
+use alloc::vec::Vec;
+use lib::rsa;
+use lib::sha256;
+
+#[cfg(not(test))]
+mod rootkey {
+	include!(env!("TEE_ROOTKEY"));
+}
+#[cfg(test)]
+#[path = "testdata/rootkey.rs"]
+mod rootkey;
+
+pub const MAGIC: u32 = 0x464d4154;
+pub const FORMAT: u16 = 1;
+pub const HEADER_SIZE: usize = 128;
+
+pub const KIND_APPLET: u16 = 1;
+pub const KIND_CONFIG: u16 = 2;
+
+/* Largest blob we accept */
+pub const MAX_IMAGE_SIZE: usize = 64 * 1024;
+
+/*
+* Errors returned from verify_image
+*/
+pub const ERR_FORMAT:    i32 = -1;
+pub const ERR_SIGNATURE: i32 = -2;
+pub const ERR_HASH:      i32 = -3;
+pub const ERR_ROLLBACK:  i32 = -4;
+pub const ERR_NO_SPACE:  i32 = -5;
+pub const ERR_NO_STORE:  i32 = -6;
+
+/* Set when counters survive reboot, images are only loaded if they do */
+pub const PERSISTENT_COUNTERS: bool = false;
+
+pub type Uuid = [u8; 16];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Manifest {
+	pub kind: u16,
+	pub uuid: Uuid,
+	pub version: u32,
+	pub quota: u32,
+	pub budget: u32,
+	pub perms: u64,
+	/* Offset and size of payload in blob */
+	pub payload_offset: usize,
+	pub payload_size: usize,
+}
+
+struct Counter {
+	uuid: Uuid,
+	kind: u16,
+	version: u32,
+	used: bool,
+}
+
+const MAX_COUNTERS: usize = 16;
+
+static mut COUNTERS: [Counter; MAX_COUNTERS] = [
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+	Counter{uuid: [0; 16], kind: 0, version: 0, used: false}, Counter{uuid: [0; 16], kind: 0, version: 0, used: false},
+];
+
+fn read_u16(b: &[u8], off: usize) -> u16 {
+	return u16::from_le_bytes([b[off], b[off + 1]]);
+}
+fn read_u32(b: &[u8], off: usize) -> u32 {
+	return u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]]);
+}
+fn read_u64(b: &[u8], off: usize) -> u64 {
+	return (read_u32(b, off) as u64) | ((read_u32(b, off + 4) as u64) << 32);
+}
+
+/**
+* Minimum version allowed for image of `kind` for `uuid`, 0 if we haven't seen
+* it.
+*/
+pub fn min_version(uuid: &Uuid, kind: u16) -> u32 {
+	for c in unsafe { COUNTERS.iter() } {
+		if c.used && c.uuid == *uuid && c.kind == kind {
+			return c.version;
+		}
+	}
+	return 0;
+}
+
+/**
+* Raise counter for image in `man` to its version, counter is never decreased.
+* Should only be called after image has been applied. Returns ERR_NO_SPACE if
+* all counters are in use.
+*/
+pub fn commit_version(man: &Manifest) -> i32 {
+	let (uuid, kind, version) = (&man.uuid, man.kind, man.version);
+	let counters = unsafe { &mut COUNTERS };
+	let mut free = usize::MAX;
+	for i in 0..MAX_COUNTERS {
+		if counters[i].used && counters[i].uuid == *uuid && counters[i].kind == kind {
+			if version > counters[i].version {
+				counters[i].version = version;
+			}
+			return 0;
+		} else if ! counters[i].used && free == usize::MAX {
+			free = i;
+		}
+	}
+	if free == usize::MAX {
+		return ERR_NO_SPACE;
+	}
+	counters[free] = Counter{uuid: *uuid, kind: kind, version: version, used: true};
+	return 0;
+}
+
+/**
+* Parse header and check that signature is made by `key`. The blob is only
+* trusted if this returns 0.
+*/
+pub fn verify_with_key(key: &rsa::PublicKey, blob: &[u8], man: &mut Manifest) -> i32 {
+	if blob.len() < HEADER_SIZE || blob.len() > MAX_IMAGE_SIZE {
+		return ERR_FORMAT;
+	}
+	let hdr = &blob[..HEADER_SIZE];
+	if read_u32(hdr, 0) != MAGIC || read_u16(hdr, 4) != FORMAT {
+		return ERR_FORMAT;
+	}
+	let sig_len = read_u32(hdr, 52) as usize;
+	let payload_size = read_u32(hdr, 48) as usize;
+	if sig_len != key.n.len() || HEADER_SIZE + sig_len + payload_size != blob.len() {
+		return ERR_FORMAT;
+	}
+
+	// Nothing in the header can be trusted before this
+	let sig = &blob[HEADER_SIZE..HEADER_SIZE + sig_len];
+	if ! rsa::verify(key, hdr, sig) {
+		return ERR_SIGNATURE;
+	}
+	let kind = read_u16(hdr, 6);
+	if (kind != KIND_APPLET && kind != KIND_CONFIG) || hdr[88..].iter().any(|b| *b != 0) {
+		return ERR_FORMAT;
+	}
+
+	let payload = &blob[HEADER_SIZE + sig_len..];
+	if sha256::digest(payload)[..] != hdr[56..88] {
+		return ERR_HASH;
+	}
+
+	let mut uuid: Uuid = [0; 16];
+	uuid.copy_from_slice(&hdr[8..24]);
+	let version = read_u32(hdr, 24);
+	if version < min_version(&uuid, kind) {
+		return ERR_ROLLBACK;
+	}
+
+	*man = Manifest{
+		kind: kind,
+		uuid: uuid,
+		version: version,
+		quota: read_u32(hdr, 28),
+		budget: read_u32(hdr, 32),
+		perms: read_u64(hdr, 40),
+		payload_offset: HEADER_SIZE + sig_len,
+		payload_size: payload_size,
+	};
+	return 0;
+}
+
+/**
+* Verify blob against the root key, see verify_with_key.
+*/
+pub fn verify_image(blob: &[u8], man: &mut Manifest) -> i32 {
+	let key = rsa::PublicKey{n: &rootkey::ROOT_KEY};
+	return verify_with_key(&key, blob, man);
+}
+
+/*
+* Verified configuration blobs, newest one for each UUID
+*/
+static mut CONFIGS: Vec<(Uuid, Vec<u8>)> = Vec::new();
+
+/**
+* Store payload from verified configuration blob for applet with `uuid`.
+*/
+pub fn store_config(uuid: &Uuid, payload: &[u8]) {
+	let configs = unsafe { &mut CONFIGS };
+	for c in configs.iter_mut().filter(|c| c.0 == *uuid) {
+		scrub(&mut c.1);
+	}
+	configs.retain(|c| c.0 != *uuid);
+	configs.push((*uuid, payload.to_vec()));
+}
+
+/* Zero payload before its memory is returned to the heap */
+fn scrub(payload: &mut Vec<u8>) {
+	for b in payload.iter_mut() {
+		unsafe { core::ptr::write_volatile(b, 0); }
+	}
+}
+
+/**
+* Zero and drop all configurations, called before system goes down.
+*/
+pub fn scrub_configs() {
+	let configs = unsafe { &mut CONFIGS };
+	for c in configs.iter_mut() {
+		scrub(&mut c.1);
+	}
+	configs.clear();
+}
+
+/**
+* Last verified configuration for `uuid`.
+*/
+pub fn config(uuid: &Uuid) -> Option<&'static [u8]> {
+	for c in unsafe { CONFIGS.iter() } {
+		if c.0 == *uuid {
+			return Some(&c.1);
+		}
+	}
+	return None;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const STORAGE: &[u8] = include_bytes!("testdata/storage.manifest");
+	const CONFIG:  &[u8] = include_bytes!("testdata/config.manifest");
+
+	#[test]
+	fn signed_manifests() {
+		let mut man = Manifest::default();
+		assert_eq!(verify_image(STORAGE, &mut man), 0);
+		assert_eq!(man.kind, KIND_APPLET);
+		assert_eq!(man.version, 2);
+		assert_eq!(man.quota, 128);
+		assert_eq!(man.budget, 500);
+		assert_eq!(man.perms, 3);
+		assert_eq!(man.payload_size, 0);
+
+		// Counter for applet doesn't affect config with lower version
+		assert_eq!(commit_version(&man), 0);
+		assert_eq!(verify_image(STORAGE, &mut man), 0);
+		assert_eq!(verify_image(CONFIG, &mut man), 0);
+		assert_eq!(man.kind, KIND_CONFIG);
+		assert_eq!(man.version, 1);
+		assert_eq!(&CONFIG[man.payload_offset..], b"storage config");
+
+		// Counter is never decreased
+		let mut newer = man;
+		newer.version = 5;
+		assert_eq!(commit_version(&newer), 0);
+		assert_eq!(commit_version(&man), 0);
+		assert_eq!(min_version(&man.uuid, KIND_CONFIG), 5);
+		assert_eq!(verify_image(CONFIG, &mut man), ERR_ROLLBACK);
+	}
+
+	#[test]
+	fn tampered_manifests() {
+		let mut man = Manifest::default();
+
+		// Raise quota
+		let mut blob = STORAGE.to_vec();
+		blob[28] = 0xff;
+		assert_eq!(verify_image(&blob, &mut man), ERR_SIGNATURE);
+
+		// Signature is fine, but payload has changed
+		let mut blob = CONFIG.to_vec();
+		let last = blob.len() - 1;
+		blob[last] ^= 1;
+		assert_eq!(verify_image(&blob, &mut man), ERR_HASH);
+
+		assert_eq!(verify_image(&CONFIG[..CONFIG.len() - 1], &mut man), ERR_FORMAT);
+		assert_eq!(verify_image(&STORAGE[..HEADER_SIZE - 1], &mut man), ERR_FORMAT);
+		assert_eq!(man, Manifest::default());
+	}
+
+	#[test]
+	fn configs_scrubbed() {
+		let uuid = [7u8; 16];
+		store_config(&uuid, b"first");
+		store_config(&uuid, b"second");
+		assert_eq!(config(&uuid), Some(&b"second"[..]));
+		scrub_configs();
+		assert_eq!(config(&uuid), None);
+	}
+}
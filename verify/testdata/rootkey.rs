@@ -0,0 +1,19 @@
+/* Generated with: tools/mkmanifest.py --key verify/testdata/test_root.pem --rootkey */
+pub const ROOT_KEY: [u8; 256] = [
+	0xdd, 0x73, 0x60, 0x5b, 0x28, 0x5f, 0xc9, 0x5c, 0x19, 0xac, 0x82, 0xd2, 0xcc, 0x5c, 0xed, 0xc3,
+	0xc7, 0x79, 0xc0, 0x0c, 0xd6, 0xe4, 0x3f, 0xa8, 0xd1, 0x45, 0xaf, 0x5e, 0x8a, 0x65, 0x60, 0xf5,
+	0xeb, 0xd5, 0xc6, 0xe2, 0x12, 0x05, 0x21, 0xb4, 0xec, 0xf8, 0x27, 0x47, 0x70, 0x6e, 0x31, 0x0c,
+	0xc0, 0x7c, 0xa9, 0x5e, 0x39, 0xd0, 0xac, 0x58, 0xe9, 0xd0, 0xac, 0xe7, 0x18, 0x07, 0x9f, 0xf3,
+	0x19, 0xb0, 0x3b, 0x02, 0x10, 0xdb, 0x23, 0xd7, 0x06, 0xc8, 0x1f, 0x8f, 0x0b, 0xf4, 0x56, 0xa9,
+	0x04, 0x6c, 0x92, 0xe4, 0xaf, 0x29, 0x2d, 0x7b, 0x64, 0xae, 0xbe, 0x33, 0xc3, 0x12, 0xd1, 0x58,
+	0x43, 0x28, 0x92, 0x7b, 0x44, 0x83, 0x71, 0xea, 0xdf, 0xdd, 0x62, 0x65, 0x33, 0x3a, 0x03, 0x17,
+	0x07, 0xf1, 0x70, 0x23, 0x77, 0xb2, 0xfe, 0xe3, 0x52, 0xf9, 0xda, 0x2b, 0xc4, 0xff, 0x74, 0x7e,
+	0x1a, 0xf3, 0x14, 0x50, 0x00, 0xef, 0xf9, 0xd5, 0x3a, 0x5c, 0x8e, 0x41, 0xcd, 0x0e, 0xc3, 0xfb,
+	0xbe, 0x3f, 0xb6, 0x00, 0x29, 0xdd, 0x7c, 0x2c, 0xeb, 0x42, 0xcb, 0xd8, 0xdc, 0x15, 0x70, 0xe7,
+	0xcb, 0x7b, 0x59, 0x6d, 0xd5, 0xc2, 0x19, 0x0a, 0xaf, 0x2e, 0x95, 0x95, 0xa0, 0x97, 0x23, 0xd3,
+	0x5d, 0xc5, 0xe1, 0x5a, 0xf5, 0xde, 0x50, 0x38, 0x6b, 0x47, 0x90, 0xc5, 0xd5, 0xe5, 0x5e, 0x4d,
+	0x49, 0x57, 0x1b, 0x97, 0x01, 0x53, 0x84, 0xf0, 0xee, 0x74, 0x78, 0x30, 0x33, 0x2a, 0xe9, 0x7b,
+	0x89, 0x81, 0x25, 0x75, 0x6f, 0x60, 0x46, 0xa3, 0xde, 0x0a, 0xb1, 0x9e, 0xc3, 0xe9, 0xf0, 0x7d,
+	0x1f, 0xc4, 0xeb, 0xb4, 0xed, 0x74, 0x3f, 0x66, 0xc2, 0xc4, 0xf6, 0x80, 0x3c, 0xd8, 0xc8, 0x20,
+	0x52, 0x09, 0x6e, 0xb2, 0x32, 0x04, 0xbe, 0x28, 0xf5, 0x88, 0xa4, 0x70, 0x86, 0x6e, 0x9c, 0xcd,
+];